@@ -5,32 +5,22 @@ use actix_web::{middleware::Logger, web, App, HttpServer};
 use anyhow::Result;
 use aws_sdk_bedrockruntime as bedrock;
 use aws_sdk_dynamodb::Client;
-use redis::aio::MultiplexedConnection;
 use routes::middlewares::inactivity_middleware::{InactivityMiddleware, LastActivityTime};
+use utils::api_key::{ApiKeyRepository, DynamoApiKeyRepository, InMemoryApiKeyRepository};
 use utils::app_state::AppState;
-use utils::global_variables::SHUTDOWN_DURATION;
+use utils::config::{Config, MailerKind, UserStore};
+use utils::entitlement::{
+    DynamoEntitlementRepository, EntitlementRepository, InMemoryEntitlementRepository,
+};
+use utils::jwt_keys::KeyRing;
+use utils::user::{DynamoUserRepository, InMemoryUserRepository, UserRepository};
 
 mod routes;
+#[cfg(test)]
+mod tests;
 mod utils;
 
-pub struct RedisClient {
-    client: redis::Client,
-}
-
-impl RedisClient {
-    pub fn new() -> Result<Self> {
-        let redis_url = (utils::environment_variables::REDIS_URL).clone();
-        let client = redis::Client::open(redis_url).map_err(anyhow::Error::from)?;
-        Ok(Self { client })
-    }
-
-    pub async fn get_async_connection(&self) -> Result<MultiplexedConnection> {
-        self.client
-            .get_multiplexed_async_connection()
-            .await
-            .map_err(anyhow::Error::from)
-    }
-}
+pub use utils::redis_client::RedisClient;
 
 #[actix_web::main]
 async fn main() -> Result<()> {
@@ -38,19 +28,80 @@ async fn main() -> Result<()> {
         std::env::set_var("RUST_LOG", "actix_web=info");
     }
 
-    println!("[+] about to init env");
-    dotenv::dotenv().ok();
+    println!("[+] about to load config");
+    // reads .env too, so RUST_LOG from there applies
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(err) => {
+            eprintln!("[-] {}", err);
+            std::process::exit(1);
+        }
+    };
     env_logger::init();
 
-    println!("[+] env initialized successfully");
-    let address = (utils::environment_variables::ADDRESS).clone();
-    let port = (utils::environment_variables::PORT).clone();
+    println!("[+] config loaded for {}", config.environment);
+    let address = config.server.address.clone();
+    let port = config.server.port;
 
-    let redis_client = web::Data::new(RedisClient::new().expect("Failed to create Redis client"));
+    let redis_client = web::Data::new(
+        RedisClient::connect(&config.redis)
+            .await
+            .expect("Failed to connect to Redis"),
+    );
+    println!("[+] connected to redis");
 
     let shared_config = aws_config::load_from_env().await;
-    let dynamo_client = Arc::new(Client::new(&shared_config));
     let bedrock_client = Arc::new(bedrock::Client::new(&shared_config));
+    let llm_provider = utils::llm::provider_from_config(&config.llm, bedrock_client);
+    println!("[+] using LLM model {}", llm_provider.model_id());
+
+    // loads and checks the signing keys now instead of on the first login
+    let key_ring = Arc::new(KeyRing::from_config(&config.jwt).expect("Failed to load JWT keys"));
+    println!(
+        "[+] signing JWTs with {:?} key {}",
+        key_ring.signing_algorithm(),
+        key_ring.signing_kid()
+    );
+
+    let mailer = utils::mailer::mailer_from_config(&config.mail).expect("Failed to set up mailer");
+    if config.mail.mailer == MailerKind::Log {
+        println!("[!] mailer is log, emails are only written locally");
+    }
+
+    let oidc = Arc::new(
+        utils::oidc::OidcVerifier::from_config(&config.oidc)
+            .expect("Failed to set up sign in providers"),
+    );
+    println!("[+] sign in providers: {:?}", oidc.enabled_providers());
+
+    let (user_repository, entitlement_repository, api_key_repository): (
+        Arc<dyn UserRepository>,
+        Arc<dyn EntitlementRepository>,
+        Arc<dyn ApiKeyRepository>,
+    ) = match config.storage.user_store {
+        UserStore::Memory => {
+            println!("[!] user store is memory, users are not persisted");
+            (
+                Arc::new(InMemoryUserRepository::default()),
+                Arc::new(InMemoryEntitlementRepository::default()),
+                Arc::new(InMemoryApiKeyRepository::default()),
+            )
+        }
+        UserStore::Dynamodb => (
+            Arc::new(DynamoUserRepository::new(
+                Client::new(&shared_config),
+                config.storage.table_name.clone(),
+            )),
+            Arc::new(DynamoEntitlementRepository::new(
+                Client::new(&shared_config),
+                config.storage.table_name.clone(),
+            )),
+            Arc::new(DynamoApiKeyRepository::new(
+                Client::new(&shared_config),
+                config.storage.table_name.clone(),
+            )),
+        ),
+    };
 
     println!("[+] dynamodb setup done");
 
@@ -59,39 +110,45 @@ async fn main() -> Result<()> {
     let last_activity = Arc::new(LastActivityTime(Mutex::new(Instant::now())));
     let last_activity_clone = last_activity.clone();
 
-    let shutdown_duration = SHUTDOWN_DURATION.clone();
+    let idle_timeout = Duration::from_secs(config.server.idle_shutdown_seconds);
+    let idle_check_interval = Duration::from_secs(config.server.idle_check_interval_seconds);
+    let shutdown_drain_seconds = config.server.shutdown_drain_seconds;
 
     let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(AppState {
+                config: Arc::clone(&config),
+                key_ring: Arc::clone(&key_ring),
                 redis_client: redis_client.clone(),
-                dynamo_client: Arc::clone(&dynamo_client),
-                bedrock_client: Arc::clone(&bedrock_client),
+                user_repository: Arc::clone(&user_repository),
+                entitlement_repository: Arc::clone(&entitlement_repository),
+                api_key_repository: Arc::clone(&api_key_repository),
+                llm_provider: Arc::clone(&llm_provider),
+                mailer: Arc::clone(&mailer),
+                oidc: Arc::clone(&oidc),
             }))
             .wrap(Logger::default())
             .wrap(InactivityMiddleware {
                 last_activity: last_activity_clone.clone(),
-                shutdown_duration: Duration::from_secs(shutdown_duration as u64),
+                shutdown_duration: idle_timeout,
             })
-            .configure(routes::auth_routes::config)
-            .configure(routes::user_routes::config)
-            .configure(routes::index_routes::config)
-            .configure(routes::map_routes::config)
+            .configure(routes::config)
     })
-    .bind((address, port))?;
-
-    let server_handle = server.run();
-
-    actix_web::rt::spawn(async move {
-        loop {
-            tokio::time::sleep(Duration::from_secs(60)).await;
-            let last_activity = last_activity.0.lock().unwrap();
-            if last_activity.elapsed() > Duration::from_secs(shutdown_duration as u64) {
-                println!("[-] No activity for 5 minutes. Shutting down.");
-                std::process::exit(0);
-            }
-        }
-    });
-
-    server_handle.await.map_err(anyhow::Error::from)
+    .shutdown_timeout(shutdown_drain_seconds)
+    // we handle SIGTERM/SIGINT ourselves, see utils::shutdown
+    .disable_signals()
+    .bind((address, port))?
+    .run();
+
+    utils::shutdown::spawn_signal_handler(server.handle());
+    utils::shutdown::spawn_idle_watchdog(
+        server.handle(),
+        last_activity,
+        idle_timeout,
+        idle_check_interval,
+    );
+
+    server.await.map_err(anyhow::Error::from)?;
+    println!("[+] server stopped");
+    Ok(())
 }
@@ -0,0 +1,27 @@
+use actix_web::{middleware::from_fn, web};
+
+use crate::utils::{api_key::ApiKeyScope, user::Role};
+
+use super::{handlers, middlewares};
+
+// user management, admins only
+pub fn config(config: &mut web::ServiceConfig) {
+    config.service(
+        web::scope("/admin")
+            // wraps run bottom to top: auth first, then the role check on who it let in
+            .wrap(middlewares::role_middleware::require_role(Role::Admin))
+            .wrap(from_fn(middlewares::auth_middleware::check_auth_middleware))
+            // runs before the auth check, so it knows to take admin scoped keys
+            .wrap(middlewares::api_key_middleware::accept_api_key(
+                ApiKeyScope::Admin,
+            ))
+            .service(handlers::admin_handlers::list_users)
+            .service(handlers::admin_handlers::get_user)
+            .service(handlers::admin_handlers::change_role)
+            .service(handlers::admin_handlers::disable_user)
+            .service(handlers::admin_handlers::enable_user)
+            .service(handlers::api_key_handlers::admin_create_api_key)
+            .service(handlers::api_key_handlers::admin_list_api_keys)
+            .service(handlers::api_key_handlers::admin_revoke_api_key),
+    );
+}
@@ -7,10 +7,21 @@ pub fn config(config: &mut web::ServiceConfig) {
         web::scope("/auth")
             .service(handlers::auth_handlers::register)
             .service(handlers::auth_handlers::login)
+            .service(handlers::auth_handlers::login_mfa)
+            .service(handlers::auth_handlers::oidc_login)
+            .service(handlers::auth_handlers::refresh)
+            .service(handlers::auth_handlers::confirm_email)
+            .service(handlers::auth_handlers::forgot_password)
+            .service(handlers::auth_handlers::reset_password)
+            .service(handlers::auth_handlers::verify_email)
             .service(
                 web::scope("")
                     .wrap(from_fn(middlewares::auth_middleware::check_auth_middleware))
-                    .service(handlers::auth_handlers::logout),
+                    .service(handlers::auth_handlers::logout)
+                    .service(handlers::auth_handlers::resend_verification_email)
+                    .service(handlers::auth_handlers::sessions)
+                    .service(handlers::auth_handlers::delete_session)
+                    .service(handlers::auth_handlers::logout_all),
             ),
     );
 }
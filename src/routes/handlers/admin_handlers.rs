@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+
+use actix_web::{get, post, put, web};
+
+use crate::utils::{
+    api_response::{ApiResponse, ErrorCode},
+    app_state,
+    principal::Principal,
+    refresh_token::revoke_all_user_tokens,
+    user::{Role, User, UserProfile},
+};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(serde::Deserialize)]
+struct ListUsersQuery {
+    // matched against name and email, case sensitive
+    q: Option<String>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ChangeRoleRequest {
+    role: Role,
+}
+
+// what admins see of a user, the profile plus account state
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AdminUserView {
+    #[serde(flatten)]
+    profile: UserProfile,
+    disabled: bool,
+}
+
+impl From<&User> for AdminUserView {
+    fn from(user: &User) -> Self {
+        AdminUserView {
+            profile: user.profile(),
+            disabled: user.disabled,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UserList {
+    users: Vec<AdminUserView>,
+    next_cursor: Option<String>,
+}
+
+fn user_not_found() -> ApiResponse {
+    ApiResponse::error(404, ErrorCode::UserNotFound, "User not found")
+}
+
+// admins can't demote or disable themselves, so there's always at least one left
+fn self_modification_error(principal: &Principal, id: &str) -> Option<ApiResponse> {
+    (principal.id == id).then(|| {
+        ApiResponse::error(
+            409,
+            ErrorCode::CannotModifySelf,
+            "Admins can't change their own role or disable themselves",
+        )
+    })
+}
+
+// role and disabled are baked into tokens and checked at sign in, so the user signs in again
+async fn revoke_tokens(app_state: &app_state::AppState, id: &str) -> Result<(), ApiResponse> {
+    revoke_all_user_tokens(
+        &app_state.redis_client,
+        &app_state.config.jwt,
+        app_state.user_repository.as_ref(),
+        app_state.api_key_repository.as_ref(),
+        id,
+    )
+    .await
+    .map_err(|err| {
+        ApiResponse::error(
+            500,
+            ErrorCode::CacheError,
+            format!("Failed to revoke sessions: {}", err),
+        )
+    })
+}
+
+#[get("/users")]
+pub async fn list_users(
+    app_state: web::Data<app_state::AppState>,
+    query: web::Query<ListUsersQuery>,
+) -> Result<ApiResponse, ApiResponse> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        let fields =
+            BTreeMap::from([("limit", format!("must be between 1 and {}", MAX_PAGE_SIZE))]);
+        return Err(
+            ApiResponse::error(400, ErrorCode::ValidationFailed, "Invalid query")
+                .with_details(serde_json::json!({ "fields": fields })),
+        );
+    }
+    let search = query
+        .q
+        .as_deref()
+        .map(str::trim)
+        .filter(|search| !search.is_empty());
+
+    let page = app_state
+        .user_repository
+        .list_users(search, limit, query.cursor.as_deref())
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
+
+    Ok(ApiResponse::new(
+        200,
+        UserList {
+            users: page.users.iter().map(AdminUserView::from).collect(),
+            next_cursor: page.next_cursor,
+        },
+    ))
+}
+
+#[get("/users/{id}")]
+pub async fn get_user(
+    app_state: web::Data<app_state::AppState>,
+    path: web::Path<String>,
+) -> Result<ApiResponse, ApiResponse> {
+    let user = app_state
+        .user_repository
+        .get_user_from_id(&path)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(user_not_found)?;
+    Ok(ApiResponse::new(200, AdminUserView::from(&user)))
+}
+
+#[put("/users/{id}/role")]
+pub async fn change_role(
+    app_state: web::Data<app_state::AppState>,
+    path: web::Path<String>,
+    request: web::Json<ChangeRoleRequest>,
+    principal: Principal,
+) -> Result<ApiResponse, ApiResponse> {
+    if let Some(error) = self_modification_error(&principal, &path) {
+        return Err(error);
+    }
+
+    let user = app_state
+        .user_repository
+        .get_user_from_id(&path)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(user_not_found)?;
+    if user.role == request.role {
+        return Ok(ApiResponse::new(200, AdminUserView::from(&user)));
+    }
+
+    let user = app_state
+        .user_repository
+        .set_user_role(&path, request.role)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(user_not_found)?;
+    revoke_tokens(&app_state, &user.id).await?;
+
+    println!(
+        "[+] {} changed the role of {} to {}",
+        principal,
+        user.id,
+        request.role.as_str()
+    );
+    Ok(ApiResponse::new(200, AdminUserView::from(&user)))
+}
+
+#[post("/users/{id}/disable")]
+pub async fn disable_user(
+    app_state: web::Data<app_state::AppState>,
+    path: web::Path<String>,
+    principal: Principal,
+) -> Result<ApiResponse, ApiResponse> {
+    if let Some(error) = self_modification_error(&principal, &path) {
+        return Err(error);
+    }
+
+    let user = app_state
+        .user_repository
+        .set_user_disabled(&path, true)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(user_not_found)?;
+    revoke_tokens(&app_state, &user.id).await?;
+
+    println!("[+] {} disabled {}", principal, user.id);
+    Ok(ApiResponse::new(200, AdminUserView::from(&user)))
+}
+
+#[post("/users/{id}/enable")]
+pub async fn enable_user(
+    app_state: web::Data<app_state::AppState>,
+    path: web::Path<String>,
+    principal: Principal,
+) -> Result<ApiResponse, ApiResponse> {
+    let user = app_state
+        .user_repository
+        .set_user_disabled(&path, false)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(user_not_found)?;
+
+    println!("[+] {} enabled {}", principal, user.id);
+    Ok(ApiResponse::new(200, AdminUserView::from(&user)))
+}
@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+
+use actix_web::{delete, get, post, web};
+
+use crate::utils::{
+    api_key::{ApiKey, ApiKeyScope},
+    api_response::{ApiResponse, ErrorCode},
+    app_state::{self, AppState},
+    jwt::Claims,
+    principal::Principal,
+    user::User,
+};
+
+use super::user_handlers::{too_long, MAX_NAME_LENGTH};
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CreateApiKeyRequest {
+    // what the key is for, e.g. "Lobby kiosk"
+    name: String,
+    scopes: Vec<ApiKeyScope>,
+}
+
+// the only response that ever contains the key itself
+#[derive(serde::Serialize)]
+struct CreatedApiKey {
+    #[serde(flatten)]
+    api_key: ApiKey,
+    key: String,
+}
+
+fn api_key_not_found() -> ApiResponse {
+    ApiResponse::error(404, ErrorCode::ApiKeyNotFound, "API key not found")
+}
+
+async fn load_user(app_state: &AppState, id: &str) -> Result<User, ApiResponse> {
+    app_state
+        .user_repository
+        .get_user_from_id(id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(|| ApiResponse::error(404, ErrorCode::UserNotFound, "User not found"))
+}
+
+// all problems at once, keyed by field name like the profile update
+fn validate(owner: &User, request: &CreateApiKeyRequest) -> BTreeMap<&'static str, String> {
+    let mut errors = BTreeMap::new();
+    let name = request.name.trim();
+    if name.is_empty() {
+        errors.insert("name", "must not be empty".to_string());
+    } else if name.chars().count() > MAX_NAME_LENGTH {
+        errors.insert("name", too_long(MAX_NAME_LENGTH));
+    }
+    if request.scopes.is_empty() {
+        errors.insert("scopes", "must name at least one scope".to_string());
+    } else if let Some(scope) = request
+        .scopes
+        .iter()
+        .find(|scope| owner.role < scope.required_role())
+    {
+        errors.insert(
+            "scopes",
+            format!(
+                "'{}' needs the '{}' role",
+                scope.as_str(),
+                scope.required_role().as_str()
+            ),
+        );
+    }
+    errors
+}
+
+async fn create_for(
+    app_state: &AppState,
+    owner: &User,
+    request: &CreateApiKeyRequest,
+) -> Result<ApiResponse, ApiResponse> {
+    let errors = validate(owner, request);
+    if !errors.is_empty() {
+        return Err(
+            ApiResponse::error(400, ErrorCode::ValidationFailed, "Invalid API key")
+                .with_details(serde_json::json!({ "fields": errors })),
+        );
+    }
+
+    let existing = app_state
+        .api_key_repository
+        .list_api_keys(&owner.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
+    let max_per_user = app_state.config.api_keys.max_per_user;
+    if existing.len() >= max_per_user {
+        return Err(ApiResponse::error(
+            409,
+            ErrorCode::TooManyApiKeys,
+            format!(
+                "At most {} API keys per user, revoke one first",
+                max_per_user
+            ),
+        ));
+    }
+
+    let mut scopes = Vec::new();
+    for scope in &request.scopes {
+        if !scopes.contains(scope) {
+            scopes.push(*scope);
+        }
+    }
+    let (api_key, key) = ApiKey::generate(&owner.id, request.name.trim(), scopes);
+    app_state
+        .api_key_repository
+        .create_api_key(&api_key)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
+
+    println!("[+] API key {} created for {}", api_key.id, owner.id);
+    Ok(ApiResponse::new(201, CreatedApiKey { api_key, key }))
+}
+
+async fn list_for(app_state: &AppState, user_id: &str) -> Result<ApiResponse, ApiResponse> {
+    let api_keys = app_state
+        .api_key_repository
+        .list_api_keys(user_id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
+    Ok(ApiResponse::new(200, api_keys))
+}
+
+async fn revoke_for(
+    app_state: &AppState,
+    user_id: &str,
+    key_id: &str,
+) -> Result<ApiResponse, ApiResponse> {
+    let revoked = app_state
+        .api_key_repository
+        .delete_api_key(user_id, key_id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
+    if !revoked {
+        return Err(api_key_not_found());
+    }
+
+    println!("[+] API key {} of {} revoked", key_id, user_id);
+    Ok(ApiResponse::message(200, "API key revoked"))
+}
+
+// a leaked key mustn't be able to make itself a replacement, so only tokens create keys
+#[post("/api-keys")]
+pub async fn create_api_key(
+    app_state: web::Data<app_state::AppState>,
+    request: web::Json<CreateApiKeyRequest>,
+    principal: Principal,
+) -> Result<ApiResponse, ApiResponse> {
+    if let Some(error) = principal.api_key_error() {
+        return Err(error);
+    }
+    let owner = load_user(&app_state, &principal.id).await?;
+    create_for(&app_state, &owner, &request).await
+}
+
+#[get("/api-keys")]
+pub async fn list_api_keys(
+    app_state: web::Data<app_state::AppState>,
+    claim_data: Claims,
+) -> Result<ApiResponse, ApiResponse> {
+    list_for(&app_state, &claim_data.id).await
+}
+
+#[delete("/api-keys/{key_id}")]
+pub async fn revoke_api_key(
+    app_state: web::Data<app_state::AppState>,
+    path: web::Path<String>,
+    claim_data: Claims,
+) -> Result<ApiResponse, ApiResponse> {
+    revoke_for(&app_state, &claim_data.id, &path).await
+}
+
+// the admin versions, e.g. for a kiosk account that nobody signs in to
+#[post("/users/{id}/api-keys")]
+pub async fn admin_create_api_key(
+    app_state: web::Data<app_state::AppState>,
+    path: web::Path<String>,
+    request: web::Json<CreateApiKeyRequest>,
+    principal: Principal,
+) -> Result<ApiResponse, ApiResponse> {
+    if let Some(error) = principal.api_key_error() {
+        return Err(error);
+    }
+    let owner = load_user(&app_state, &path).await?;
+    println!("[+] {} creates an API key for {}", principal, owner.id);
+    create_for(&app_state, &owner, &request).await
+}
+
+#[get("/users/{id}/api-keys")]
+pub async fn admin_list_api_keys(
+    app_state: web::Data<app_state::AppState>,
+    path: web::Path<String>,
+) -> Result<ApiResponse, ApiResponse> {
+    let owner = load_user(&app_state, &path).await?;
+    list_for(&app_state, &owner.id).await
+}
+
+#[delete("/users/{id}/api-keys/{key_id}")]
+pub async fn admin_revoke_api_key(
+    app_state: web::Data<app_state::AppState>,
+    path: web::Path<(String, String)>,
+    principal: Principal,
+) -> Result<ApiResponse, ApiResponse> {
+    let (user_id, key_id) = path.into_inner();
+    println!(
+        "[+] {} revokes API key {} of {}",
+        principal, key_id, user_id
+    );
+    revoke_for(&app_state, &user_id, &key_id).await
+}
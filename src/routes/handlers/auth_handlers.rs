@@ -1,16 +1,28 @@
-use std::collections::HashMap;
-
+use super::user_handlers::{EmailChange, EMAIL_CHANGE_TOKEN_PURPOSE, MAX_NAME_LENGTH};
 use crate::utils::{
-    api_response::{self, ApiResponse},
+    api_response::{self, ApiResponse, ErrorCode},
     app_state::{self, AppState},
-    global_variables::DYNAMO_DB_TABLE_NAME,
-    jwt::{add_to_blacklist, encode_jwt},
-    user::get_user_from_email,
-    user::User,
+    jwt::{add_to_blacklist, Claims},
+    login_attempts::{reserve_login_attempt, LoginAttempt, Reservation},
+    mailer::{account_locked_email, password_reset_email, send_in_background, verification_email},
+    mfa::{
+        complete_mfa_challenge, get_mfa_challenge, issue_mfa_challenge, reserve_mfa_guess,
+        verify_second_factor, MfaChallenge, SecondFactor,
+    },
+    oidc::{Identity, OidcError, Provider},
+    one_time_token::{consume_token, issue_token},
+    password::{hash_password, password_problem, verify_password, PasswordCheck},
+    refresh_token::{
+        issue_token_pair, revoke_all_user_tokens, rotate_refresh_token, RefreshResult,
+        RefreshTokenRecord, TokenPair,
+    },
+    session::{client_ip, create_session, list_sessions, revoke_session, SessionInfo},
+    throttle::check_cooldown,
+    user::{find_user_by_email, normalize_email, Role, User, USER_ID_PREFIX},
 };
-use actix_web::{post, web, HttpRequest};
+use actix_web::{delete, get, http::header::RETRY_AFTER, post, web, HttpRequest};
 use anyhow::Result;
-use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::Duration;
 use sha256::digest;
 
 #[derive(serde::Deserialize)]
@@ -23,8 +35,301 @@ struct RegisterRequest {
 #[derive(serde::Deserialize)]
 struct LoginRequest {
     email: String,
-    #[allow(dead_code)]
     password: String,
+    // shown in the session list, e.g. "Pixel 8"
+    device: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(serde::Deserialize)]
+struct ConfirmEmailRequest {
+    token: String,
+}
+
+#[derive(serde::Deserialize)]
+struct ForgotPasswordRequest {
+    email: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResetPasswordRequest {
+    token: String,
+    new_password: String,
+}
+
+const PASSWORD_RESET_TOKEN_PURPOSE: &str = "password_reset";
+
+pub(crate) const EMAIL_VERIFICATION_TOKEN_PURPOSE: &str = "email_verification";
+
+// the address is in it too, so a token sent before an email change can't verify the new one
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EmailVerification {
+    pub(crate) user_id: String,
+    pub(crate) email: String,
+}
+
+// The fingerprint ties the token to the password hash it was issued for, so any password
+// change (including another reset) makes older reset tokens useless.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PasswordReset {
+    user_id: String,
+    password_fingerprint: String,
+}
+
+// a 400 naming the field if the password isn't acceptable
+pub(crate) fn new_password_error(field: &str, password: &str) -> Option<ApiResponse> {
+    password_problem(password).map(|problem| {
+        ApiResponse::error(400, ErrorCode::ValidationFailed, "Invalid password")
+            .with_details(serde_json::json!({ "fields": { field: problem } }))
+    })
+}
+
+// stores the new password and logs the user out on every device
+pub(crate) async fn set_new_password(
+    app_state: &AppState,
+    user_id: &str,
+    password: &str,
+) -> Result<(), ApiResponse> {
+    let password = password.to_string();
+    let password_hash = web::block(move || hash_password(&password))
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?
+        .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?;
+
+    app_state
+        .user_repository
+        .update_user_password(user_id, &password_hash)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
+
+    revoke_all_user_tokens(
+        &app_state.redis_client,
+        &app_state.config.jwt,
+        app_state.user_repository.as_ref(),
+        app_state.api_key_repository.as_ref(),
+        user_id,
+    )
+    .await
+    .map_err(|err| {
+        ApiResponse::error(
+            500,
+            ErrorCode::CacheError,
+            format!("Failed to revoke tokens: {}", err),
+        )
+    })
+}
+
+#[derive(serde::Deserialize)]
+struct VerifyEmailRequest {
+    token: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LoginMfaRequest {
+    mfa_token: String,
+    // from the authenticator app, or one of the recovery codes instead
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+// login answer for users with two-factor on, instead of the tokens
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MfaRequired {
+    mfa_required: bool,
+    mfa_token: String,
+    expires_in_seconds: i64,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OidcLoginRequest {
+    id_token: String,
+    // the nonce the app generated for this sign in, if it passed one to the provider
+    nonce: Option<String>,
+    // Apple only hands the name to the app, on the first sign in
+    name: Option<String>,
+    device: Option<String>,
+}
+
+async fn send_verification_email(
+    app_state: &AppState,
+    user_id: &str,
+    email: &str,
+    name: &str,
+) -> Result<()> {
+    let verification = EmailVerification {
+        user_id: user_id.to_string(),
+        email: email.to_string(),
+    };
+    let token = issue_token(
+        &app_state.redis_client,
+        EMAIL_VERIFICATION_TOKEN_PURPOSE,
+        &verification,
+        Duration::hours(app_state.config.auth.email_verification_token_hours),
+    )
+    .await?;
+    send_in_background(
+        app_state.mailer.clone(),
+        verification_email(&app_state.config.mail, email, name, &token),
+    );
+    Ok(())
+}
+
+// 403 for accounts an admin disabled. Check it after the credentials, so it doesn't tell
+// anyone else which accounts exist
+pub(crate) fn disabled_account_error(user: &User) -> Option<ApiResponse> {
+    user.disabled.then(|| {
+        ApiResponse::error(
+            403,
+            ErrorCode::AccountDisabled,
+            "This account has been disabled",
+        )
+    })
+}
+
+// auth.admin_emails become admins on sign in, but only once the address is verified
+async fn promote_configured_admin(app_state: &AppState, user: User) -> Result<User> {
+    if user.role == Role::Admin
+        || !user.verified
+        || !app_state.config.auth.admin_emails.contains(&user.email)
+    {
+        return Ok(user);
+    }
+    println!("[+] promoting {} to admin, listed in admin_emails", user.id);
+    Ok(app_state
+        .user_repository
+        .set_user_role(&user.id, Role::Admin)
+        .await?
+        .unwrap_or(user))
+}
+
+// Counts the failure towards the brute force limits and tells the owner if it locked their
+// account
+pub(crate) async fn record_login_failure(
+    app_state: &AppState,
+    attempt: LoginAttempt,
+    user: Option<&User>,
+) {
+    let config = &app_state.config.auth;
+    match attempt.failed(&app_state.redis_client, config).await {
+        Ok(true) => {
+            if let Some(user) = user {
+                send_in_background(
+                    app_state.mailer.clone(),
+                    account_locked_email(
+                        &user.email,
+                        &user.name,
+                        config.login_lockout_seconds / 60,
+                    ),
+                );
+            }
+        }
+        Ok(false) => {}
+        Err(err) => println!("[-] failed to record login failure: {}", err),
+    }
+}
+
+// Always the same 401, whether the email exists or not
+async fn login_failed(
+    app_state: &AppState,
+    attempt: LoginAttempt,
+    user: Option<&User>,
+) -> ApiResponse {
+    record_login_failure(app_state, attempt, user).await;
+    ApiResponse::error(
+        401,
+        ErrorCode::InvalidCredentials,
+        "Invalid email or password",
+    )
+}
+
+// reserve_login_attempt turned the attempt away
+pub(crate) fn too_many_login_attempts(retry_after: u64) -> ApiResponse {
+    ApiResponse::error(
+        429,
+        ErrorCode::TooManyLoginAttempts,
+        "Too many failed login attempts, please wait before trying again",
+    )
+    .with_details(serde_json::json!({ "retryAfterSeconds": retry_after }))
+    .with_header(RETRY_AFTER, retry_after.to_string())
+}
+
+pub(crate) fn second_factor_required() -> ApiResponse {
+    ApiResponse::error(
+        400,
+        ErrorCode::SecondFactorRequired,
+        "Send either a code or a recoveryCode",
+    )
+}
+
+// Last step of every login: the tokens, or a challenge for /login/mfa if the user has
+// two-factor on
+async fn finish_sign_in(
+    app_state: &AppState,
+    user: User,
+    req: &HttpRequest,
+    device: Option<String>,
+) -> Result<ApiResponse, ApiResponse> {
+    if user.totp_secret.is_some() {
+        let challenge = MfaChallenge {
+            user_id: user.id,
+            token_version: user.token_version,
+            device,
+        };
+        let mfa_token =
+            issue_mfa_challenge(&app_state.redis_client, &app_state.config.auth, &challenge)
+                .await
+                .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+        return Ok(ApiResponse::new(
+            200,
+            MfaRequired {
+                mfa_required: true,
+                mfa_token,
+                expires_in_seconds: Duration::minutes(app_state.config.auth.mfa_challenge_minutes)
+                    .num_seconds(),
+            },
+        ));
+    }
+
+    let info = SessionInfo::from_request(req, &app_state.config.server, device);
+    let tokens = start_session(app_state, user, info)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+    Ok(ApiResponse::new(200, tokens))
+}
+
+// every successful login ends up here, one new session per login
+pub(crate) async fn start_session(
+    app_state: &AppState,
+    user: User,
+    info: SessionInfo,
+) -> Result<TokenPair> {
+    let user = promote_configured_admin(app_state, user).await?;
+    let config = &app_state.config.jwt;
+    let session_id = create_session(&app_state.redis_client, config, &user.id, info).await?;
+    issue_token_pair(
+        &app_state.redis_client,
+        &app_state.key_ring,
+        config,
+        RefreshTokenRecord {
+            user_id: user.id,
+            email: user.email,
+            session_id,
+            token_version: user.token_version,
+            role: user.role,
+        },
+    )
+    .await
 }
 
 #[post("/register")]
@@ -32,97 +337,708 @@ pub async fn register(
     app_state: web::Data<AppState>,
     request: web::Json<RegisterRequest>,
 ) -> Result<ApiResponse, ApiResponse> {
-    let result = get_user_from_email(&app_state.dynamo_client, request.email.clone())
+    let email = normalize_email(&request.email);
+    let existing = find_user_by_email(app_state.user_repository.as_ref(), &request.email)
         .await
-        .map_err(|err| ApiResponse::new(500, err.to_string()))?;
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
 
-    if let Some(_) = result.items.and_then(|items| items.first().cloned()) {
-        return Err(ApiResponse::new(409, "User already exists".to_string()));
+    if existing.is_some() {
+        return Err(ApiResponse::error(
+            409,
+            ErrorCode::UserAlreadyExists,
+            "User already exists",
+        ));
     }
 
-    let mut item = HashMap::new();
-
-    item.insert(
-        "id".to_string(),
-        AttributeValue::S(format!("USER#{}", uuid::Uuid::new_v4())),
-    );
-    item.insert("name".to_string(), AttributeValue::S(request.name.clone()));
-    item.insert(
-        "email".to_string(),
-        AttributeValue::S(request.email.clone()),
-    );
-    item.insert(
-        "password".to_string(),
-        AttributeValue::S(digest(request.password.clone())),
-    );
+    // argon2 is deliberately slow, so keep it off the async workers
+    let password = request.password.clone();
+    let password_hash = web::block(move || hash_password(&password))
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?
+        .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?;
 
-    let table_name = DYNAMO_DB_TABLE_NAME.clone();
+    let user = User {
+        id: format!("{}{}", USER_ID_PREFIX, uuid::Uuid::new_v4()),
+        name: request.name.clone(),
+        email,
+        password: password_hash,
+        verified: false,
+        token_version: 0,
+        role: Role::User,
+        disabled: false,
+        identities: Vec::new(),
+        totp_secret: None,
+        recovery_codes: Vec::new(),
+        bio: None,
+        avatar_url: None,
+        pending_email: None,
+    };
 
     app_state
-        .dynamo_client
-        .put_item()
-        .table_name(table_name)
-        .set_item(Some(item))
-        .send()
+        .user_repository
+        .create_user(&user)
         .await
-        .map_err(|err| ApiResponse::new(500, err.to_string()))?;
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
 
-    Ok(api_response::ApiResponse::new(
-        200,
-        format!("created user: {} - {}", request.name, request.email),
-    ))
+    // the account works right away, if the mail gets lost the user can ask for another one
+    send_verification_email(&app_state, &user.id, &user.email, &user.name)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+
+    Ok(api_response::ApiResponse::new(200, user.profile()))
 }
 
 #[post("/login")]
 pub async fn login(
     app_state: web::Data<app_state::AppState>,
     request: web::Json<LoginRequest>,
+    req: HttpRequest,
+) -> Result<ApiResponse, ApiResponse> {
+    let ip = client_ip(&req, &app_state.config.server);
+
+    // the throttling and the lookup go by the same address
+    let email = normalize_email(&request.email);
+
+    let reservation = reserve_login_attempt(
+        &app_state.redis_client,
+        &app_state.config.auth,
+        &email,
+        ip.as_deref(),
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+    let attempt = match reservation {
+        Reservation::Allowed(attempt) => attempt,
+        Reservation::RetryAfter(retry_after) => return Err(too_many_login_attempts(retry_after)),
+    };
+
+    // same response for unknown email and wrong password so accounts can't be enumerated
+    let user = find_user_by_email(app_state.user_repository.as_ref(), &request.email)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
+    let Some(user) = user else {
+        return Err(login_failed(&app_state, attempt, None).await);
+    };
+
+    let password = request.password.clone();
+    let stored_hash = user.password.clone();
+    let check = web::block(move || verify_password(&password, &stored_hash))
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?
+        .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?;
+
+    match check {
+        PasswordCheck::Valid => {}
+        PasswordCheck::ValidLegacy => {
+            // upgrade the old sha256 hash now that we know the plain password
+            let password = request.password.clone();
+            let password_hash = web::block(move || hash_password(&password))
+                .await
+                .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?
+                .map_err(|err| {
+                    ApiResponse::error(500, ErrorCode::InternalError, err.to_string())
+                })?;
+            if let Err(err) = app_state
+                .user_repository
+                .update_user_password(&user.id, &password_hash)
+                .await
+            {
+                // login still succeeds, we'll try again next time
+                println!(
+                    "[-] failed to upgrade password hash for {}: {}",
+                    user.id, err
+                );
+            }
+        }
+        PasswordCheck::Invalid => return Err(login_failed(&app_state, attempt, Some(&user)).await),
+    }
+
+    // with two-factor on the login isn't done yet, login_mfa clears the failures
+    let cleared = if user.totp_secret.is_some() {
+        attempt.released(&app_state.redis_client).await
+    } else {
+        attempt.succeeded(&app_state.redis_client).await
+    };
+    if let Err(err) = cleared {
+        println!("[-] failed to clear login failures: {}", err);
+    }
+
+    if let Some(error) = disabled_account_error(&user) {
+        return Err(error);
+    }
+
+    finish_sign_in(&app_state, user, &req, request.device.clone()).await
+}
+
+// Second step of a login with two-factor on, trades the challenge from /login plus a code
+// for the tokens
+#[post("/login/mfa")]
+pub async fn login_mfa(
+    app_state: web::Data<app_state::AppState>,
+    request: web::Json<LoginMfaRequest>,
+    req: HttpRequest,
 ) -> Result<ApiResponse, ApiResponse> {
-    let result = get_user_from_email(&app_state.dynamo_client, request.email.clone())
+    let factor =
+        SecondFactor::from_fields(request.code.as_deref(), request.recovery_code.as_deref())
+            .ok_or_else(second_factor_required)?;
+    let invalid_token = || {
+        ApiResponse::error(
+            401,
+            ErrorCode::InvalidMfaToken,
+            "Invalid or expired MFA token, please log in again",
+        )
+    };
+
+    let challenge = get_mfa_challenge(&app_state.redis_client, &request.mfa_token)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?
+        .ok_or_else(invalid_token)?;
+    let user = app_state
+        .user_repository
+        .get_user_from_id(&challenge.user_id)
         .await
-        .map_err(|err| ApiResponse::new(409, err.to_string()))?;
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .filter(|user| user.token_version == challenge.token_version)
+        .ok_or_else(invalid_token)?;
 
-    let user = result
-    .items
-    .and_then(|items| items.first().cloned())
-    .ok_or_else(|| ApiResponse::new(404, "User not found. Please check the email address.".to_string()))
-    .and_then(|item| {
-        User::from_item(&item).map_err(|err| {
-            ApiResponse::new(
+    // codes count against the same limits as passwords, and are reserved before they're checked
+    let ip = client_ip(&req, &app_state.config.server);
+    let reservation = reserve_login_attempt(
+        &app_state.redis_client,
+        &app_state.config.auth,
+        &user.email,
+        ip.as_deref(),
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+    let attempt = match reservation {
+        Reservation::Allowed(attempt) => attempt,
+        Reservation::RetryAfter(retry_after) => return Err(too_many_login_attempts(retry_after)),
+    };
+    let guess_allowed = reserve_mfa_guess(
+        &app_state.redis_client,
+        &app_state.config.auth,
+        &request.mfa_token,
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+    if !guess_allowed {
+        record_login_failure(&app_state, attempt, Some(&user)).await;
+        return Err(invalid_token());
+    }
+
+    let verified = verify_second_factor(
+        &app_state.redis_client,
+        app_state.user_repository.as_ref(),
+        &user,
+        &factor,
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?;
+    if !verified {
+        record_login_failure(&app_state, attempt, Some(&user)).await;
+        return Err(ApiResponse::error(
+            401,
+            ErrorCode::InvalidMfaCode,
+            "Invalid code",
+        ));
+    }
+
+    // taking it out makes sure one challenge gives one session
+    complete_mfa_challenge(&app_state.redis_client, &request.mfa_token)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?
+        .ok_or_else(invalid_token)?;
+    if let Err(err) = attempt.succeeded(&app_state.redis_client).await {
+        println!("[-] failed to clear login failures: {}", err);
+    }
+    if let Some(error) = disabled_account_error(&user) {
+        return Err(error);
+    }
+
+    let info = SessionInfo::from_request(&req, &app_state.config.server, challenge.device);
+    let tokens = start_session(&app_state, user, info)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+    Ok(ApiResponse::new(200, tokens))
+}
+
+// The user a verified identity signs in as: the one it's linked to, else the account with the
+// same verified email (linking it), else a new one
+async fn user_for_identity(
+    app_state: &AppState,
+    identity: &Identity,
+    name: Option<&str>,
+) -> Result<User, ApiResponse> {
+    let provider = identity.provider.as_str();
+    let database_error =
+        |err: anyhow::Error| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string());
+
+    if let Some(user) = app_state
+        .user_repository
+        .get_user_from_identity(provider, &identity.subject)
+        .await
+        .map_err(database_error)?
+    {
+        return Ok(user);
+    }
+
+    let email = match &identity.email {
+        Some(email) if identity.email_verified => email,
+        _ => {
+            return Err(ApiResponse::error(
+                403,
+                ErrorCode::OidcEmailNotVerified,
+                format!("Your {} account has no verified email address", provider),
+            ))
+        }
+    };
+
+    let existing = find_user_by_email(app_state.user_repository.as_ref(), email)
+        .await
+        .map_err(database_error)?;
+    let user = match existing {
+        Some(user) if user.verified => user,
+        Some(user) => {
+            // whoever registered this address never proved they own it, the provider just did.
+            // Their password and sessions could be someone else's, so they go
+            println!(
+                "[!] {} sign in took over unverified account {}",
+                provider, user.id
+            );
+            app_state
+                .user_repository
+                .update_user_password(&user.id, "")
+                .await
+                .map_err(database_error)?;
+            revoke_all_user_tokens(
+                &app_state.redis_client,
+                &app_state.config.jwt,
+                app_state.user_repository.as_ref(),
+                app_state.api_key_repository.as_ref(),
+                &user.id,
+            )
+            .await
+            .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+            app_state
+                .user_repository
+                .mark_email_verified(&user.id, &user.email)
+                .await
+                .map_err(database_error)?
+                .ok_or_else(|| {
+                    ApiResponse::error(500, ErrorCode::DatabaseError, "User changed while linking")
+                })?
+        }
+        None => {
+            let name = name
+                .or(identity.name.as_deref())
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| email.split('@').next().unwrap_or_default())
+                .chars()
+                .take(MAX_NAME_LENGTH)
+                .collect();
+            let user = User {
+                id: format!("{}{}", USER_ID_PREFIX, uuid::Uuid::new_v4()),
+                name,
+                email: normalize_email(email),
+                password: String::new(),
+                verified: true,
+                token_version: 0,
+                role: Role::User,
+                disabled: false,
+                identities: Vec::new(),
+                totp_secret: None,
+                recovery_codes: Vec::new(),
+                bio: None,
+                avatar_url: None,
+                pending_email: None,
+            };
+            app_state
+                .user_repository
+                .create_user(&user)
+                .await
+                .map_err(database_error)?;
+            user
+        }
+    };
+
+    app_state
+        .user_repository
+        .link_identity(&user.id, provider, &identity.subject)
+        .await
+        .map_err(database_error)?;
+    Ok(user)
+}
+
+// Sign in with an ID token from Apple or Google, answers like /login
+fn oidc_not_configured(provider: &str) -> ApiResponse {
+    ApiResponse::error(
+        404,
+        ErrorCode::OidcProviderNotConfigured,
+        format!("Sign in with '{}' is not available", provider),
+    )
+}
+
+pub(crate) fn oidc_error(provider: Provider, err: OidcError) -> ApiResponse {
+    match err {
+        OidcError::NotConfigured => oidc_not_configured(provider.as_str()),
+        OidcError::InvalidToken(err) => {
+            ApiResponse::error(401, ErrorCode::InvalidIdToken, err.description())
+        }
+        OidcError::KeysUnavailable(err) => {
+            println!("[-] failed to load {} keys: {}", provider.as_str(), err);
+            ApiResponse::error(
+                503,
+                ErrorCode::OidcUnavailable,
+                format!(
+                    "Sign in with '{}' is unavailable right now",
+                    provider.as_str()
+                ),
+            )
+        }
+    }
+}
+
+#[post("/oidc/{provider}")]
+pub async fn oidc_login(
+    app_state: web::Data<app_state::AppState>,
+    path: web::Path<String>,
+    request: web::Json<OidcLoginRequest>,
+    req: HttpRequest,
+) -> Result<ApiResponse, ApiResponse> {
+    let provider = Provider::from_name(&path).ok_or_else(|| oidc_not_configured(&path))?;
+
+    let identity = app_state
+        .oidc
+        .verify(provider, &request.id_token, request.nonce.as_deref())
+        .await
+        .map_err(|err| oidc_error(provider, err))?;
+
+    let user = user_for_identity(&app_state, &identity, request.name.as_deref()).await?;
+    if let Some(error) = disabled_account_error(&user) {
+        return Err(error);
+    }
+
+    finish_sign_in(&app_state, user, &req, request.device.clone()).await
+}
+
+#[post("/refresh")]
+pub async fn refresh(
+    app_state: web::Data<app_state::AppState>,
+    request: web::Json<RefreshRequest>,
+) -> Result<ApiResponse, ApiResponse> {
+    let result = rotate_refresh_token(
+        &app_state.redis_client,
+        &app_state.key_ring,
+        &app_state.config.jwt,
+        app_state.user_repository.as_ref(),
+        &request.refresh_token,
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+
+    match result {
+        RefreshResult::Rotated(tokens) => Ok(api_response::ApiResponse::new(200, tokens)),
+        RefreshResult::Invalid => Err(ApiResponse::error(
+            401,
+            ErrorCode::InvalidRefreshToken,
+            "Invalid refresh token",
+        )),
+        RefreshResult::ReuseDetected => Err(ApiResponse::error(
+            401,
+            ErrorCode::RefreshTokenReused,
+            "Refresh token was already used. Please log in again.",
+        )),
+    }
+}
+
+#[post("/logout")]
+async fn logout(
+    app_state: web::Data<app_state::AppState>,
+    claim_data: Claims,
+) -> Result<ApiResponse, ApiResponse> {
+    // ends the session, which takes the refresh token and every access token of it along
+    revoke_session(&app_state.redis_client, &claim_data.id, &claim_data.sid)
+        .await
+        .map_err(|err| {
+            ApiResponse::error(
                 500,
-                format!("Failed to parse user data: {}. This might be due to data corruption or schema mismatch.", err),
+                ErrorCode::CacheError,
+                format!("Failed to revoke session: {}", err),
             )
-        })
-    })?;
+        })?;
+
+    // Add token to blacklist until it would have expired anyway
+    if let Err(e) = add_to_blacklist(
+        &app_state.redis_client,
+        &app_state.config.jwt,
+        &claim_data.jti,
+    )
+    .await
+    {
+        return Err(ApiResponse::error(
+            500,
+            ErrorCode::CacheError,
+            format!("Failed to blacklist token: {}", e),
+        ));
+    }
+    Ok(ApiResponse::message(200, "Successfully logged out"))
+}
+
+// No auth here, the token from the email is proof enough and the link may be opened on another device
+#[post("/email/confirm")]
+pub async fn confirm_email(
+    app_state: web::Data<app_state::AppState>,
+    request: web::Json<ConfirmEmailRequest>,
+) -> Result<ApiResponse, ApiResponse> {
+    let invalid_token = || {
+        ApiResponse::error(
+            400,
+            ErrorCode::InvalidEmailToken,
+            "Invalid or expired email confirmation token",
+        )
+    };
+
+    let change: EmailChange = consume_token(
+        &app_state.redis_client,
+        EMAIL_CHANGE_TOKEN_PURPOSE,
+        &request.token,
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?
+    .ok_or_else(invalid_token)?;
+
+    // someone could have registered the address since the change was requested
+    let taken = find_user_by_email(app_state.user_repository.as_ref(), &change.email)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .is_some_and(|user| user.id != change.user_id);
+    if taken {
+        return Err(ApiResponse::error(
+            409,
+            ErrorCode::EmailAlreadyInUse,
+            "Email is already in use",
+        ));
+    }
+
+    // None when the user asked for another address (or cancelled) after this token went out
+    let user = app_state
+        .user_repository
+        .confirm_email_change(&change.user_id, &change.email)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(invalid_token)?;
+
+    Ok(api_response::ApiResponse::new(200, user.profile()))
+}
 
-    let token =
-        encode_jwt(user.email, user.id).map_err(|err| ApiResponse::new(500, err.to_string()))?;
+// Always answers the same way so it can't be used to find out which emails have an account
+#[post("/password/forgot")]
+pub async fn forgot_password(
+    app_state: web::Data<app_state::AppState>,
+    request: web::Json<ForgotPasswordRequest>,
+) -> Result<ApiResponse, ApiResponse> {
+    let user = find_user_by_email(app_state.user_repository.as_ref(), &request.email)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
+
+    if let Some(user) = user {
+        let reset = PasswordReset {
+            user_id: user.id.clone(),
+            password_fingerprint: digest(&user.password),
+        };
+        let token = issue_token(
+            &app_state.redis_client,
+            PASSWORD_RESET_TOKEN_PURPOSE,
+            &reset,
+            Duration::minutes(app_state.config.auth.password_reset_token_minutes),
+        )
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+
+        send_in_background(
+            app_state.mailer.clone(),
+            password_reset_email(&app_state.config.mail, &user.email, &user.name, &token),
+        );
+    }
 
-    Ok(api_response::ApiResponse::new(
+    Ok(ApiResponse::message(
         200,
-        format!("{{'token': '{}'}}", token),
+        "If an account exists for this email, a reset link has been sent",
     ))
 }
 
-#[post("/logout")]
-async fn logout(
+#[post("/password/reset")]
+pub async fn reset_password(
     app_state: web::Data<app_state::AppState>,
-    req: HttpRequest,
+    request: web::Json<ResetPasswordRequest>,
 ) -> Result<ApiResponse, ApiResponse> {
-    if let Some(auth_header) = req.headers().get("Authorization") {
-        if let Ok(auth_str) = auth_header.to_str() {
-            if auth_str.starts_with("Bearer ") {
-                let token = auth_str.replace("Bearer ", "");
-                // Add token to blacklist with an expiry time of 24 hours
-                if let Err(e) = add_to_blacklist(&app_state.redis_client, &token).await {
-                    return Err(ApiResponse::new(
-                        500,
-                        format!("Failed to blacklist token: {}", e),
-                    ));
-                }
-                return Ok(ApiResponse::new(200, "Successfully logged out".to_string()));
-            }
-        }
+    // checked before the token is consumed so a rejected password doesn't burn it
+    if let Some(err) = new_password_error("newPassword", &request.new_password) {
+        return Err(err);
+    }
+
+    let invalid_token = || {
+        ApiResponse::error(
+            400,
+            ErrorCode::InvalidResetToken,
+            "Invalid or expired password reset token",
+        )
+    };
+
+    let reset: PasswordReset = consume_token(
+        &app_state.redis_client,
+        PASSWORD_RESET_TOKEN_PURPOSE,
+        &request.token,
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?
+    .ok_or_else(invalid_token)?;
+
+    let user = app_state
+        .user_repository
+        .get_user_from_id(&reset.user_id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .filter(|user| digest(&user.password) == reset.password_fingerprint)
+        .ok_or_else(invalid_token)?;
+
+    set_new_password(&app_state, &user.id, &request.new_password).await?;
+
+    Ok(ApiResponse::message(
+        200,
+        "Password has been reset, please log in again",
+    ))
+}
+
+// No auth here either, like confirm_email. Each token works once
+#[post("/verify-email")]
+pub async fn verify_email(
+    app_state: web::Data<app_state::AppState>,
+    request: web::Json<VerifyEmailRequest>,
+) -> Result<ApiResponse, ApiResponse> {
+    let invalid_token = || {
+        ApiResponse::error(
+            400,
+            ErrorCode::InvalidVerificationToken,
+            "Invalid or expired verification token",
+        )
+    };
+
+    let verification: EmailVerification = consume_token(
+        &app_state.redis_client,
+        EMAIL_VERIFICATION_TOKEN_PURPOSE,
+        &request.token,
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?
+    .ok_or_else(invalid_token)?;
+
+    // None also when the email changed after the token was sent
+    let user = app_state
+        .user_repository
+        .mark_email_verified(&verification.user_id, &verification.email)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(invalid_token)?;
+
+    Ok(api_response::ApiResponse::new(200, user.profile()))
+}
+
+#[post("/verify-email/resend")]
+pub async fn resend_verification_email(
+    app_state: web::Data<app_state::AppState>,
+    claim_data: Claims,
+) -> Result<ApiResponse, ApiResponse> {
+    let user = app_state
+        .user_repository
+        .get_user_from_id(&claim_data.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(|| ApiResponse::error(404, ErrorCode::UserNotFound, "User not found"))?;
+
+    if user.verified {
+        return Err(ApiResponse::error(
+            409,
+            ErrorCode::EmailAlreadyVerified,
+            "Email is already verified",
+        ));
+    }
+
+    let retry_after = check_cooldown(
+        &app_state.redis_client,
+        &format!("verify_email_resend:{}", user.id),
+        app_state.config.auth.verification_resend_seconds,
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+    if let Some(retry_after) = retry_after {
+        return Err(ApiResponse::error(
+            429,
+            ErrorCode::TooManyRequests,
+            "A verification email was sent recently, please wait before asking again",
+        )
+        .with_details(serde_json::json!({ "retryAfterSeconds": retry_after }))
+        .with_header(RETRY_AFTER, retry_after.to_string()));
+    }
+
+    send_verification_email(&app_state, &user.id, &user.email, &user.name)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+
+    Ok(ApiResponse::message(200, "Verification email sent"))
+}
+
+#[get("/sessions")]
+pub async fn sessions(
+    app_state: web::Data<app_state::AppState>,
+    claim_data: Claims,
+) -> Result<ApiResponse, ApiResponse> {
+    let sessions = list_sessions(&app_state.redis_client, &claim_data.id, &claim_data.sid)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+    Ok(api_response::ApiResponse::new(200, sessions))
+}
+
+#[delete("/sessions/{id}")]
+pub async fn delete_session(
+    app_state: web::Data<app_state::AppState>,
+    path: web::Path<String>,
+    claim_data: Claims,
+) -> Result<ApiResponse, ApiResponse> {
+    let revoked = revoke_session(&app_state.redis_client, &claim_data.id, &path)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+    if !revoked {
+        return Err(ApiResponse::error(
+            404,
+            ErrorCode::SessionNotFound,
+            "Session not found",
+        ));
     }
-    Err(ApiResponse::new(400, "Invalid token".to_string()))
+    Ok(ApiResponse::message(200, "Session revoked"))
+}
+
+#[post("/logout-all")]
+pub async fn logout_all(
+    app_state: web::Data<app_state::AppState>,
+    claim_data: Claims,
+) -> Result<ApiResponse, ApiResponse> {
+    revoke_all_user_tokens(
+        &app_state.redis_client,
+        &app_state.config.jwt,
+        app_state.user_repository.as_ref(),
+        app_state.api_key_repository.as_ref(),
+        &claim_data.id,
+    )
+    .await
+    .map_err(|err| {
+        ApiResponse::error(
+            500,
+            ErrorCode::CacheError,
+            format!("Failed to revoke sessions: {}", err),
+        )
+    })?;
+    Ok(ApiResponse::message(200, "Logged out from all devices"))
 }
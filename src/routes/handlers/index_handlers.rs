@@ -1,10 +1,40 @@
-use actix_web::{get, web};
+use actix_web::{get, http::header::CACHE_CONTROL, web, HttpResponse};
 
-use crate::utils::{api_response::ApiResponse, app_state};
+use crate::utils::{
+    api_response::{ApiResponse, ErrorCode},
+    app_state,
+};
 
 #[get("/index")]
 pub async fn index(
     #[allow(unused_variables)] app_state: web::Data<app_state::AppState>,
 ) -> Result<ApiResponse, ApiResponse> {
-    Ok(ApiResponse::new(200, format!("OK")))
+    Ok(ApiResponse::new(200, "OK"))
+}
+
+// Readiness probe, 503 while redis can't be reached since nearly every request needs it.
+// Doesn't count as activity for the idle shutdown.
+#[get("/ready")]
+pub async fn ready(app_state: web::Data<app_state::AppState>) -> ApiResponse {
+    let redis = app_state.redis_client.health().await;
+    if redis.healthy {
+        ApiResponse::new(200, serde_json::json!({ "redis": redis }))
+    } else {
+        ApiResponse::error(503, ErrorCode::NotReady, "Redis is unreachable")
+            .with_details(serde_json::json!({ "redis": redis }))
+    }
+}
+
+// fallback for every path no other route matched
+pub async fn not_found() -> ApiResponse {
+    ApiResponse::error(404, ErrorCode::NotFound, "Not found")
+}
+
+// Public keys other services use to verify our tokens. Plain JWKS without the { data, error }
+// envelope, that's what JWT libraries expect to find here.
+#[get("/.well-known/jwks.json")]
+pub async fn jwks(app_state: web::Data<app_state::AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "public, max-age=300"))
+        .json(app_state.key_ring.jwks())
 }
@@ -1,9 +1,16 @@
-use crate::utils::{api_response::ApiResponse, app_state};
-use actix_web::{post, web};
-use aws_sdk_bedrockruntime::primitives::Blob;
-use serde_json::json;
+use crate::utils::{
+    api_response::{ApiError, ApiResponse, ErrorCode},
+    app_state,
+    llm::{GenerationRequest, StreamEvent},
+    principal::Principal,
+    quota::{reserve_request, QuotaCheck, QuotaSlot},
+};
+use actix_web::{http::header, post, web, HttpResponse};
+use chrono::SecondsFormat;
+use futures_util::StreamExt;
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+// no Debug, so the prompt can't end up in a log line by accident
+#[derive(serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct VibeRequest {
     input_text: String,
@@ -12,37 +19,234 @@ struct VibeRequest {
 
 const REQUEST_MAX_TOKENS: u32 = 200;
 
-#[post("/map")]
+// validates the request and counts it against the caller's daily quota
+async fn prepare_request(
+    app_state: &app_state::AppState,
+    principal: &Principal,
+    vibe: &VibeRequest,
+) -> Result<(GenerationRequest, QuotaSlot), ApiResponse> {
+    let config = &app_state.config.llm;
+    let max_tokens = vibe.max_tokens.unwrap_or(REQUEST_MAX_TOKENS);
+    if max_tokens == 0 || max_tokens > config.max_tokens_limit {
+        return Err(ApiResponse::error(
+            400,
+            ErrorCode::InvalidMaxTokens,
+            format!(
+                "maxTokens must be between 1 and {}",
+                config.max_tokens_limit
+            ),
+        ));
+    }
+
+    let quota = reserve_request(&app_state.redis_client, config, &principal.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+    let slot = match quota {
+        QuotaCheck::Allowed(slot) => slot,
+        QuotaCheck::Exceeded(status) => {
+            return Err(ApiResponse::error(
+                429,
+                ErrorCode::QuotaExceeded,
+                format!(
+                    "Daily quota exceeded, resets at {}",
+                    status.reset_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+                ),
+            )
+            .with_header(
+                header::RETRY_AFTER,
+                status.seconds_until_reset().to_string(),
+            )
+            .with_details(status))
+        }
+    };
+
+    Ok((
+        GenerationRequest {
+            prompt: vibe.input_text.clone(),
+            max_tokens,
+        },
+        slot,
+    ))
+}
+
+// the provider failed before generating anything, so the request doesn't count
+async fn refund_request(app_state: &app_state::AppState, slot: &QuotaSlot, reserved_tokens: i64) {
+    if let Err(err) = slot.refund(&app_state.redis_client).await {
+        println!("[-] /map - failed to refund the request: {}", err);
+    }
+    if reserved_tokens > 0 {
+        if let Err(err) = slot
+            .record_tokens(&app_state.redis_client, -reserved_tokens)
+            .await
+        {
+            println!("[-] /map - failed to refund the reserved tokens: {}", err);
+        }
+    }
+}
+
+#[post("")]
 pub async fn index(
     app_state: web::Data<app_state::AppState>,
+    principal: Principal,
     vibe: web::Json<VibeRequest>,
 ) -> Result<ApiResponse, ApiResponse> {
-    println!("[+] /map - invoked {:#?}", vibe.0);
-
-    let mut request_body = json!({
-        "inputText": vibe.input_text,
-        "textGenerationConfig": {}
-    });
-    request_body["textGenerationConfig"]["maxTokenCount"] =
-        json!(vibe.max_tokens.unwrap_or(REQUEST_MAX_TOKENS));
-
-    let result = app_state
-        .bedrock_client
-        .invoke_model()
-        .model_id("amazon.titan-text-express-v1")
-        .content_type("application/json")
-        .body(Blob::new(serde_json::to_string(&request_body).unwrap()))
-        .send()
-        .await
-        .map_err(|err| {
+    // prompts and generations stay out of the logs, only who asked and how much
+    println!(
+        "[+] /map - invoked by {}, {} chars",
+        principal.id,
+        vibe.input_text.chars().count()
+    );
+
+    let (request, slot) = prepare_request(&app_state, &principal, &vibe).await?;
+
+    let generation = match app_state.llm_provider.generate(&request).await {
+        Ok(generation) => generation,
+        Err(err) => {
             println!("map_handlers /map response error: {:#?}", err);
-            ApiResponse::new(
+            refund_request(&app_state, &slot, 0).await;
+            return Err(ApiResponse::error(
                 500,
-                "The service is unable to respond at this time".to_string(),
-            )
-        })?;
+                ErrorCode::LlmUnavailable,
+                "The service is unable to respond at this time",
+            ));
+        }
+    };
+
+    println!(
+        "[+] /map - {} chars for {}, {} input and {} output tokens, stop reason {:?}",
+        generation.text.chars().count(),
+        principal.id,
+        generation.input_tokens,
+        generation.output_tokens,
+        generation.stop_reason
+    );
+    let tokens = generation.input_tokens + generation.output_tokens;
+    if let Err(err) = slot
+        .record_tokens(&app_state.redis_client, tokens as i64)
+        .await
+    {
+        println!("[-] /map - failed to record token usage: {}", err);
+    }
+    Ok(ApiResponse::new(200, generation))
+}
+
+// Logs when the client goes away mid-stream. Dropping the response body drops the
+// provider stream with it, which closes the bedrock connection. The tokens reserved for the
+// stream stay charged then, bedrock bills what it generated until the connection closed.
+struct StreamGuard {
+    finished: bool,
+}
+
+impl StreamGuard {
+    fn finish(&mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            println!("[-] /map/stream - ended before completion (client disconnected?), upstream stream dropped");
+        }
+    }
+}
+
+fn sse_event(event: &str, data: &impl serde::Serialize) -> web::Bytes {
+    web::Bytes::from(format!(
+        "event: {}\ndata: {}\n\n",
+        event,
+        serde_json::to_string(data).unwrap_or_default()
+    ))
+}
+
+// Same as /map but answers with text/event-stream: one "delta" event per chunk of
+// generated text, then a single "done" event with the stop reason and token counts.
+#[post("/stream")]
+pub async fn index_stream(
+    app_state: web::Data<app_state::AppState>,
+    principal: Principal,
+    vibe: web::Json<VibeRequest>,
+) -> Result<HttpResponse, ApiResponse> {
+    // as in /map, no prompt or generated text in the logs
+    println!(
+        "[+] /map/stream - invoked by {}, {} chars",
+        principal.id,
+        vibe.input_text.chars().count()
+    );
+
+    let (request, slot) = prepare_request(&app_state, &principal, &vibe).await?;
+
+    // usage is only known at the end, which the client can keep from coming by disconnecting.
+    // So the most the output can cost is charged now and swapped for the real count on done
+    let reserved_tokens = request.max_tokens as i64;
+    slot.record_tokens(&app_state.redis_client, reserved_tokens)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+
+    let generation = match app_state.llm_provider.generate_stream(&request).await {
+        Ok(generation) => generation,
+        Err(err) => {
+            println!("map_handlers /map/stream response error: {:#?}", err);
+            refund_request(&app_state, &slot, reserved_tokens).await;
+            return Err(ApiResponse::error(
+                500,
+                ErrorCode::LlmUnavailable,
+                "The service is unable to respond at this time",
+            ));
+        }
+    };
+
+    let mut guard = StreamGuard { finished: false };
+    let redis_client = app_state.redis_client.clone();
+    let body = generation
+        // stop forwarding after the done or error event
+        .scan(false, |ended, event| {
+            let item = if *ended { None } else { Some(event) };
+            *ended = matches!(item, Some(Ok(StreamEvent::Done(_))) | Some(Err(_)));
+            std::future::ready(item)
+        })
+        .map(move |event| {
+            let bytes = match event {
+                Ok(StreamEvent::Delta(text)) => {
+                    sse_event("delta", &serde_json::json!({ "text": text }))
+                }
+                Ok(StreamEvent::Done(summary)) => {
+                    guard.finish();
+                    println!("[+] /map/stream - done {:?}", summary);
+                    let redis_client = redis_client.clone();
+                    let slot = slot.clone();
+                    let tokens = (summary.input_tokens + summary.output_tokens) as i64;
+                    actix_web::rt::spawn(async move {
+                        if let Err(err) = slot
+                            .record_tokens(&redis_client, tokens - reserved_tokens)
+                            .await
+                        {
+                            println!("[-] /map/stream - failed to record token usage: {}", err);
+                        }
+                    });
+                    sse_event("done", &summary)
+                }
+                Err(err) => {
+                    // part of the output may already be billed, so the reservation stays
+                    guard.finish();
+                    println!("map_handlers /map/stream stream error: {:#?}", err);
+                    sse_event(
+                        "error",
+                        &ApiError {
+                            code: ErrorCode::LlmUnavailable,
+                            message: "The service is unable to respond at this time".to_string(),
+                            details: None,
+                        },
+                    )
+                }
+            };
+            Ok::<_, actix_web::Error>(bytes)
+        });
 
-    let output = std::str::from_utf8(result.body().as_ref()).unwrap();
-    println!("[+] /map - invoked - got response {}", output);
-    Ok(ApiResponse::new(200, output.to_string()))
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // keeps nginx / load balancers from buffering the whole stream
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body))
 }
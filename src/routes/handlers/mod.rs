@@ -1,4 +1,7 @@
+pub mod admin_handlers;
+pub mod api_key_handlers;
 pub mod auth_handlers;
 pub mod index_handlers;
 pub mod map_handlers;
 pub mod user_handlers;
+pub mod webhook_handlers;
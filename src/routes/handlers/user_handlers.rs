@@ -1,17 +1,182 @@
-use actix_web::{get, web};
-use aws_sdk_dynamodb::types::AttributeValue;
+use std::collections::BTreeMap;
+
+use actix_web::{delete, get, http::header::CONTENT_DISPOSITION, patch, post, web, HttpRequest};
+use chrono::{DateTime, Duration, Utc};
 
 use crate::utils::{
-    api_response::{self, ApiResponse},
-    app_state,
-    global_variables::DYNAMO_DB_TABLE_NAME,
+    api_key::ApiKey,
+    api_response::{self, ApiResponse, ErrorCode},
+    app_state::{self, AppState},
+    entitlement::{invalidate_entitlement_cache, Entitlements},
     jwt::Claims,
-    user::User,
+    login_attempts::{clear_login_failures, reserve_login_attempt, Reservation},
+    mailer::{email_change_email, send_in_background},
+    mfa::{
+        clear_pending_enrollment, generate_recovery_codes, generate_totp_secret, otpauth_uri,
+        pending_enrollment, store_pending_enrollment, verify_second_factor, verify_totp_code,
+        SecondFactor,
+    },
+    oidc::{OidcError, Provider},
+    one_time_token::issue_token,
+    password::{verify_password, PasswordCheck},
+    quota::{clear_usage, current_usage, QuotaUsage},
+    refresh_token::revoke_all_user_tokens,
+    session::{client_ip, list_sessions, Session},
+    token_version::forget_token_version,
+    user::{find_user_by_email, identity_name, normalize_email, ProfileUpdate, User, UserProfile},
 };
 
-#[derive(serde::Serialize, serde::Deserialize)]
+use super::auth_handlers::{
+    new_password_error, oidc_error, record_login_failure, second_factor_required, set_new_password,
+    too_many_login_attempts,
+};
+
+pub(crate) const EMAIL_CHANGE_TOKEN_PURPOSE: &str = "email_change";
+
+pub(crate) const MAX_NAME_LENGTH: usize = 100;
+const MAX_BIO_LENGTH: usize = 500;
+const MAX_URL_LENGTH: usize = 2048;
+const MAX_EMAIL_LENGTH: usize = 254;
+
+// every field is optional, only the ones sent get changed. Empty bio/avatarUrl clears them.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 struct UpdateUserModel {
-    name: String,
+    name: Option<String>,
+    bio: Option<String>,
+    avatar_url: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(serde::Deserialize)]
+struct ConfirmTotpRequest {
+    code: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DisableTotpRequest {
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+// Proof that the caller is the account owner, the same things a sign in takes: the password,
+// or a fresh ID token from a linked provider for accounts without one, plus the second
+// factor if two-factor is on
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeleteAccountRequest {
+    password: Option<String>,
+    provider: Option<String>,
+    id_token: Option<String>,
+    nonce: Option<String>,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+// everything we keep about a user, minus secrets like password and TOTP hashes
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UserExport {
+    exported_at: DateTime<Utc>,
+    profile: UserProfile,
+    disabled: bool,
+    has_password: bool,
+    linked_identities: Vec<String>,
+    recovery_codes_remaining: usize,
+    sessions: Vec<Session>,
+    api_keys: Vec<ApiKey>,
+    entitlements: Entitlements,
+    quota_usage: QuotaUsage,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TotpEnrollment {
+    secret: String,
+    otpauth_uri: String,
+    expires_in_seconds: i64,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+fn mfa_already_enabled() -> ApiResponse {
+    ApiResponse::error(
+        409,
+        ErrorCode::MfaAlreadyEnabled,
+        "Two-factor authentication is already enabled",
+    )
+}
+
+// what the email change token resolves to, see auth_handlers::confirm_email
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EmailChange {
+    pub(crate) user_id: String,
+    pub(crate) email: String,
+}
+
+pub(crate) fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+    email.len() <= MAX_EMAIL_LENGTH
+        && !local.is_empty()
+        && !domain.contains('@')
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !email.chars().any(char::is_whitespace)
+}
+
+pub(crate) fn too_long(max_length: usize) -> String {
+    format!("must be at most {} characters", max_length)
+}
+
+impl UpdateUserModel {
+    // all problems at once, keyed by field name, so forms can show them next to the inputs
+    fn validate(&self) -> BTreeMap<&'static str, String> {
+        let mut errors = BTreeMap::new();
+        if let Some(name) = &self.name {
+            if name.trim().is_empty() {
+                errors.insert("name", "must not be empty".to_string());
+            } else if name.chars().count() > MAX_NAME_LENGTH {
+                errors.insert("name", too_long(MAX_NAME_LENGTH));
+            }
+        }
+        if let Some(bio) = &self.bio {
+            if bio.chars().count() > MAX_BIO_LENGTH {
+                errors.insert("bio", too_long(MAX_BIO_LENGTH));
+            }
+        }
+        if let Some(avatar_url) = &self.avatar_url {
+            if avatar_url.len() > MAX_URL_LENGTH {
+                errors.insert("avatarUrl", too_long(MAX_URL_LENGTH));
+            } else if !avatar_url.is_empty()
+                && (!avatar_url.starts_with("https://")
+                    || avatar_url.chars().any(char::is_whitespace))
+            {
+                errors.insert("avatarUrl", "must be an https:// url".to_string());
+            }
+        }
+        if let Some(email) = &self.email {
+            if !is_valid_email(email) {
+                errors.insert("email", "must be a valid email address".to_string());
+            }
+        }
+        errors
+    }
 }
 
 #[get("")]
@@ -19,54 +184,524 @@ pub async fn user(
     app_state: web::Data<app_state::AppState>,
     claim_data: Claims,
 ) -> Result<ApiResponse, ApiResponse> {
-    let table_name = DYNAMO_DB_TABLE_NAME.clone();
-    let result = app_state
-        .dynamo_client
-        .query()
-        .table_name(table_name)
-        .key_condition_expression("id = :id")
-        .expression_attribute_values(":id", AttributeValue::S(claim_data.id))
-        .send()
-        .await
-        .map_err(|err| ApiResponse::new(500, err.to_string()))?;
-
-    let user = result
-        .items
-        .and_then(|items| items.first().cloned())
-        .ok_or_else(|| ApiResponse::new(404, "User not found".to_string()))
-        .and_then(|item| {
-            User::from_item(&item).map_err(|err| ApiResponse::new(500, err.to_string()))
-        })?;
-    Ok(api_response::ApiResponse::new(
+    let user = app_state
+        .user_repository
+        .get_user_from_id(&claim_data.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(|| ApiResponse::error(404, ErrorCode::UserNotFound, "User not found"))?;
+    Ok(api_response::ApiResponse::new(200, user.profile()))
+}
+
+#[patch("")]
+pub async fn update_user(
+    app_state: web::Data<app_state::AppState>,
+    request: web::Json<UpdateUserModel>,
+    claim_data: Claims,
+) -> Result<ApiResponse, ApiResponse> {
+    let errors = request.validate();
+    if !errors.is_empty() {
+        return Err(
+            ApiResponse::error(400, ErrorCode::ValidationFailed, "Invalid profile update")
+                .with_details(serde_json::json!({ "fields": errors })),
+        );
+    }
+
+    let current = app_state
+        .user_repository
+        .get_user_from_id(&claim_data.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(|| ApiResponse::error(404, ErrorCode::UserNotFound, "User not found"))?;
+
+    let mut update = ProfileUpdate {
+        name: request.name.as_ref().map(|name| name.trim().to_string()),
+        bio: request.bio.clone(),
+        avatar_url: request.avatar_url.clone(),
+        pending_email: None,
+    };
+
+    // the new address only replaces the current one once it's confirmed through the emailed token
+    let new_email = request
+        .email
+        .as_deref()
+        .map(normalize_email)
+        .filter(|email| *email != current.email);
+    if let Some(email) = &new_email {
+        let taken = find_user_by_email(app_state.user_repository.as_ref(), email)
+            .await
+            .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+            .is_some();
+        if taken {
+            return Err(ApiResponse::error(
+                409,
+                ErrorCode::EmailAlreadyInUse,
+                "Email is already in use",
+            ));
+        }
+        update.pending_email = Some(email.clone());
+    } else if request.email.is_some() && current.pending_email.is_some() {
+        // sending the current email again cancels a pending change
+        update.pending_email = Some(String::new());
+    }
+
+    let updated = app_state
+        .user_repository
+        .update_user_profile(&current.id, &update)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(|| ApiResponse::error(404, ErrorCode::UserNotFound, "User not found"))?;
+
+    if let Some(email) = new_email {
+        let change = EmailChange {
+            user_id: current.id.clone(),
+            email: email.clone(),
+        };
+        let token = issue_token(
+            &app_state.redis_client,
+            EMAIL_CHANGE_TOKEN_PURPOSE,
+            &change,
+            Duration::minutes(app_state.config.auth.email_change_token_minutes),
+        )
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+
+        send_in_background(
+            app_state.mailer.clone(),
+            email_change_email(&app_state.config.mail, &email, &updated.name, &token),
+        );
+    }
+
+    Ok(api_response::ApiResponse::new(200, updated.profile()))
+}
+
+#[post("/password")]
+pub async fn change_password(
+    app_state: web::Data<app_state::AppState>,
+    request: web::Json<ChangePasswordRequest>,
+    claim_data: Claims,
+) -> Result<ApiResponse, ApiResponse> {
+    if let Some(err) = new_password_error("newPassword", &request.new_password) {
+        return Err(err);
+    }
+
+    let current = app_state
+        .user_repository
+        .get_user_from_id(&claim_data.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(|| ApiResponse::error(404, ErrorCode::UserNotFound, "User not found"))?;
+
+    let password = request.current_password.clone();
+    let stored_hash = current.password.clone();
+    let check = web::block(move || verify_password(&password, &stored_hash))
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?
+        .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?;
+
+    // 403 rather than 401, the access token itself is fine
+    if let PasswordCheck::Invalid = check {
+        return Err(ApiResponse::error(
+            403,
+            ErrorCode::InvalidCredentials,
+            "Current password is incorrect",
+        ));
+    }
+
+    set_new_password(&app_state, &current.id, &request.new_password).await?;
+
+    Ok(ApiResponse::message(
         200,
-        format!("{{ 'name': '{}', 'email': '{}'}}", user.name, user.email),
+        "Password changed, please log in again",
     ))
 }
 
-// #[post("update")]
-// pub async fn update_user(
-//     app_state: web::Data<app_state::AppState>,
-//     user_data: web::Json<UpdateUserModel>,
-//     claim_data: Claims,
-// ) -> Result<ApiResponse, ApiResponse> {
-//     let mut user_model = entity::user::Entity::find_by_id(claim_data.id)
-//         .one(&app_state.db)
-//         .await
-//         .map_err(|err| api_response::ApiResponse::new(500, err.to_string()))?
-//         .ok_or(api_response::ApiResponse::new(
-//             404,
-//             "User not found".to_owned(),
-//         ))?
-//         .into_active_model();
-
-//     user_model.name = Set(user_data.name.clone());
-//     user_model
-//         .update(&app_state.db)
-//         .await
-//         .map_err(|err| api_response::ApiResponse::new(500, err.to_string()))?;
-
-//     Ok(api_response::ApiResponse::new(
-//         200,
-//         "User updated".to_owned(),
-//     ))
-// }
+// First step of turning on two-factor: a fresh secret for the authenticator app, which only
+// takes effect once /mfa/totp/confirm sees a code generated from it
+#[post("/mfa/totp")]
+pub async fn start_totp_enrollment(
+    app_state: web::Data<app_state::AppState>,
+    claim_data: Claims,
+) -> Result<ApiResponse, ApiResponse> {
+    let current = app_state
+        .user_repository
+        .get_user_from_id(&claim_data.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(|| ApiResponse::error(404, ErrorCode::UserNotFound, "User not found"))?;
+    if current.totp_secret.is_some() {
+        return Err(mfa_already_enabled());
+    }
+
+    let secret = generate_totp_secret()
+        .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?;
+    store_pending_enrollment(
+        &app_state.redis_client,
+        &app_state.config.auth,
+        &current.id,
+        &secret,
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+
+    Ok(ApiResponse::new(
+        200,
+        TotpEnrollment {
+            otpauth_uri: otpauth_uri(&secret, &current.email),
+            secret,
+            expires_in_seconds: Duration::minutes(app_state.config.auth.totp_enrollment_minutes)
+                .num_seconds(),
+        },
+    ))
+}
+
+#[post("/mfa/totp/confirm")]
+pub async fn confirm_totp_enrollment(
+    app_state: web::Data<app_state::AppState>,
+    request: web::Json<ConfirmTotpRequest>,
+    claim_data: Claims,
+) -> Result<ApiResponse, ApiResponse> {
+    let secret = pending_enrollment(&app_state.redis_client, &claim_data.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?
+        .ok_or_else(|| {
+            ApiResponse::error(
+                404,
+                ErrorCode::MfaEnrollmentNotFound,
+                "No enrollment in progress, it may have expired",
+            )
+        })?;
+
+    let valid = verify_totp_code(
+        &app_state.redis_client,
+        &claim_data.id,
+        &secret,
+        &request.code,
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+    if !valid {
+        return Err(ApiResponse::error(
+            400,
+            ErrorCode::InvalidMfaCode,
+            "Invalid code, check the time on your device",
+        ));
+    }
+
+    let (recovery_codes, hashes) = generate_recovery_codes()
+        .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?;
+    app_state
+        .user_repository
+        .enable_totp(&claim_data.id, &secret, &hashes)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(mfa_already_enabled)?;
+    if let Err(err) = clear_pending_enrollment(&app_state.redis_client, &claim_data.id).await {
+        println!("[-] failed to clear TOTP enrollment: {}", err);
+    }
+
+    // the only time the plain codes are shown
+    Ok(ApiResponse::new(200, RecoveryCodes { recovery_codes }))
+}
+
+#[post("/mfa/totp/disable")]
+pub async fn disable_totp(
+    app_state: web::Data<app_state::AppState>,
+    request: web::Json<DisableTotpRequest>,
+    claim_data: Claims,
+) -> Result<ApiResponse, ApiResponse> {
+    let factor =
+        SecondFactor::from_fields(request.code.as_deref(), request.recovery_code.as_deref())
+            .ok_or_else(second_factor_required)?;
+
+    let current = app_state
+        .user_repository
+        .get_user_from_id(&claim_data.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(|| ApiResponse::error(404, ErrorCode::UserNotFound, "User not found"))?;
+    if current.totp_secret.is_none() {
+        return Err(ApiResponse::error(
+            409,
+            ErrorCode::MfaNotEnabled,
+            "Two-factor authentication is not enabled",
+        ));
+    }
+
+    let verified = verify_second_factor(
+        &app_state.redis_client,
+        app_state.user_repository.as_ref(),
+        &current,
+        &factor,
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?;
+    // 403 like a wrong current password, the access token itself is fine
+    if !verified {
+        return Err(ApiResponse::error(
+            403,
+            ErrorCode::InvalidMfaCode,
+            "Invalid code",
+        ));
+    }
+
+    let updated = app_state
+        .user_repository
+        .disable_totp(&current.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(|| ApiResponse::error(404, ErrorCode::UserNotFound, "User not found"))?;
+    Ok(ApiResponse::new(200, updated.profile()))
+}
+
+// A stolen access token mustn't give endless guesses at the password or the codes, so they
+// count against the same limits as logins do
+async fn reauthenticate(
+    app_state: &AppState,
+    current: &User,
+    request: &DeleteAccountRequest,
+    ip: Option<&str>,
+) -> Result<(), ApiResponse> {
+    let reservation = reserve_login_attempt(
+        &app_state.redis_client,
+        &app_state.config.auth,
+        &current.email,
+        ip,
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+    let attempt = match reservation {
+        Reservation::Allowed(attempt) => attempt,
+        Reservation::RetryAfter(retry_after) => return Err(too_many_login_attempts(retry_after)),
+    };
+
+    match check_credentials(app_state, current, request).await {
+        Ok(Ok(())) => {
+            if let Err(err) = attempt.succeeded(&app_state.redis_client).await {
+                println!("[-] failed to clear login failures: {}", err);
+            }
+            Ok(())
+        }
+        Ok(Err(error)) => {
+            record_login_failure(app_state, attempt, Some(current)).await;
+            Err(error)
+        }
+        // nothing was guessed, e.g. the password was missing
+        Err(error) => {
+            if let Err(err) = attempt.released(&app_state.redis_client).await {
+                println!("[-] failed to release login attempt: {}", err);
+            }
+            Err(error)
+        }
+    }
+}
+
+// Ok(Err) when the credentials were wrong, Err when they couldn't be checked
+async fn check_credentials(
+    app_state: &AppState,
+    current: &User,
+    request: &DeleteAccountRequest,
+) -> Result<Result<(), ApiResponse>, ApiResponse> {
+    if !current.password.is_empty() {
+        let password = request.password.clone().ok_or_else(|| {
+            ApiResponse::error(
+                400,
+                ErrorCode::ReauthenticationRequired,
+                "Send your password to confirm",
+            )
+        })?;
+        let stored_hash = current.password.clone();
+        let check = web::block(move || verify_password(&password, &stored_hash))
+            .await
+            .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?
+            .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?;
+        if let PasswordCheck::Invalid = check {
+            return Ok(Err(ApiResponse::error(
+                403,
+                ErrorCode::InvalidCredentials,
+                "Password is incorrect",
+            )));
+        }
+    } else {
+        let (Some(provider), Some(id_token)) = (&request.provider, &request.id_token) else {
+            return Err(ApiResponse::error(
+                400,
+                ErrorCode::ReauthenticationRequired,
+                "Sign in with a linked provider again and send its provider and idToken",
+            ));
+        };
+        let provider = Provider::from_name(provider).ok_or_else(|| {
+            ApiResponse::error(
+                400,
+                ErrorCode::ReauthenticationRequired,
+                format!("'{}' is not linked to this account", provider),
+            )
+        })?;
+        let identity = match app_state
+            .oidc
+            .verify(provider, id_token, request.nonce.as_deref())
+            .await
+        {
+            Ok(identity) => identity,
+            Err(err @ OidcError::InvalidToken(_)) => return Ok(Err(oidc_error(provider, err))),
+            Err(err) => return Err(oidc_error(provider, err)),
+        };
+        if !current
+            .identities
+            .contains(&identity_name(provider.as_str(), &identity.subject))
+        {
+            return Ok(Err(ApiResponse::error(
+                403,
+                ErrorCode::InvalidIdToken,
+                "The ID token belongs to a different account",
+            )));
+        }
+    }
+
+    if current.totp_secret.is_some() {
+        let factor =
+            SecondFactor::from_fields(request.code.as_deref(), request.recovery_code.as_deref())
+                .ok_or_else(second_factor_required)?;
+        let verified = verify_second_factor(
+            &app_state.redis_client,
+            app_state.user_repository.as_ref(),
+            current,
+            &factor,
+        )
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::InternalError, err.to_string()))?;
+        if !verified {
+            return Ok(Err(ApiResponse::error(
+                403,
+                ErrorCode::InvalidMfaCode,
+                "Invalid code",
+            )));
+        }
+    }
+    Ok(Ok(()))
+}
+
+// Deletes the account for good: the user item, its linked identities, API keys and
+// entitlements, and whatever redis still holds for it. Every token of the user stops working
+// right away.
+#[delete("")]
+pub async fn delete_user(
+    app_state: web::Data<app_state::AppState>,
+    request: web::Json<DeleteAccountRequest>,
+    claim_data: Claims,
+    req: HttpRequest,
+) -> Result<ApiResponse, ApiResponse> {
+    let current = app_state
+        .user_repository
+        .get_user_from_id(&claim_data.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(|| ApiResponse::error(404, ErrorCode::UserNotFound, "User not found"))?;
+
+    let ip = client_ip(&req, &app_state.config.server);
+    reauthenticate(&app_state, &current, &request, ip.as_deref()).await?;
+
+    // before anything else is deleted, so a failure halfway leaves the user logged out (API keys
+    // included), not half gone
+    revoke_all_user_tokens(
+        &app_state.redis_client,
+        &app_state.config.jwt,
+        app_state.user_repository.as_ref(),
+        app_state.api_key_repository.as_ref(),
+        &current.id,
+    )
+    .await
+    .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+
+    app_state
+        .entitlement_repository
+        .delete_entitlements(&current.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
+    app_state
+        .user_repository
+        .delete_user(&current.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
+    println!("[!] {} deleted their account", current.id);
+
+    // the rest expires on its own, failing here only leaves it around a bit longer
+    let redis_client = &app_state.redis_client;
+    for (what, result) in [
+        (
+            "token version",
+            forget_token_version(redis_client, &current.id).await,
+        ),
+        (
+            "entitlement cache",
+            invalidate_entitlement_cache(redis_client, [&current.id]).await,
+        ),
+        (
+            "TOTP enrollment",
+            clear_pending_enrollment(redis_client, &current.id).await,
+        ),
+        // all /map keeps of a user, it stores no prompts or generations (see export_user)
+        ("quota usage", clear_usage(redis_client, &current.id).await),
+        (
+            "login failures",
+            clear_login_failures(redis_client, &current.email).await,
+        ),
+    ] {
+        if let Err(err) = result {
+            println!("[-] failed to clear {} of deleted user: {}", what, err);
+        }
+    }
+
+    Ok(ApiResponse::message(200, "Account deleted"))
+}
+
+#[get("/export")]
+pub async fn export_user(
+    app_state: web::Data<app_state::AppState>,
+    claim_data: Claims,
+) -> Result<ApiResponse, ApiResponse> {
+    let current = app_state
+        .user_repository
+        .get_user_from_id(&claim_data.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .ok_or_else(|| ApiResponse::error(404, ErrorCode::UserNotFound, "User not found"))?;
+
+    let sessions = list_sessions(&app_state.redis_client, &current.id, &claim_data.sid)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+    let api_keys = app_state
+        .api_key_repository
+        .list_api_keys(&current.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
+    // straight from the table, the cache may be a few seconds behind
+    let entitlements = app_state
+        .entitlement_repository
+        .get_entitlements(&current.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?
+        .entitlements;
+    // /map keeps no history, prompts and generations are neither stored nor logged. These
+    // counters are all that's left of them
+    let quota_usage = current_usage(&app_state.redis_client, &current.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::CacheError, err.to_string()))?;
+
+    let export = UserExport {
+        exported_at: Utc::now(),
+        profile: current.profile(),
+        disabled: current.disabled,
+        has_password: !current.password.is_empty(),
+        linked_identities: current.identities.clone(),
+        recovery_codes_remaining: current.recovery_codes.len(),
+        sessions,
+        api_keys,
+        entitlements,
+        quota_usage,
+    };
+    Ok(ApiResponse::new(200, export).with_header(
+        CONTENT_DISPOSITION,
+        "attachment; filename=\"artizans-export.json\"",
+    ))
+}
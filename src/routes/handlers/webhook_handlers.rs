@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use actix_web::{http::header::AUTHORIZATION, post, web, HttpRequest};
+
+use crate::utils::{
+    api_response::{ApiResponse, ErrorCode},
+    app_state,
+    entitlement::{
+        invalidate_entitlement_cache, EventOutcome, RevenueCatEvent, StoredEntitlements,
+    },
+    password::constant_time_eq,
+};
+
+// how often an event is recomputed when the entitlements change under it, after that
+// RevenueCat's retry gets another go
+const APPLY_ATTEMPTS: usize = 5;
+
+#[derive(Debug, serde::Deserialize)]
+struct RevenueCatWebhook {
+    event: RevenueCatEvent,
+}
+
+// RevenueCat retries anything that isn't a 200, so replays of an event we've
+// already stored are answered with 200 as well.
+#[post("/revenuecat")]
+pub async fn revenuecat(
+    app_state: web::Data<app_state::AppState>,
+    req: HttpRequest,
+    webhook: web::Json<RevenueCatWebhook>,
+) -> Result<ApiResponse, ApiResponse> {
+    let secret = app_state
+        .config
+        .revenuecat
+        .webhook_secret
+        .as_ref()
+        .ok_or_else(|| {
+            println!("[-] /webhooks/revenuecat - revenuecat.webhook_secret is not set");
+            ApiResponse::error(
+                503,
+                ErrorCode::WebhookNotConfigured,
+                "Webhook is not configured",
+            )
+        })?;
+    let authorized = req
+        .headers()
+        .get(AUTHORIZATION)
+        .is_some_and(|value| constant_time_eq(value.as_bytes(), secret.as_bytes()));
+    if !authorized {
+        return Err(ApiResponse::error(
+            401,
+            ErrorCode::InvalidWebhookAuthorization,
+            "Invalid Authorization header",
+        ));
+    }
+
+    let event = &webhook.event;
+    println!(
+        "[+] /webhooks/revenuecat - {:?} event {}",
+        event.event_type, event.id
+    );
+
+    let already_processed = app_state
+        .entitlement_repository
+        .is_event_processed(&event.id)
+        .await
+        .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
+    if already_processed {
+        return Ok(ApiResponse::message(200, "Event already processed"));
+    }
+
+    for _ in 0..APPLY_ATTEMPTS {
+        let mut stored = HashMap::new();
+        for user_id in event.affected_users() {
+            let entitlements = app_state
+                .entitlement_repository
+                .get_entitlements(&user_id)
+                .await
+                .map_err(|err| {
+                    ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string())
+                })?;
+            stored.insert(user_id, entitlements);
+        }
+
+        let current = stored
+            .iter()
+            .map(|(user_id, stored)| (user_id.clone(), stored.entitlements.clone()))
+            .collect();
+        let mut changed = event.apply(&current);
+        // users the event leaves as they are are written back too, their version is what
+        // tells whether another event changed them in the meantime
+        let updates: HashMap<String, StoredEntitlements> = stored
+            .into_iter()
+            .map(|(user_id, stored)| {
+                let entitlements = changed.remove(&user_id).unwrap_or(stored.entitlements);
+                (
+                    user_id,
+                    StoredEntitlements {
+                        entitlements,
+                        version: stored.version,
+                    },
+                )
+            })
+            .collect();
+        let outcome = app_state
+            .entitlement_repository
+            .apply_event(&event.id, &updates)
+            .await
+            .map_err(|err| ApiResponse::error(500, ErrorCode::DatabaseError, err.to_string()))?;
+        match outcome {
+            EventOutcome::Applied => {}
+            EventOutcome::AlreadyProcessed => {
+                return Ok(ApiResponse::message(200, "Event already processed"))
+            }
+            EventOutcome::Conflict => {
+                println!(
+                    "[!] /webhooks/revenuecat - entitlements changed while applying {}, retrying",
+                    event.id
+                );
+                continue;
+            }
+        }
+
+        if let Err(err) =
+            invalidate_entitlement_cache(&app_state.redis_client, updates.keys()).await
+        {
+            println!(
+                "[-] /webhooks/revenuecat - failed to clear entitlement cache: {}",
+                err
+            );
+        }
+        return Ok(ApiResponse::message(200, "Event processed"));
+    }
+
+    Err(ApiResponse::error(
+        500,
+        ErrorCode::DatabaseError,
+        "Entitlements kept changing while applying the event",
+    ))
+}
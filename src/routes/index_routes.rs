@@ -3,5 +3,8 @@ use actix_web::web;
 use super::handlers;
 
 pub fn config(config: &mut web::ServiceConfig) {
-    config.service(handlers::index_handlers::index);
+    config
+        .service(handlers::index_handlers::index)
+        .service(handlers::index_handlers::ready)
+        .service(handlers::index_handlers::jwks);
 }
@@ -1,7 +1,23 @@
-use actix_web::web;
+use actix_web::{middleware::from_fn, web};
 
-use super::handlers;
+use crate::utils::api_key::ApiKeyScope;
+
+use super::{handlers, middlewares};
 
 pub fn config(config: &mut web::ServiceConfig) {
-    config.service(handlers::map_handlers::index);
+    config.service(
+        web::scope("/map")
+            // wraps run bottom to top: auth first, then the paid entitlement check
+            .wrap(middlewares::entitlement_middleware::require_entitlement(
+                "pro",
+            ))
+            // LLM calls cost money, only logged in users, see utils::quota for the daily limits
+            .wrap(from_fn(middlewares::auth_middleware::check_auth_middleware))
+            // kiosks call this with a map scoped API key instead of logging in
+            .wrap(middlewares::api_key_middleware::accept_api_key(
+                ApiKeyScope::Map,
+            ))
+            .service(handlers::map_handlers::index)
+            .service(handlers::map_handlers::index_stream),
+    );
 }
@@ -0,0 +1,65 @@
+use std::rc::Rc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{Error, HttpMessage};
+use futures_util::future::LocalBoxFuture;
+
+use crate::utils::api_key::ApiKeyScope;
+
+// the scope check_auth_middleware asks of an X-Api-Key on this route
+#[derive(Clone, Copy)]
+pub(crate) struct AcceptedApiKeyScope(pub(crate) ApiKeyScope);
+
+// Lets check_auth_middleware take API keys with `scope` on top of Bearer tokens. Routes
+// without it only take tokens. It has to run before the auth check, so wrap it last, e.g.
+// .wrap(from_fn(check_auth_middleware)).wrap(accept_api_key(ApiKeyScope::Map))
+pub fn accept_api_key(scope: ApiKeyScope) -> AcceptApiKey {
+    AcceptApiKey { scope }
+}
+
+pub struct AcceptApiKey {
+    scope: ApiKeyScope,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AcceptApiKey
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AcceptApiKeyService<S>;
+    type Future = std::future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        std::future::ready(Ok(AcceptApiKeyService {
+            service: Rc::new(service),
+            scope: self.scope,
+        }))
+    }
+}
+
+pub struct AcceptApiKeyService<S> {
+    service: Rc<S>,
+    scope: ApiKeyScope,
+}
+
+impl<S, B> Service<ServiceRequest> for AcceptApiKeyService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        req.extensions_mut().insert(AcceptedApiKeyScope(self.scope));
+        Box::pin(self.service.call(req))
+    }
+}
@@ -2,68 +2,248 @@ use actix_web::middleware::Next;
 use actix_web::{
     body::MessageBody,
     dev::{ServiceRequest, ServiceResponse},
-    http::header::AUTHORIZATION,
+    http::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE},
     web, Error, HttpMessage,
 };
 
 use crate::utils::{
-    api_response::{self, ApiResponse},
+    api_key::{key_id, record_api_key_use},
+    api_response::{ApiResponse, ErrorCode},
     app_state::AppState,
     jwt::{decode_jwt, is_blacklisted},
+    jwt_keys::TokenError,
+    principal::{AuthMethod, Principal},
+    session::touch_session,
+    token_version::current_token_version,
 };
 
-// middleware that checks if the request has a valid token
+use super::api_key_middleware::AcceptedApiKeyScope;
+
+const REALM: &str = "artizans";
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+// 401 with a WWW-Authenticate challenge (RFC 6750); `error` is None when no credentials were sent
+fn unauthorized(code: ErrorCode, error: Option<&str>, message: &str) -> Error {
+    let challenge = match error {
+        Some(error) => format!(
+            "Bearer realm=\"{}\", error=\"{}\", error_description=\"{}\"",
+            REALM, error, message
+        ),
+        None => format!("Bearer realm=\"{}\"", REALM),
+    };
+    Error::from(ApiResponse::error(401, code, message).with_header(WWW_AUTHENTICATE, challenge))
+}
+
+fn invalid_token(code: ErrorCode, message: &str) -> Error {
+    unauthorized(code, Some("invalid_token"), message)
+}
+
+// The key's owner, for a request made with an API key. There are no Claims for it, so only
+// handlers that take a Principal work with keys.
+async fn authenticate_api_key(
+    req: &ServiceRequest,
+    app_state: &AppState,
+    key: &HeaderValue,
+) -> Result<Principal, Error> {
+    let Some(AcceptedApiKeyScope(scope)) = req.extensions().get::<AcceptedApiKeyScope>().copied()
+    else {
+        return Err(unauthorized(
+            ErrorCode::ApiKeyNotAccepted,
+            None,
+            "API keys are not accepted here, use a Bearer token",
+        ));
+    };
+    let invalid_key = || {
+        Error::from(ApiResponse::error(
+            401,
+            ErrorCode::InvalidApiKey,
+            "Invalid API key",
+        ))
+    };
+    let database_error = |err: anyhow::Error| {
+        Error::from(ApiResponse::error(
+            500,
+            ErrorCode::DatabaseError,
+            format!("Failed to check API key: {}", err),
+        ))
+    };
+
+    let key = key.to_str().map_err(|_| invalid_key())?;
+    let id = key_id(key).ok_or_else(invalid_key)?;
+    let api_key = app_state
+        .api_key_repository
+        .get_api_key(id)
+        .await
+        .map_err(database_error)?
+        .filter(|api_key| api_key.matches(key))
+        .ok_or_else(invalid_key)?;
+
+    // the owner's current role and state, not the ones from when the key was made
+    let user = app_state
+        .user_repository
+        .get_user_from_id(&api_key.user_id)
+        .await
+        .map_err(database_error)?
+        .ok_or_else(invalid_key)?;
+    if user.disabled {
+        return Err(Error::from(ApiResponse::error(
+            403,
+            ErrorCode::AccountDisabled,
+            "This account has been disabled",
+        )));
+    }
+
+    let principal = Principal {
+        id: user.id,
+        role: user.role,
+        method: AuthMethod::ApiKey {
+            key_id: api_key.id,
+            scopes: api_key.scopes,
+        },
+    };
+    if !principal.has_scope(scope) {
+        return Err(Error::from(
+            ApiResponse::error(
+                403,
+                ErrorCode::InsufficientScope,
+                format!(
+                    "This requires an API key with the '{}' scope",
+                    scope.as_str()
+                ),
+            )
+            .with_details(serde_json::json!({ "scope": scope.as_str() })),
+        ));
+    }
+
+    if let Err(err) = record_api_key_use(
+        &app_state.redis_client,
+        &app_state.config.api_keys,
+        app_state.api_key_repository.as_ref(),
+        id,
+    )
+    .await
+    {
+        println!("[-] failed to record API key use: {}", err);
+    }
+    Ok(principal)
+}
+
+// middleware that checks if the request has a valid token, or an API key where the route takes them
 pub async fn check_auth_middleware(
     req: ServiceRequest,
     next: Next<impl MessageBody>,
 ) -> Result<ServiceResponse<impl MessageBody>, Error> {
     let app_state = req.app_data::<web::Data<AppState>>().cloned().unwrap();
-    let auth = req.headers().get(AUTHORIZATION);
-    if auth.is_none() {
-        return Err(Error::from(api_response::ApiResponse::new(
-            401,
-            "Unauthorized".to_string(),
-        )));
+    if !req.headers().contains_key(AUTHORIZATION) {
+        if let Some(key) = req.headers().get(API_KEY_HEADER).cloned() {
+            let principal = authenticate_api_key(&req, &app_state, &key).await?;
+            req.extensions_mut().insert(principal);
+            return next.call(req).await;
+        }
     }
-    let token = auth
+
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
         .ok_or_else(|| {
-            Error::from(ApiResponse::new(
-                401,
-                "Missing Authorization header".to_string(),
-            ))
+            unauthorized(
+                ErrorCode::MissingAuthorization,
+                None,
+                "Missing Authorization header",
+            )
         })?
         .to_str()
         .map_err(|_| {
-            Error::from(ApiResponse::new(
-                401,
-                "Invalid Authorization header".to_string(),
-            ))
+            unauthorized(
+                ErrorCode::InvalidAuthorizationHeader,
+                Some("invalid_request"),
+                "Invalid Authorization header",
+            )
         })?
         .strip_prefix("Bearer ")
-        .ok_or_else(|| Error::from(ApiResponse::new(401, "Invalid token format".to_string())))?
+        .ok_or_else(|| {
+            unauthorized(
+                ErrorCode::InvalidTokenFormat,
+                Some("invalid_request"),
+                "Invalid token format",
+            )
+        })?
         .to_owned();
 
-    // Check if token is blacklisted meaning user logged out already within past 24 hours
-    match is_blacklisted(&app_state.redis_client, &token).await {
+    let claim = decode_jwt(&app_state.key_ring, &app_state.config.jwt, &token).map_err(|err| {
+        let code = match err {
+            TokenError::Malformed => ErrorCode::MalformedToken,
+            TokenError::InvalidSignature => ErrorCode::InvalidSignature,
+            TokenError::Expired => ErrorCode::TokenExpired,
+            TokenError::InvalidClaims => ErrorCode::InvalidTokenClaims,
+        };
+        invalid_token(code, err.description())
+    })?;
+
+    // Check if token is blacklisted meaning user logged out with it
+    match is_blacklisted(&app_state.redis_client, &claim.claims.jti).await {
         Ok(true) => {
-            return Err(Error::from(ApiResponse::new(
-                401,
-                "Token is invalid".to_string(),
-            )))
+            return Err(invalid_token(
+                ErrorCode::TokenRevoked,
+                "The token has been revoked",
+            ))
         }
         Ok(false) => {} // Token is not blacklisted, continue
         Err(e) => {
-            return Err(Error::from(ApiResponse::new(
+            return Err(Error::from(ApiResponse::error(
+                500,
+                ErrorCode::CacheError,
+                format!("Failed to check token: {}", e),
+            )))
+        }
+    }
+
+    // stale after a password change, logout-all or ban; None once the user is deleted
+    match current_token_version(
+        &app_state.redis_client,
+        &app_state.config.jwt,
+        app_state.user_repository.as_ref(),
+        &claim.claims.id,
+    )
+    .await
+    {
+        Ok(Some(version)) if version == claim.claims.ver => {}
+        Ok(_) => {
+            return Err(invalid_token(
+                ErrorCode::TokenRevoked,
+                "The token has been revoked",
+            ))
+        }
+        Err(e) => {
+            return Err(Error::from(ApiResponse::error(
                 500,
+                ErrorCode::CacheError,
                 format!("Failed to check token: {}", e),
             )))
         }
     }
 
-    let claim = decode_jwt(token).unwrap();
+    // logged out, or revoked from another device through /auth/sessions
+    match touch_session(&app_state.redis_client, &claim.claims.sid).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err(invalid_token(
+                ErrorCode::SessionRevoked,
+                "Session has been revoked",
+            ))
+        }
+        Err(e) => {
+            return Err(Error::from(ApiResponse::error(
+                500,
+                ErrorCode::CacheError,
+                format!("Failed to check session: {}", e),
+            )))
+        }
+    }
+
+    req.extensions_mut().insert(Principal::from(&claim.claims));
     req.extensions_mut().insert(claim.claims);
 
-    next.call(req)
-        .await
-        .map_err(|err| Error::from(ApiResponse::new(500, err.to_string())))
+    // errors from inner middlewares (e.g. a 403 from require_entitlement) already carry the right status
+    next.call(req).await
 }
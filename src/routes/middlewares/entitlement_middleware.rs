@@ -0,0 +1,115 @@
+use std::rc::Rc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpMessage};
+use futures_util::future::LocalBoxFuture;
+
+use crate::utils::{
+    api_response::{ApiResponse, ErrorCode},
+    app_state::AppState,
+    entitlement::get_cached_entitlements,
+    principal::Principal,
+};
+
+// Rejects requests from users without an active RevenueCat entitlement. Needs
+// check_auth_middleware to run first, e.g.
+// .wrap(require_entitlement("pro")).wrap(from_fn(check_auth_middleware))
+pub fn require_entitlement(entitlement: &str) -> RequireEntitlement {
+    RequireEntitlement {
+        entitlement: Rc::new(entitlement.to_string()),
+    }
+}
+
+pub struct RequireEntitlement {
+    entitlement: Rc<String>,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireEntitlement
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireEntitlementService<S>;
+    type Future = std::future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        std::future::ready(Ok(RequireEntitlementService {
+            service: Rc::new(service),
+            entitlement: self.entitlement.clone(),
+        }))
+    }
+}
+
+pub struct RequireEntitlementService<S> {
+    service: Rc<S>,
+    entitlement: Rc<String>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireEntitlementService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let entitlement = self.entitlement.clone();
+
+        Box::pin(async move {
+            let app_state = req.app_data::<web::Data<AppState>>().cloned().unwrap();
+            let principal = req
+                .extensions()
+                .get::<Principal>()
+                .cloned()
+                .ok_or_else(|| {
+                    Error::from(ApiResponse::error(
+                        401,
+                        ErrorCode::MissingAuthorization,
+                        "Unauthorized",
+                    ))
+                })?;
+
+            let entitlements = get_cached_entitlements(
+                &app_state.redis_client,
+                &app_state.config.revenuecat,
+                app_state.entitlement_repository.as_ref(),
+                &principal.id,
+            )
+            .await
+            .map_err(|err| {
+                Error::from(ApiResponse::error(
+                    500,
+                    ErrorCode::CacheError,
+                    format!("Failed to check entitlements: {}", err),
+                ))
+            })?;
+
+            let now_ms = chrono::Utc::now().timestamp_millis();
+            let active = entitlements
+                .get(entitlement.as_str())
+                .is_some_and(|state| state.is_active(now_ms));
+            if !active {
+                return Err(Error::from(
+                    ApiResponse::error(
+                        403,
+                        ErrorCode::MissingEntitlement,
+                        format!("This feature requires the '{}' entitlement", entitlement),
+                    )
+                    .with_details(serde_json::json!({ "entitlement": entitlement.as_str() })),
+                ));
+            }
+
+            service.call(req).await
+        })
+    }
+}
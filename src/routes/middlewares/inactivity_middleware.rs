@@ -6,6 +6,8 @@ use actix_web::Error;
 use futures_util::future::LocalBoxFuture;
 use std::time::Duration;
 
+const READY_PATH: &str = "/ready";
+
 pub struct LastActivityTime(pub Mutex<Instant>);
 pub struct InactivityMiddleware {
     pub last_activity: Arc<LastActivityTime>,
@@ -53,10 +55,13 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let last_activity = self.last_activity.clone();
-        let mut last_activity = last_activity.0.lock().unwrap();
-        *last_activity = Instant::now();
-        drop(last_activity);
+        // readiness probes come in regularly and would keep the server up forever
+        if req.path() != READY_PATH {
+            let last_activity = self.last_activity.clone();
+            let mut last_activity = last_activity.0.lock().unwrap();
+            *last_activity = Instant::now();
+            drop(last_activity);
+        }
 
         let fut = self.service.call(req);
         Box::pin(async move {
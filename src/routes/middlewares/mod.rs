@@ -1,2 +1,5 @@
+pub mod api_key_middleware;
 pub mod auth_middleware;
+pub mod entitlement_middleware;
 pub mod inactivity_middleware;
+pub mod role_middleware;
@@ -0,0 +1,92 @@
+use std::rc::Rc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{Error, HttpMessage};
+use futures_util::future::LocalBoxFuture;
+
+use crate::utils::{
+    api_response::{ApiResponse, ErrorCode},
+    principal::Principal,
+    user::Role,
+};
+
+// Rejects requests from users below `role`, read from the token so it costs no lookup. Needs
+// check_auth_middleware to run first, e.g.
+// .wrap(require_role(Role::Admin)).wrap(from_fn(check_auth_middleware))
+pub fn require_role(role: Role) -> RequireRole {
+    RequireRole { role }
+}
+
+pub struct RequireRole {
+    role: Role,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireRoleService<S>;
+    type Future = std::future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        std::future::ready(Ok(RequireRoleService {
+            service: Rc::new(service),
+            role: self.role,
+        }))
+    }
+}
+
+pub struct RequireRoleService<S> {
+    service: Rc<S>,
+    role: Role,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireRoleService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let role = self.role;
+
+        Box::pin(async move {
+            let principal = req
+                .extensions()
+                .get::<Principal>()
+                .cloned()
+                .ok_or_else(|| {
+                    Error::from(ApiResponse::error(
+                        401,
+                        ErrorCode::MissingAuthorization,
+                        "Unauthorized",
+                    ))
+                })?;
+
+            if principal.role < role {
+                return Err(Error::from(
+                    ApiResponse::error(
+                        403,
+                        ErrorCode::InsufficientRole,
+                        format!("This requires the '{}' role", role.as_str()),
+                    )
+                    .with_details(serde_json::json!({ "role": role.as_str() })),
+                ));
+            }
+
+            service.call(req).await
+        })
+    }
+}
@@ -1,7 +1,28 @@
 pub mod handlers;
 pub mod middlewares;
 
+pub mod admin_routes;
 pub mod auth_routes;
 pub mod index_routes;
 pub mod map_routes;
 pub mod user_routes;
+pub mod webhook_routes;
+
+use actix_web::web;
+
+use crate::utils::api_response::{json_error_handler, query_error_handler};
+
+// Everything an App needs besides AppState and the server-wide middlewares, shared by main
+// and the tests so they run the same routes
+pub fn config(config: &mut web::ServiceConfig) {
+    config
+        .app_data(web::JsonConfig::default().error_handler(json_error_handler))
+        .app_data(web::QueryConfig::default().error_handler(query_error_handler))
+        .configure(auth_routes::config)
+        .configure(user_routes::config)
+        .configure(index_routes::config)
+        .configure(map_routes::config)
+        .configure(webhook_routes::config)
+        .configure(admin_routes::config)
+        .default_service(web::to(handlers::index_handlers::not_found));
+}
@@ -9,6 +9,16 @@ pub fn config(config: &mut web::ServiceConfig) {
         web::scope("/user")
             // this wrap sets middleware for user authentication. the .service() after this line will be affected by this middleware
             .wrap(from_fn(middlewares::auth_middleware::check_auth_middleware))
-            .service(handlers::user_handlers::user), // .service(handlers::user_handlers::update_user),
+            .service(handlers::user_handlers::user)
+            .service(handlers::user_handlers::update_user)
+            .service(handlers::user_handlers::delete_user)
+            .service(handlers::user_handlers::export_user)
+            .service(handlers::user_handlers::change_password)
+            .service(handlers::user_handlers::start_totp_enrollment)
+            .service(handlers::user_handlers::confirm_totp_enrollment)
+            .service(handlers::user_handlers::disable_totp)
+            .service(handlers::api_key_handlers::create_api_key)
+            .service(handlers::api_key_handlers::list_api_keys)
+            .service(handlers::api_key_handlers::revoke_api_key),
     );
 }
@@ -0,0 +1,8 @@
+use actix_web::web;
+
+use super::handlers;
+
+// called by third parties, each handler checks its own shared secret instead of a user JWT
+pub fn config(config: &mut web::ServiceConfig) {
+    config.service(web::scope("/webhooks").service(handlers::webhook_handlers::revenuecat));
+}
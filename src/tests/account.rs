@@ -0,0 +1,192 @@
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{
+    bearer, call, grant_pro, login, register, test_app, test_config, test_state, unique_email,
+    TEST_PASSWORD,
+};
+
+#[actix_web::test]
+async fn users_can_export_and_delete_their_account() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state.clone()).await;
+    let email = unique_email();
+    let user_id = register(&app, &email).await;
+    grant_pro(&state, &user_id).await;
+    let (access_token, refresh_token) = login(&app, &email).await;
+
+    let (status, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user/export")
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"]["profile"]["email"], email);
+    assert_eq!(body["data"]["hasPassword"], true);
+    assert_eq!(body["data"]["sessions"].as_array().unwrap().len(), 1);
+    assert!(body["data"]["entitlements"]["pro"].is_object());
+    assert!(body["data"].get("password").is_none());
+
+    // only with the password
+    for (password, status, code) in [
+        (None, 400, "reauthentication_required"),
+        (Some("wrong password"), 403, "invalid_credentials"),
+    ] {
+        let (actual, body) = call(
+            &app,
+            TestRequest::delete()
+                .uri("/user")
+                .insert_header(bearer(&access_token))
+                .set_json(json!({ "password": password })),
+        )
+        .await;
+        assert_eq!(actual, status, "{}", body);
+        assert_eq!(body["error"]["code"], code);
+    }
+
+    let (status, body) = call(
+        &app,
+        TestRequest::delete()
+            .uri("/user")
+            .insert_header(bearer(&access_token))
+            .set_json(json!({ "password": TEST_PASSWORD })),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+
+    let (status, _) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(status, 401);
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(json!({ "refreshToken": refresh_token })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/login")
+            .set_json(json!({ "email": email, "password": TEST_PASSWORD })),
+    )
+    .await;
+    assert_eq!(status, 401);
+
+    // the address is free again
+    register(&app, &email).await;
+}
+
+#[actix_web::test]
+async fn deleting_the_account_ends_every_session() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+    let email = unique_email();
+    register(&app, &email).await;
+
+    let (phone_access_token, phone_refresh_token) = login(&app, &email).await;
+    let (access_token, refresh_token) = login(&app, &email).await;
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/user/api-keys")
+            .insert_header(bearer(&access_token))
+            .set_json(json!({ "name": "Kiosk", "scopes": ["map"] })),
+    )
+    .await;
+    assert_eq!(status, 201, "{}", body);
+    let api_key = body["data"]["key"].as_str().unwrap().to_string();
+
+    let (status, body) = call(
+        &app,
+        TestRequest::delete()
+            .uri("/user")
+            .insert_header(bearer(&access_token))
+            .set_json(json!({ "password": TEST_PASSWORD })),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+
+    for token in [&phone_access_token, &access_token] {
+        let (status, _) = call(
+            &app,
+            TestRequest::get().uri("/user").insert_header(bearer(token)),
+        )
+        .await;
+        assert_eq!(status, 401);
+    }
+    for token in [&phone_refresh_token, &refresh_token] {
+        let (status, _) = call(
+            &app,
+            TestRequest::post()
+                .uri("/auth/refresh")
+                .set_json(json!({ "refreshToken": token })),
+        )
+        .await;
+        assert_eq!(status, 401);
+    }
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri("/map")
+            .insert_header(("X-Api-Key", api_key.as_str()))
+            .set_json(json!({ "inputText": "draw a cat" })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/login")
+            .set_json(json!({ "email": email, "password": TEST_PASSWORD })),
+    )
+    .await;
+    assert_eq!(status, 401);
+}
+
+#[actix_web::test]
+async fn wrong_passwords_on_delete_count_against_the_login_limits() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state.clone()).await;
+    let email = unique_email();
+    register(&app, &email).await;
+    let (access_token, _) = login(&app, &email).await;
+
+    let delete = |password: &'static str| {
+        call(
+            &app,
+            TestRequest::delete()
+                .uri("/user")
+                .insert_header(bearer(&access_token))
+                .set_json(json!({ "password": password })),
+        )
+    };
+
+    for _ in 0..3 {
+        let (status, body) = delete("wrong password").await;
+        assert_eq!(status, 403, "{}", body);
+        assert_eq!(body["error"]["code"], "invalid_credentials");
+    }
+
+    // even the right one waits now, and so does signing in
+    let (status, body) = delete(TEST_PASSWORD).await;
+    assert_eq!(status, 429, "{}", body);
+    assert_eq!(body["error"]["code"], "too_many_login_attempts");
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/login")
+            .set_json(json!({ "email": email, "password": TEST_PASSWORD })),
+    )
+    .await;
+    assert_eq!(status, 429, "{}", body);
+}
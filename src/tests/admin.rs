@@ -0,0 +1,111 @@
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{bearer, call, login, register, test_app, test_config, test_state, unique_email};
+use crate::utils::user::Role;
+
+// user ids contain a '#', which has to be escaped in a path
+fn path(id: &str) -> String {
+    id.replace('#', "%23")
+}
+
+#[actix_web::test]
+async fn admins_manage_users() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state.clone()).await;
+
+    let admin_email = unique_email();
+    let admin_id = register(&app, &admin_email).await;
+    state
+        .user_repository
+        .set_user_role(&admin_id, Role::Admin)
+        .await
+        .unwrap();
+    let (admin, _) = login(&app, &admin_email).await;
+
+    let email = unique_email();
+    let id = register(&app, &email).await;
+    let (user, _) = login(&app, &email).await;
+
+    // the role comes from the token
+    let (status, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/admin/users")
+            .insert_header(bearer(&user)),
+    )
+    .await;
+    assert_eq!(status, 403);
+    assert_eq!(body["error"]["code"], "insufficient_role");
+    assert_eq!(body["error"]["details"]["role"], "admin");
+
+    let (status, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/admin/users?limit=0")
+            .insert_header(bearer(&admin)),
+    )
+    .await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"]["code"], "validation_failed");
+
+    let (status, body) = call(
+        &app,
+        TestRequest::get()
+            .uri(&format!("/admin/users/{}", path(&id)))
+            .insert_header(bearer(&admin)),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"]["email"], email);
+    assert_eq!(body["data"]["disabled"], false);
+
+    let (status, body) = call(
+        &app,
+        TestRequest::put()
+            .uri(&format!("/admin/users/{}/role", path(&admin_id)))
+            .insert_header(bearer(&admin))
+            .set_json(json!({ "role": "user" })),
+    )
+    .await;
+    assert_eq!(status, 409);
+    assert_eq!(body["error"]["code"], "cannot_modify_self");
+
+    // disabling signs the user out and keeps them out
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri(&format!("/admin/users/{}/disable", path(&id)))
+            .insert_header(bearer(&admin)),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"]["disabled"], true);
+
+    let (status, _) = call(
+        &app,
+        TestRequest::get().uri("/user").insert_header(bearer(&user)),
+    )
+    .await;
+    assert_eq!(status, 401);
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/login")
+            .set_json(json!({ "email": email, "password": super::TEST_PASSWORD })),
+    )
+    .await;
+    assert_eq!(status, 403);
+    assert_eq!(body["error"]["code"], "account_disabled");
+
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri(&format!("/admin/users/{}/enable", path(&id)))
+            .insert_header(bearer(&admin)),
+    )
+    .await;
+    assert_eq!(status, 200);
+    login(&app, &email).await;
+}
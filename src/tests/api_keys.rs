@@ -0,0 +1,187 @@
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{
+    bearer, call, grant_pro, login, register, test_app, test_config, test_state, unique_email,
+    TEST_PASSWORD,
+};
+use crate::utils::user::Role;
+
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+#[actix_web::test]
+async fn keys_work_within_their_scopes_until_revoked() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state.clone()).await;
+    let email = unique_email();
+    let user_id = register(&app, &email).await;
+    grant_pro(&state, &user_id).await;
+    let (token, _) = login(&app, &email).await;
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/user/api-keys")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "name": "Kiosk", "scopes": ["map"] })),
+    )
+    .await;
+    assert_eq!(status, 201, "{}", body);
+    let key_id = body["data"]["id"].as_str().unwrap().to_string();
+    let key = body["data"]["key"].as_str().unwrap().to_string();
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/map")
+            .insert_header((API_KEY_HEADER, key.as_str()))
+            .set_json(json!({ "inputText": "draw a cat" })),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"]["text"], "echo: draw a cat");
+
+    // a route that takes keys, but not map scoped ones
+    let (status, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/admin/users")
+            .insert_header((API_KEY_HEADER, key.as_str())),
+    )
+    .await;
+    assert_eq!(status, 403);
+    assert_eq!(body["error"]["code"], "insufficient_scope");
+    assert_eq!(body["error"]["details"]["scope"], "admin");
+
+    // and one that takes no keys at all
+    let (status, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header((API_KEY_HEADER, key.as_str())),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "api_key_not_accepted");
+
+    let (status, _) = call(
+        &app,
+        TestRequest::delete()
+            .uri(&format!("/user/api-keys/{}", key_id))
+            .insert_header(bearer(&token)),
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/map")
+            .insert_header((API_KEY_HEADER, key.as_str()))
+            .set_json(json!({ "inputText": "draw a cat" })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "invalid_api_key");
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/map")
+            .insert_header((API_KEY_HEADER, "artz_not-a-real-key"))
+            .set_json(json!({ "inputText": "draw a cat" })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "invalid_api_key");
+}
+
+#[actix_web::test]
+async fn keys_cannot_make_keys_and_go_with_the_tokens() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state.clone()).await;
+    let email = unique_email();
+    let user_id = register(&app, &email).await;
+    state
+        .user_repository
+        .set_user_role(&user_id, Role::Admin)
+        .await
+        .unwrap();
+    grant_pro(&state, &user_id).await;
+    let (token, _) = login(&app, &email).await;
+
+    let create_key = |token: String, scopes: serde_json::Value| {
+        TestRequest::post()
+            .uri("/user/api-keys")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "name": "Kiosk", "scopes": scopes }))
+    };
+
+    let (status, body) = call(&app, create_key(token.clone(), json!(["admin"]))).await;
+    assert_eq!(status, 201, "{}", body);
+    let key = body["data"]["key"].as_str().unwrap().to_string();
+
+    // an admin key can reach the admin routes, but not mint another key there
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri(&format!(
+                "/admin/users/{}/api-keys",
+                user_id.replace('#', "%23")
+            ))
+            .insert_header((API_KEY_HEADER, key.as_str()))
+            .set_json(json!({ "name": "Spare", "scopes": ["map"] })),
+    )
+    .await;
+    assert_eq!(status, 403);
+    assert_eq!(body["error"]["code"], "api_key_not_accepted");
+
+    // signing out everywhere takes the keys with it
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/logout-all")
+            .insert_header(bearer(&token)),
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    let (status, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/admin/users")
+            .insert_header((API_KEY_HEADER, key.as_str())),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "invalid_api_key");
+
+    // and so does a new password
+    let (token, _) = login(&app, &email).await;
+    let (status, body) = call(&app, create_key(token.clone(), json!(["map"]))).await;
+    assert_eq!(status, 201, "{}", body);
+    let key = body["data"]["key"].as_str().unwrap().to_string();
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/user/password")
+            .insert_header(bearer(&token))
+            .set_json(
+                json!({ "currentPassword": TEST_PASSWORD, "newPassword": "a new passphrase" }),
+            ),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/map")
+            .insert_header((API_KEY_HEADER, key.as_str()))
+            .set_json(json!({ "inputText": "draw a cat" })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "invalid_api_key");
+}
@@ -0,0 +1,540 @@
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use chrono::Duration;
+
+use crate::routes::handlers::auth_handlers::{EmailVerification, EMAIL_VERIFICATION_TOKEN_PURPOSE};
+use crate::utils::{
+    mfa::generate_totp_secret,
+    one_time_token::issue_token,
+    password::hash_password,
+    user::{Role, User},
+};
+
+use super::{
+    bearer, call, login, register, test_app, test_config, test_state, unique_email, TEST_PASSWORD,
+};
+
+#[actix_web::test]
+async fn register_login_refresh_logout() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+    let email = unique_email();
+
+    let user_id = register(&app, &email).await;
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/register")
+            .set_json(json!({ "name": "Again", "email": email, "password": "another password" })),
+    )
+    .await;
+    assert_eq!(status, 409);
+    assert_eq!(body["error"]["code"], "user_already_exists");
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/login")
+            .set_json(json!({ "email": email, "password": "wrong password" })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "invalid_credentials");
+
+    let (access_token, refresh_token) = login(&app, &email).await;
+
+    let (status, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["data"]["id"], user_id);
+    assert_eq!(body["data"]["email"], email);
+
+    let (status, body) = call(&app, TestRequest::get().uri("/user")).await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "missing_authorization");
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(json!({ "refreshToken": refresh_token })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let access_token = body["data"]["accessToken"].as_str().unwrap().to_string();
+    let refresh_token = body["data"]["refreshToken"].as_str().unwrap().to_string();
+
+    let (status, _) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/logout")
+            .insert_header(bearer(&access_token))
+            .set_json(json!({ "refreshToken": refresh_token })),
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    // neither the access token nor its refresh token work anymore
+    let (status, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "token_revoked");
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(json!({ "refreshToken": refresh_token })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "invalid_refresh_token");
+}
+
+#[actix_web::test]
+async fn reusing_a_rotated_refresh_token_revokes_the_session() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+    let email = unique_email();
+    register(&app, &email).await;
+
+    let (_, stolen_refresh_token) = login(&app, &email).await;
+    let (other_access_token, _) = login(&app, &email).await;
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(json!({ "refreshToken": stolen_refresh_token })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let access_token = body["data"]["accessToken"].as_str().unwrap().to_string();
+    let refresh_token = body["data"]["refreshToken"].as_str().unwrap().to_string();
+
+    // whoever else holds the old token presents it again
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(json!({ "refreshToken": stolen_refresh_token })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "refresh_token_reused");
+
+    // that ends the session for both of them, rotated tokens included
+    let (status, _) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(status, 401);
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(json!({ "refreshToken": refresh_token })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "invalid_refresh_token");
+
+    // other sessions of the user are left alone
+    let (status, _) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(&other_access_token)),
+    )
+    .await;
+    assert_eq!(status, 200);
+}
+
+#[actix_web::test]
+async fn sessions_can_be_listed_and_revoked() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+    let email = unique_email();
+    register(&app, &email).await;
+    let (access_token, _) = login(&app, &email).await;
+    let (other_access_token, other_refresh_token) = login(&app, &email).await;
+
+    let (status, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/auth/sessions")
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let sessions = body["data"].as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(
+        sessions
+            .iter()
+            .filter(|session| session["current"] == true)
+            .count(),
+        1
+    );
+    let other_session_id = sessions
+        .iter()
+        .find(|session| session["current"] == false)
+        .unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let (status, _) = call(
+        &app,
+        TestRequest::delete()
+            .uri(&format!("/auth/sessions/{}", other_session_id))
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let (status, body) = call(
+        &app,
+        TestRequest::delete()
+            .uri(&format!("/auth/sessions/{}", other_session_id))
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(status, 404);
+    assert_eq!(body["error"]["code"], "session_not_found");
+
+    // the revoked device is logged out right away
+    let (status, _) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(&other_access_token)),
+    )
+    .await;
+    assert_eq!(status, 401);
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(json!({ "refreshToken": other_refresh_token })),
+    )
+    .await;
+    assert_eq!(status, 401);
+
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/logout-all")
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let (status, _) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(status, 401);
+}
+
+#[actix_web::test]
+async fn unknown_paths_get_the_json_404() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+
+    let (status, body) = call(&app, TestRequest::get().uri("/nope")).await;
+    assert_eq!(status, 404);
+    assert_eq!(body["error"]["code"], "not_found");
+}
+
+#[actix_web::test]
+async fn ready_while_redis_is_reachable() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+
+    let (status, body) = call(&app, TestRequest::get().uri("/ready")).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"]["redis"]["healthy"], true);
+}
+
+#[actix_web::test]
+async fn changing_the_password_logs_out_everywhere() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+    let email = unique_email();
+    register(&app, &email).await;
+    let (access_token, refresh_token) = login(&app, &email).await;
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/user/password")
+            .insert_header(bearer(&access_token))
+            .set_json(
+                json!({ "currentPassword": "wrong password", "newPassword": "a new passphrase" }),
+            ),
+    )
+    .await;
+    assert_eq!(status, 403);
+    assert_eq!(body["error"]["code"], "invalid_credentials");
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/user/password")
+            .insert_header(bearer(&access_token))
+            .set_json(
+                json!({ "currentPassword": TEST_PASSWORD, "newPassword": "a new passphrase" }),
+            ),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+
+    let (status, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "token_revoked");
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(json!({ "refreshToken": refresh_token })),
+    )
+    .await;
+    assert_eq!(status, 401);
+
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/login")
+            .set_json(json!({ "email": email, "password": TEST_PASSWORD })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/login")
+            .set_json(json!({ "email": email, "password": "a new passphrase" })),
+    )
+    .await;
+    assert_eq!(status, 200);
+}
+
+#[actix_web::test]
+async fn reset_tokens_are_checked() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/password/reset")
+            .set_json(json!({ "token": "made-up", "newPassword": "a new passphrase" })),
+    )
+    .await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"]["code"], "invalid_reset_token");
+
+    // weak passwords are refused before the token is looked at
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/password/reset")
+            .set_json(json!({ "token": "made-up", "newPassword": "short" })),
+    )
+    .await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"]["code"], "validation_failed");
+}
+
+#[actix_web::test]
+async fn verification_tokens_work_once() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state.clone()).await;
+    let email = unique_email();
+    let user_id = register(&app, &email).await;
+
+    // the same token the registration mailed out
+    let token = issue_token(
+        &state.redis_client,
+        EMAIL_VERIFICATION_TOKEN_PURPOSE,
+        &EmailVerification {
+            user_id,
+            email: email.clone(),
+        },
+        Duration::hours(1),
+    )
+    .await
+    .unwrap();
+    let verify = || {
+        call(
+            &app,
+            TestRequest::post()
+                .uri("/auth/verify-email")
+                .set_json(json!({ "token": token })),
+        )
+    };
+
+    let (status, body) = verify().await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"]["emailVerified"], true);
+
+    let (status, body) = verify().await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"]["code"], "invalid_verification_token");
+}
+
+#[actix_web::test]
+async fn bad_tokens_get_a_401_with_the_reason() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+
+    for (header, code) in [
+        ("Basic abc".to_string(), "invalid_token_format"),
+        ("Bearer not-a-jwt".to_string(), "malformed_token"),
+    ] {
+        let request = TestRequest::get()
+            .uri("/user")
+            .insert_header(("Authorization", header));
+        let (status, body) = call(&app, request).await;
+        assert_eq!(status, 401);
+        assert_eq!(body["error"]["code"], code);
+    }
+}
+
+#[actix_web::test]
+async fn emails_match_whatever_their_case() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+    let email = unique_email();
+
+    register(&app, &format!(" {} ", email.to_uppercase())).await;
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/register")
+            .set_json(json!({ "name": "Again", "email": email, "password": "another password" })),
+    )
+    .await;
+    assert_eq!(status, 409);
+    assert_eq!(body["error"]["code"], "user_already_exists");
+
+    let (access_token, _) = login(&app, &email).await;
+    let (_, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(body["data"]["email"], email);
+}
+
+#[actix_web::test]
+async fn accounts_from_before_normalization_can_still_log_in() {
+    let state = test_state(test_config()).await;
+    let email = format!("Old.{}", unique_email());
+    let user = User {
+        id: format!("USER#{}", uuid::Uuid::new_v4()),
+        name: "Old".to_string(),
+        email: email.clone(),
+        password: hash_password(TEST_PASSWORD).unwrap(),
+        verified: true,
+        token_version: 0,
+        role: Role::User,
+        disabled: false,
+        identities: Vec::new(),
+        totp_secret: None,
+        recovery_codes: Vec::new(),
+        bio: None,
+        avatar_url: None,
+        pending_email: None,
+    };
+    state.user_repository.create_user(&user).await.unwrap();
+    let app = test_app(state).await;
+
+    login(&app, &email).await;
+
+    // taking the address again in another case would leave two accounts for one inbox
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/register")
+            .set_json(json!({ "name": "Again", "email": email, "password": "another password" })),
+    )
+    .await;
+    assert_eq!(status, 409);
+}
+
+#[actix_web::test]
+async fn wrong_codes_count_against_the_login_limits() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state.clone()).await;
+    let email = unique_email();
+    let user_id = register(&app, &email).await;
+    state
+        .user_repository
+        .enable_totp(&user_id, &generate_totp_secret().unwrap(), &[])
+        .await
+        .unwrap();
+
+    let log_in = || {
+        call(
+            &app,
+            TestRequest::post()
+                .uri("/auth/login")
+                .set_json(json!({ "email": email, "password": TEST_PASSWORD })),
+        )
+    };
+    let guess = |mfa_token: String| {
+        call(
+            &app,
+            TestRequest::post()
+                .uri("/auth/login/mfa")
+                .set_json(json!({ "mfaToken": mfa_token, "code": "wrong!" })),
+        )
+    };
+
+    // a fresh challenge for every guess still runs into the delay after the third
+    for _ in 0..3 {
+        let (status, body) = log_in().await;
+        assert_eq!(status, 200);
+        let mfa_token = body["data"]["mfaToken"].as_str().unwrap().to_string();
+        let (status, body) = guess(mfa_token).await;
+        assert_eq!(status, 401);
+        assert_eq!(body["error"]["code"], "invalid_mfa_code");
+    }
+
+    let (status, body) = log_in().await;
+    assert_eq!(status, 429);
+    assert_eq!(body["error"]["code"], "too_many_login_attempts");
+}
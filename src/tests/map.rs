@@ -0,0 +1,135 @@
+use actix_web::test::{self, TestRequest};
+use serde_json::json;
+
+use crate::utils::quota::current_usage;
+
+use super::{
+    bearer, call, grant_pro, login, register, test_app, test_config, test_state, unique_email,
+};
+
+#[actix_web::test]
+async fn map_answers_from_the_stub_for_pro_users() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state.clone()).await;
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/map")
+            .set_json(json!({ "inputText": "draw a cat" })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "missing_authorization");
+
+    let free_email = unique_email();
+    register(&app, &free_email).await;
+    let (free_token, _) = login(&app, &free_email).await;
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/map")
+            .insert_header(bearer(&free_token))
+            .set_json(json!({ "inputText": "draw a cat" })),
+    )
+    .await;
+    assert_eq!(status, 403);
+    assert_eq!(body["error"]["code"], "missing_entitlement");
+
+    let email = unique_email();
+    let user_id = register(&app, &email).await;
+    grant_pro(&state, &user_id).await;
+    let (token, _) = login(&app, &email).await;
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/map")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "inputText": "draw a cat" })),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"]["text"], "echo: draw a cat");
+    assert_eq!(body["data"]["stopReason"], "end_turn");
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/map")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "inputText": "draw a cat", "maxTokens": 2 })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["data"]["text"], "echo: draw");
+    assert_eq!(body["data"]["stopReason"], "max_tokens");
+    assert_eq!(body["data"]["outputTokens"], 2);
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/map")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "inputText": "draw a cat", "maxTokens": 0 })),
+    )
+    .await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"]["code"], "invalid_max_tokens");
+}
+
+#[actix_web::test]
+async fn map_stream_sends_deltas_then_done() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state.clone()).await;
+    let email = unique_email();
+    let user_id = register(&app, &email).await;
+    grant_pro(&state, &user_id).await;
+    let (token, _) = login(&app, &email).await;
+
+    let response = test::call_service(
+        &app,
+        TestRequest::post()
+            .uri("/map/stream")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "inputText": "draw a cat" }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers().get("Content-Type").unwrap(),
+        "text/event-stream"
+    );
+    let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+
+    let events: Vec<(&str, serde_json::Value)> = body
+        .split_terminator("\n\n")
+        .map(|event| {
+            let (name, data) = event.split_once('\n').unwrap();
+            (
+                name.strip_prefix("event: ").unwrap(),
+                serde_json::from_str(data.strip_prefix("data: ").unwrap()).unwrap(),
+            )
+        })
+        .collect();
+    let (done, deltas) = events.split_last().unwrap();
+    assert!(deltas.iter().all(|(name, _)| *name == "delta"));
+    assert_eq!(done.0, "done");
+    assert_eq!(done.1["stopReason"], "end_turn");
+    assert_eq!(done.1["outputTokens"], 4);
+
+    // the reserved maxTokens are swapped for the 3 + 4 used, in the background
+    let mut tokens = 0;
+    for _ in 0..50 {
+        tokens = current_usage(&state.redis_client, &user_id)
+            .await
+            .unwrap()
+            .tokens;
+        if tokens == 7 {
+            break;
+        }
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(tokens, 7);
+}
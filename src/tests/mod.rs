@@ -0,0 +1,235 @@
+// Tests that send requests through the whole app (routes::config) with the in-memory
+// repositories and the stub LLM, so they need no AWS. Sessions, refresh tokens and the
+// blacklist still live in redis: TEST_REDIS_URL, or the one from compose.yml on localhost.
+// Without a redis they fail, so a missing one can't pass for a green run.
+// fixtures/ has an RSA key with its JWKS to sign in with Apple and Google, and a second key
+// the JWKS doesn't know.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+
+use actix_http::Request;
+use actix_web::{
+    body::{to_bytes, MessageBody},
+    dev::{Service, ServiceResponse},
+    test, web, App, Error,
+};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde_json::{json, Value};
+
+use crate::routes;
+use crate::utils::{
+    api_key::InMemoryApiKeyRepository,
+    app_state::AppState,
+    config::{Config, JwtConfig, LlmConfig, LlmProviderKind, OidcConfig, RedisConfig},
+    entitlement::{Entitlement, InMemoryEntitlementRepository, StoredEntitlements},
+    jwt_keys::KeyRing,
+    llm::stub::StubProvider,
+    mailer::mailer_from_config,
+    oidc::OidcVerifier,
+    user::InMemoryUserRepository,
+};
+use crate::RedisClient;
+
+mod account;
+mod admin;
+mod api_keys;
+mod auth_flow;
+mod map;
+mod oidc;
+mod profile;
+
+const DEFAULT_TEST_REDIS_URL: &str = "redis://127.0.0.1:6379";
+pub(crate) const TEST_PASSWORD: &str = "correct horse battery";
+// aud of the test ID tokens, for both providers
+pub(crate) const OIDC_CLIENT_ID: &str = "com.artizans.test";
+// kid of fixtures/oidc_key.pem in fixtures/oidc_jwks.json
+pub(crate) const OIDC_KID: &str = "test-key";
+pub(crate) const OIDC_KEY: &str = include_str!("fixtures/oidc_key.pem");
+pub(crate) const OIDC_OTHER_KEY: &str = include_str!("fixtures/oidc_other_key.pem");
+
+pub(crate) fn test_config() -> Config {
+    Config {
+        environment: "test".to_string(),
+        redis: RedisConfig {
+            url: env::var("TEST_REDIS_URL").unwrap_or(DEFAULT_TEST_REDIS_URL.to_string()),
+            // fail right away when there is no redis
+            reconnect_attempts: 0,
+            ..Default::default()
+        },
+        jwt: JwtConfig {
+            secret_key: Some("test-secret-that-is-at-least-32-chars".to_string()),
+            ..Default::default()
+        },
+        llm: LlmConfig {
+            provider: LlmProviderKind::Stub,
+            ..Default::default()
+        },
+        oidc: test_oidc_config(),
+        ..Default::default()
+    }
+}
+
+// Apple and Google both take tokens signed with fixtures/oidc_key.pem
+pub(crate) fn test_oidc_config() -> OidcConfig {
+    let jwks = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/tests/fixtures/oidc_jwks.json"
+    );
+    OidcConfig {
+        google_client_ids: vec![OIDC_CLIENT_ID.to_string()],
+        google_jwks: jwks.to_string(),
+        apple_client_ids: vec![OIDC_CLIENT_ID.to_string()],
+        apple_jwks: jwks.to_string(),
+        ..Default::default()
+    }
+}
+
+pub(crate) async fn test_redis(config: &RedisConfig) -> RedisClient {
+    RedisClient::connect(config).await.unwrap_or_else(|err| {
+        panic!(
+            "no redis at {} ({}). Start one with `docker compose up -d artizans_redis` or point TEST_REDIS_URL at one",
+            config.url, err
+        )
+    })
+}
+
+// AppState like main builds it with USER_STORE=memory
+pub(crate) async fn test_state(config: Config) -> web::Data<AppState> {
+    let redis_client = test_redis(&config.redis).await;
+    web::Data::new(AppState {
+        key_ring: Arc::new(KeyRing::from_config(&config.jwt).unwrap()),
+        redis_client: web::Data::new(redis_client),
+        user_repository: Arc::new(InMemoryUserRepository::default()),
+        entitlement_repository: Arc::new(InMemoryEntitlementRepository::default()),
+        api_key_repository: Arc::new(InMemoryApiKeyRepository::default()),
+        llm_provider: Arc::new(StubProvider),
+        mailer: mailer_from_config(&config.mail).unwrap(),
+        oidc: Arc::new(OidcVerifier::from_config(&config.oidc).unwrap()),
+        config: Arc::new(config),
+    })
+}
+
+// A Google ID token for `email` signed with `key`, `overrides` replace or add claims
+pub(crate) fn id_token(key: &str, subject: &str, email: &str, overrides: Value) -> String {
+    let now = chrono::Utc::now().timestamp();
+    let mut claims = json!({
+        "iss": "https://accounts.google.com",
+        "aud": OIDC_CLIENT_ID,
+        "sub": subject,
+        "email": email,
+        "email_verified": true,
+        "iat": now,
+        "exp": now + 600,
+    });
+    for (name, value) in overrides.as_object().into_iter().flatten() {
+        claims[name] = value.clone();
+    }
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(OIDC_KID.to_string());
+    encode(
+        &header,
+        &claims,
+        &EncodingKey::from_rsa_pem(key.as_bytes()).unwrap(),
+    )
+    .unwrap()
+}
+
+pub(crate) async fn test_app(
+    state: web::Data<AppState>,
+) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error> {
+    test::init_service(App::new().app_data(state).configure(routes::config)).await
+}
+
+// status and JSON body of the response. Middlewares answer with errors, which the server
+// would turn into responses the same way
+pub(crate) async fn call<S, B>(app: &S, request: test::TestRequest) -> (u16, Value)
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    let (status, body) = match test::try_call_service(app, request.to_request()).await {
+        Ok(response) => (response.status(), test::read_body(response).await),
+        Err(err) => {
+            let response = err.error_response();
+            let status = response.status();
+            let body = to_bytes(response.into_body()).await.unwrap_or_default();
+            (status, body)
+        }
+    };
+    (
+        status.as_u16(),
+        serde_json::from_slice(&body).unwrap_or(Value::Null),
+    )
+}
+
+pub(crate) fn bearer(token: &str) -> (&'static str, String) {
+    ("Authorization", format!("Bearer {}", token))
+}
+
+// tests share the redis, so every one gets its own address (login throttling goes by email)
+pub(crate) fn unique_email() -> String {
+    format!("{}@example.com", uuid::Uuid::new_v4().simple())
+}
+
+// registers a user with TEST_PASSWORD, returns its id
+pub(crate) async fn register<S, B>(app: &S, email: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    let (status, body) = call(
+        app,
+        test::TestRequest::post()
+            .uri("/auth/register")
+            .set_json(json!({ "name": "Test", "email": email, "password": TEST_PASSWORD })),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    body["data"]["id"].as_str().unwrap().to_string()
+}
+
+// (access token, refresh token)
+pub(crate) async fn login<S, B>(app: &S, email: &str) -> (String, String)
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    let (status, body) = call(
+        app,
+        test::TestRequest::post()
+            .uri("/auth/login")
+            .set_json(json!({ "email": email, "password": TEST_PASSWORD })),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    (
+        body["data"]["accessToken"].as_str().unwrap().to_string(),
+        body["data"]["refreshToken"].as_str().unwrap().to_string(),
+    )
+}
+
+// what /map needs, as if RevenueCat had sent a purchase. Do it before the user's first /map
+// call, entitlements are cached
+pub(crate) async fn grant_pro(state: &AppState, user_id: &str) {
+    let pro = Entitlement {
+        product_id: "pro_monthly".to_string(),
+        expires_at_ms: None,
+        will_renew: true,
+        billing_issue: false,
+        updated_at_ms: 0,
+    };
+    let update = StoredEntitlements {
+        entitlements: HashMap::from([("pro".to_string(), pro)]),
+        version: 0,
+    };
+    state
+        .entitlement_repository
+        .apply_event(
+            &uuid::Uuid::new_v4().to_string(),
+            &HashMap::from([(user_id.to_string(), update)]),
+        )
+        .await
+        .unwrap();
+}
@@ -0,0 +1,137 @@
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{
+    bearer, call, id_token, login, register, test_app, test_config, test_state, unique_email,
+    OIDC_KEY, OIDC_OTHER_KEY, TEST_PASSWORD,
+};
+
+#[actix_web::test]
+async fn google_sign_in_takes_over_unverified_accounts() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+    let email = unique_email();
+
+    // someone registers the address without ever verifying it, and signs in with the password
+    let squatter_id = register(&app, &email).await;
+    let (access_token, refresh_token) = login(&app, &email).await;
+
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/oidc/google")
+            .set_json(json!({ "idToken": id_token(OIDC_KEY, "g-owner", &email, json!({})) })),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    let owner_token = body["data"]["accessToken"].as_str().unwrap().to_string();
+
+    let (status, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(&owner_token)),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["data"]["id"], squatter_id);
+    assert_eq!(body["data"]["emailVerified"], true);
+
+    // the squatter's sessions and password are gone
+    let (status, _) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(status, 401);
+    let (status, _) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(json!({ "refreshToken": refresh_token })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/login")
+            .set_json(json!({ "email": email, "password": TEST_PASSWORD })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "invalid_credentials");
+
+    // the identity is linked now, so the next sign in finds the same account
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/oidc/google")
+            .set_json(json!({ "idToken": id_token(OIDC_KEY, "g-owner", &email, json!({})) })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let (_, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(body["data"]["accessToken"].as_str().unwrap())),
+    )
+    .await;
+    assert_eq!(body["data"]["id"], squatter_id);
+}
+
+#[actix_web::test]
+async fn unverified_provider_emails_are_refused() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+    let email = unique_email();
+    let user_id = register(&app, &email).await;
+
+    let token = id_token(
+        OIDC_KEY,
+        "g-unverified",
+        &email,
+        json!({ "email_verified": false }),
+    );
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/oidc/google")
+            .set_json(json!({ "idToken": token })),
+    )
+    .await;
+    assert_eq!(status, 403);
+    assert_eq!(body["error"]["code"], "oidc_email_not_verified");
+
+    // and the account that has the address is left alone
+    let (access_token, _) = login(&app, &email).await;
+    let (_, body) = call(
+        &app,
+        TestRequest::get()
+            .uri("/user")
+            .insert_header(bearer(&access_token)),
+    )
+    .await;
+    assert_eq!(body["data"]["id"], user_id);
+    assert_eq!(body["data"]["emailVerified"], false);
+}
+
+#[actix_web::test]
+async fn forged_id_tokens_are_refused() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+
+    let token = id_token(OIDC_OTHER_KEY, "g-forged", &unique_email(), json!({}));
+    let (status, body) = call(
+        &app,
+        TestRequest::post()
+            .uri("/auth/oidc/google")
+            .set_json(json!({ "idToken": token })),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["code"], "invalid_id_token");
+}
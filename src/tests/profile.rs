@@ -0,0 +1,78 @@
+use actix_web::test::TestRequest;
+use serde_json::json;
+
+use super::{bearer, call, login, register, test_app, test_config, test_state, unique_email};
+
+#[actix_web::test]
+async fn profile_updates_are_validated() {
+    let state = test_state(test_config()).await;
+    let app = test_app(state).await;
+    let email = unique_email();
+    register(&app, &email).await;
+    let (token, _) = login(&app, &email).await;
+
+    let (status, body) = call(
+        &app,
+        TestRequest::patch()
+            .uri("/user")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "name": "  Ada  ", "bio": "Draws maps" })),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"]["name"], "Ada");
+    assert_eq!(body["data"]["bio"], "Draws maps");
+    assert_eq!(body["data"]["email"], email);
+
+    let (status, body) = call(
+        &app,
+        TestRequest::patch()
+            .uri("/user")
+            .insert_header(bearer(&token))
+            .set_json(json!({
+                "name": "",
+                "bio": "x".repeat(501),
+                "avatarUrl": "http://example.com/a.png",
+                "email": "not an email",
+            })),
+    )
+    .await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"]["code"], "validation_failed");
+    assert_eq!(
+        body["error"]["details"]["fields"],
+        json!({
+            "name": "must not be empty",
+            "bio": "must be at most 500 characters",
+            "avatarUrl": "must be an https:// url",
+            "email": "must be a valid email address",
+        })
+    );
+
+    // a new address waits for its confirmation, taken ones are refused right away
+    let new_email = unique_email();
+    let (status, body) = call(
+        &app,
+        TestRequest::patch()
+            .uri("/user")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "email": new_email })),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"]["email"], email);
+    assert_eq!(body["data"]["pendingEmail"], new_email);
+
+    let other_email = unique_email();
+    register(&app, &other_email).await;
+    let (status, body) = call(
+        &app,
+        TestRequest::patch()
+            .uri("/user")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "email": other_email })),
+    )
+    .await;
+    assert_eq!(status, 409);
+    assert_eq!(body["error"]["code"], "email_already_in_use");
+}
@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use aws_sdk_dynamodb::{
+    operation::{transact_write_items::TransactWriteItemsError, update_item::UpdateItemError},
+    types::{AttributeValue, Delete, KeysAndAttributes, Put, TransactWriteItem, Update},
+    Client,
+};
+use chrono::{DateTime, Utc};
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+use sha256::digest;
+
+use crate::RedisClient;
+
+use super::{config::ApiKeyConfig, refresh_token::generate_token, user::Role};
+
+// every key starts with this, so leaked ones are easy to grep for
+const KEY_PREFIX: &str = "artz_";
+
+// What a key can be used for. Routes take keys only when wrapped in accept_api_key with one
+// of these, everything else needs a Bearer token from an interactive login.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiKeyScope {
+    Map,
+    Admin,
+}
+
+impl ApiKeyScope {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ApiKeyScope::Map => "map",
+            ApiKeyScope::Admin => "admin",
+        }
+    }
+
+    fn from_attribute(value: &str) -> Result<Self> {
+        match value {
+            "map" => Ok(ApiKeyScope::Map),
+            "admin" => Ok(ApiKeyScope::Admin),
+            _ => Err(anyhow!("Unknown api key scope {}", value)),
+        }
+    }
+
+    // the role the key's owner needs to hold a key with this scope
+    pub(crate) fn required_role(&self) -> Role {
+        match self {
+            ApiKeyScope::Map => Role::User,
+            ApiKeyScope::Admin => Role::Admin,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ApiKey {
+    pub(crate) id: String,
+    #[serde(skip)]
+    pub(crate) user_id: String,
+    pub(crate) name: String,
+    pub(crate) scopes: Vec<ApiKeyScope>,
+    // sha256 of the whole key, it's random enough that a slow hash buys nothing
+    #[serde(skip)]
+    pub(crate) key_hash: String,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) last_used_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    // the new key and the plain "artz_<id>_<secret>" value, which is only ever shown once
+    pub(crate) fn generate(
+        user_id: &str,
+        name: &str,
+        scopes: Vec<ApiKeyScope>,
+    ) -> (ApiKey, String) {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let key = format!("{}{}_{}", KEY_PREFIX, id, generate_token());
+        let api_key = ApiKey {
+            id,
+            user_id: user_id.to_string(),
+            name: name.to_string(),
+            scopes,
+            key_hash: digest(&key),
+            created_at: Utc::now(),
+            last_used_at: None,
+        };
+        (api_key, key)
+    }
+
+    pub(crate) fn matches(&self, key: &str) -> bool {
+        self.key_hash == digest(key)
+    }
+
+    fn to_item(&self) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::new();
+        item.insert("id".to_string(), AttributeValue::S(item_id(&self.id)));
+        item.insert(
+            "user_id".to_string(),
+            AttributeValue::S(self.user_id.clone()),
+        );
+        item.insert("name".to_string(), AttributeValue::S(self.name.clone()));
+        item.insert(
+            "scopes".to_string(),
+            AttributeValue::Ss(
+                self.scopes
+                    .iter()
+                    .map(|scope| scope.as_str().to_string())
+                    .collect(),
+            ),
+        );
+        item.insert(
+            "key_hash".to_string(),
+            AttributeValue::S(self.key_hash.clone()),
+        );
+        item.insert(
+            "created_at".to_string(),
+            AttributeValue::S(self.created_at.to_rfc3339()),
+        );
+        item
+    }
+
+    fn from_item(item: &HashMap<String, AttributeValue>) -> Result<Self> {
+        let required = |attribute: &str| {
+            item.get(attribute)
+                .and_then(|v| v.as_s().ok())
+                .map(|v| v.to_string())
+                .ok_or_else(|| anyhow!("Missing {}", attribute))
+        };
+        let timestamp = |value: String| -> Result<DateTime<Utc>> {
+            Ok(DateTime::parse_from_rfc3339(&value)?.into())
+        };
+        Ok(ApiKey {
+            id: required("id")?
+                .strip_prefix(API_KEY_ID_PREFIX)
+                .ok_or_else(|| anyhow!("Not an api key item"))?
+                .to_string(),
+            user_id: required("user_id")?,
+            name: required("name")?,
+            scopes: item
+                .get("scopes")
+                .and_then(|v| v.as_ss().ok())
+                .map(|scopes| {
+                    scopes
+                        .iter()
+                        .map(|scope| ApiKeyScope::from_attribute(scope))
+                        .collect::<Result<Vec<_>>>()
+                })
+                .transpose()?
+                .unwrap_or_default(),
+            key_hash: required("key_hash")?,
+            created_at: timestamp(required("created_at")?)?,
+            last_used_at: required("last_used_at").ok().map(timestamp).transpose()?,
+        })
+    }
+}
+
+// the id part of "artz_<id>_<secret>", None for anything that isn't shaped like a key
+pub(crate) fn key_id(key: &str) -> Option<&str> {
+    let (id, secret) = key.strip_prefix(KEY_PREFIX)?.split_once('_')?;
+    (!id.is_empty() && !secret.is_empty()).then_some(id)
+}
+
+// Keys live in the users table as APIKEY#<id> items. The owner's item lists the ids in
+// api_keys, so they can be listed and removed along with the user without a scan.
+#[async_trait]
+pub(crate) trait ApiKeyRepository: Send + Sync {
+    async fn create_api_key(&self, api_key: &ApiKey) -> Result<()>;
+    async fn get_api_key(&self, id: &str) -> Result<Option<ApiKey>>;
+    async fn list_api_keys(&self, user_id: &str) -> Result<Vec<ApiKey>>;
+    // false if the user has no key with that id
+    async fn delete_api_key(&self, user_id: &str, id: &str) -> Result<bool>;
+    async fn delete_user_api_keys(&self, user_id: &str) -> Result<()>;
+    async fn set_last_used(&self, id: &str, at: DateTime<Utc>) -> Result<()>;
+}
+
+pub(crate) const API_KEY_ID_PREFIX: &str = "APIKEY#";
+
+fn item_id(id: &str) -> String {
+    format!("{}{}", API_KEY_ID_PREFIX, id)
+}
+
+// Updates last_used_at, but only once per api_keys.last_used_seconds, so a busy kiosk doesn't
+// turn every request into a DynamoDB write
+pub(crate) async fn record_api_key_use(
+    redis_client: &RedisClient,
+    config: &ApiKeyConfig,
+    api_key_repository: &dyn ApiKeyRepository,
+    id: &str,
+) -> Result<()> {
+    let mut conn = redis_client.connection();
+    let first: Option<String> = conn
+        .set_options(
+            format!("api_key_used:{}", id),
+            1,
+            SetOptions::default()
+                .conditional_set(ExistenceCheck::NX)
+                .with_expiration(SetExpiry::EX(config.last_used_seconds)),
+        )
+        .await?;
+    if first.is_some() {
+        api_key_repository.set_last_used(id, Utc::now()).await?;
+    }
+    Ok(())
+}
+
+pub(crate) struct DynamoApiKeyRepository {
+    client: Client,
+    table_name: String,
+}
+
+impl DynamoApiKeyRepository {
+    pub(crate) fn new(client: Client, table_name: String) -> Self {
+        Self { client, table_name }
+    }
+
+    async fn key_ids(&self, user_id: &str) -> Result<Vec<String>> {
+        let result = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key("id", AttributeValue::S(user_id.to_string()))
+            .projection_expression("api_keys")
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(result
+            .item
+            .as_ref()
+            .and_then(|item| item.get("api_keys"))
+            .and_then(|v| v.as_ss().ok())
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl ApiKeyRepository for DynamoApiKeyRepository {
+    async fn create_api_key(&self, api_key: &ApiKey) -> Result<()> {
+        let put = Put::builder()
+            .table_name(&self.table_name)
+            .set_item(Some(api_key.to_item()))
+            .condition_expression("attribute_not_exists(id)")
+            .build()?;
+        let update = Update::builder()
+            .table_name(&self.table_name)
+            .key("id", AttributeValue::S(api_key.user_id.clone()))
+            .update_expression("ADD api_keys :id")
+            .condition_expression("attribute_exists(id)")
+            .expression_attribute_values(":id", AttributeValue::Ss(vec![api_key.id.clone()]))
+            .build()?;
+
+        self.client
+            .transact_write_items()
+            .transact_items(TransactWriteItem::builder().put(put).build())
+            .transact_items(TransactWriteItem::builder().update(update).build())
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    async fn get_api_key(&self, id: &str) -> Result<Option<ApiKey>> {
+        let result = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key("id", AttributeValue::S(item_id(id)))
+            // a revoked key has to stop working right away
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+        result.item.as_ref().map(ApiKey::from_item).transpose()
+    }
+
+    async fn list_api_keys(&self, user_id: &str) -> Result<Vec<ApiKey>> {
+        let mut pending: Vec<HashMap<String, AttributeValue>> = self
+            .key_ids(user_id)
+            .await?
+            .iter()
+            .map(|id| HashMap::from([("id".to_string(), AttributeValue::S(item_id(id)))]))
+            .collect();
+
+        let mut api_keys = Vec::new();
+        // at most MAX_API_KEYS_PER_USER, well below the 100 keys a batch takes
+        while !pending.is_empty() {
+            let result = self
+                .client
+                .batch_get_item()
+                .request_items(
+                    &self.table_name,
+                    KeysAndAttributes::builder()
+                        .set_keys(Some(pending))
+                        .consistent_read(true)
+                        .build()?,
+                )
+                .send()
+                .await
+                .map_err(anyhow::Error::from)?;
+            for item in result
+                .responses
+                .as_ref()
+                .and_then(|responses| responses.get(&self.table_name))
+                .into_iter()
+                .flatten()
+            {
+                api_keys.push(ApiKey::from_item(item)?);
+            }
+            // throttled reads come back as unprocessed and are simply asked for again
+            pending = result
+                .unprocessed_keys
+                .and_then(|mut unprocessed| unprocessed.remove(&self.table_name))
+                .map(|keys| keys.keys)
+                .unwrap_or_default();
+        }
+
+        api_keys.sort_by_key(|api_key| api_key.created_at);
+        Ok(api_keys)
+    }
+
+    async fn delete_api_key(&self, user_id: &str, id: &str) -> Result<bool> {
+        let delete = Delete::builder()
+            .table_name(&self.table_name)
+            .key("id", AttributeValue::S(item_id(id)))
+            .condition_expression("user_id = :user_id")
+            .expression_attribute_values(":user_id", AttributeValue::S(user_id.to_string()))
+            .build()?;
+        let update = Update::builder()
+            .table_name(&self.table_name)
+            .key("id", AttributeValue::S(user_id.to_string()))
+            .update_expression("DELETE api_keys :id")
+            .expression_attribute_values(":id", AttributeValue::Ss(vec![id.to_string()]))
+            .build()?;
+
+        match self
+            .client
+            .transact_write_items()
+            .transact_items(TransactWriteItem::builder().delete(delete).build())
+            .transact_items(TransactWriteItem::builder().update(update).build())
+            .send()
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(err) => match err.into_service_error() {
+                TransactWriteItemsError::TransactionCanceledException(err)
+                    if err
+                        .cancellation_reasons()
+                        .first()
+                        .and_then(|reason| reason.code())
+                        == Some("ConditionalCheckFailed") =>
+                {
+                    Ok(false)
+                }
+                err => Err(anyhow!(err)),
+            },
+        }
+    }
+
+    async fn delete_user_api_keys(&self, user_id: &str) -> Result<()> {
+        for id in self.key_ids(user_id).await? {
+            self.delete_api_key(user_id, &id).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_last_used(&self, id: &str, at: DateTime<Utc>) -> Result<()> {
+        let result = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key("id", AttributeValue::S(item_id(id)))
+            .update_expression("SET last_used_at = :at")
+            // don't bring back a key that was revoked in the meantime
+            .condition_expression("attribute_exists(id)")
+            .expression_attribute_values(":at", AttributeValue::S(at.to_rfc3339()))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => match err.into_service_error() {
+                UpdateItemError::ConditionalCheckFailedException(_) => Ok(()),
+                err => Err(anyhow!(err)),
+            },
+        }
+    }
+}
+
+// keeps keys in a HashMap keyed by id, for local runs without AWS
+#[derive(Default)]
+pub(crate) struct InMemoryApiKeyRepository {
+    api_keys: Mutex<HashMap<String, ApiKey>>,
+}
+
+#[async_trait]
+impl ApiKeyRepository for InMemoryApiKeyRepository {
+    async fn create_api_key(&self, api_key: &ApiKey) -> Result<()> {
+        self.api_keys
+            .lock()
+            .unwrap()
+            .insert(api_key.id.clone(), api_key.clone());
+        Ok(())
+    }
+
+    async fn get_api_key(&self, id: &str) -> Result<Option<ApiKey>> {
+        Ok(self.api_keys.lock().unwrap().get(id).cloned())
+    }
+
+    async fn list_api_keys(&self, user_id: &str) -> Result<Vec<ApiKey>> {
+        let mut api_keys: Vec<ApiKey> = self
+            .api_keys
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|api_key| api_key.user_id == user_id)
+            .cloned()
+            .collect();
+        api_keys.sort_by_key(|api_key| api_key.created_at);
+        Ok(api_keys)
+    }
+
+    async fn delete_api_key(&self, user_id: &str, id: &str) -> Result<bool> {
+        let mut api_keys = self.api_keys.lock().unwrap();
+        if api_keys.get(id).map(|api_key| api_key.user_id.as_str()) != Some(user_id) {
+            return Ok(false);
+        }
+        api_keys.remove(id);
+        Ok(true)
+    }
+
+    async fn delete_user_api_keys(&self, user_id: &str) -> Result<()> {
+        self.api_keys
+            .lock()
+            .unwrap()
+            .retain(|_, api_key| api_key.user_id != user_id);
+        Ok(())
+    }
+
+    async fn set_last_used(&self, id: &str, at: DateTime<Utc>) -> Result<()> {
+        if let Some(api_key) = self.api_keys.lock().unwrap().get_mut(id) {
+            api_key.last_used_at = Some(at);
+        }
+        Ok(())
+    }
+}
@@ -1,30 +1,185 @@
-use actix_web::{body::BoxBody, http::StatusCode, web, HttpResponse, Responder, ResponseError};
+use actix_web::{
+    body::BoxBody,
+    error::{JsonPayloadError, QueryPayloadError},
+    http::header::{ContentType, HeaderName, HeaderValue},
+    http::StatusCode,
+    HttpRequest, HttpResponse, Responder, ResponseError,
+};
+use serde_json::Value;
 use std::fmt::Display;
 
+// machine readable error codes, serialized as snake_case strings like "user_already_exists"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // generic
+    InternalError,
+    DatabaseError,
+    CacheError,
+    InvalidRequestBody,
+    InvalidQueryString,
+    ValidationFailed,
+    NotFound,
+    NotReady,
+    // auth
+    MissingAuthorization,
+    InvalidAuthorizationHeader,
+    InvalidTokenFormat,
+    MalformedToken,
+    InvalidSignature,
+    TokenExpired,
+    InvalidTokenClaims,
+    TokenRevoked,
+    SessionRevoked,
+    SessionNotFound,
+    BadClaims,
+    MissingEntitlement,
+    InsufficientRole,
+    AccountDisabled,
+    UserAlreadyExists,
+    InvalidCredentials,
+    InvalidRefreshToken,
+    RefreshTokenReused,
+    InvalidResetToken,
+    InvalidVerificationToken,
+    EmailAlreadyVerified,
+    TooManyRequests,
+    TooManyLoginAttempts,
+    OidcProviderNotConfigured,
+    InvalidIdToken,
+    OidcUnavailable,
+    OidcEmailNotVerified,
+    InvalidMfaToken,
+    InvalidMfaCode,
+    SecondFactorRequired,
+    MfaAlreadyEnabled,
+    MfaNotEnabled,
+    MfaEnrollmentNotFound,
+    ReauthenticationRequired,
+    InvalidApiKey,
+    ApiKeyNotAccepted,
+    InsufficientScope,
+    // user
+    UserNotFound,
+    EmailAlreadyInUse,
+    InvalidEmailToken,
+    // map
+    LlmUnavailable,
+    InvalidMaxTokens,
+    QuotaExceeded,
+    // api keys
+    ApiKeyNotFound,
+    TooManyApiKeys,
+    // admin
+    CannotModifySelf,
+    // webhooks
+    WebhookNotConfigured,
+    InvalidWebhookAuthorization,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    // extra machine readable context, e.g. remaining quota on a 429
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+// every response goes out as { "data": ..., "error": ... } with exactly one of them set
+#[derive(Debug, serde::Serialize)]
+struct Envelope<'a> {
+    data: &'a Option<Value>,
+    error: &'a Option<ApiError>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct MessageResponse {
+    pub message: String,
+}
+
 #[derive(Debug)]
 pub struct ApiResponse {
     pub status_code: u16,
-    pub body: String,
+    pub data: Option<Value>,
+    pub error: Option<ApiError>,
     response_code: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
 }
 
 impl ApiResponse {
-    pub fn new(status_code: u16, body: String) -> Self {
+    pub fn new(status_code: u16, data: impl serde::Serialize) -> Self {
         ApiResponse {
             status_code,
-            body,
+            data: Some(serde_json::to_value(data).unwrap_or(Value::Null)),
+            error: None,
             response_code: StatusCode::from_u16(status_code).unwrap(),
+            headers: Vec::new(),
         }
     }
+
+    pub fn message(status_code: u16, message: impl Into<String>) -> Self {
+        Self::new(
+            status_code,
+            MessageResponse {
+                message: message.into(),
+            },
+        )
+    }
+
+    pub fn error(status_code: u16, code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiResponse {
+            status_code,
+            data: None,
+            error: Some(ApiError {
+                code,
+                message: message.into(),
+                details: None,
+            }),
+            response_code: StatusCode::from_u16(status_code).unwrap(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn with_details(mut self, details: impl serde::Serialize) -> Self {
+        if let Some(error) = self.error.as_mut() {
+            error.details = serde_json::to_value(details).ok();
+        }
+        self
+    }
+
+    // values that aren't valid header values are dropped
+    pub fn with_header(mut self, name: HeaderName, value: impl AsRef<str>) -> Self {
+        if let Ok(value) = HeaderValue::from_str(value.as_ref()) {
+            self.headers.push((name, value));
+        }
+        self
+    }
+
+    fn to_http_response(&self) -> HttpResponse<BoxBody> {
+        let envelope = Envelope {
+            data: &self.data,
+            error: &self.error,
+        };
+        let mut response = HttpResponse::build(self.response_code);
+        response.content_type(ContentType::json());
+        for header in &self.headers {
+            response.insert_header(header.clone());
+        }
+        response.body(serde_json::to_string(&envelope).unwrap_or_default())
+    }
 }
 
 impl Display for ApiResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Error: {} \n Status Code: {}",
-            self.body, self.status_code
-        )
+        match &self.error {
+            Some(error) => write!(
+                f,
+                "Error: {:?} {} \n Status Code: {}",
+                error.code, error.message, self.status_code
+            ),
+            None => write!(f, "Status Code: {}", self.status_code),
+        }
     }
 }
 
@@ -32,8 +187,7 @@ impl Responder for ApiResponse {
     type Body = BoxBody;
 
     fn respond_to(self, _req: &actix_web::HttpRequest) -> actix_web::HttpResponse<Self::Body> {
-        let body = BoxBody::new(web::BytesMut::from(self.body.as_bytes()));
-        HttpResponse::new(self.response_code).set_body(body)
+        self.to_http_response()
     }
 }
 
@@ -43,7 +197,16 @@ impl ResponseError for ApiResponse {
     }
 
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        let body = BoxBody::new(web::BytesMut::from(self.body.as_bytes()));
-        HttpResponse::new(self.status_code()).set_body(body)
+        self.to_http_response()
     }
 }
+
+// registered on web::JsonConfig so malformed request bodies get the same envelope as everything else
+pub fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiResponse::error(400, ErrorCode::InvalidRequestBody, err.to_string()).into()
+}
+
+// same for query strings, through web::QueryConfig
+pub fn query_error_handler(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiResponse::error(400, ErrorCode::InvalidQueryString, err.to_string()).into()
+}
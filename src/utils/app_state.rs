@@ -1,13 +1,22 @@
 use std::sync::Arc;
 
 use actix_web::web;
-use aws_sdk_bedrockruntime::Client as BedrockClient;
-use aws_sdk_dynamodb::Client;
 
 use crate::RedisClient;
 
+use super::{
+    api_key::ApiKeyRepository, config::Config, entitlement::EntitlementRepository,
+    jwt_keys::KeyRing, llm::LlmProvider, mailer::Mailer, oidc::OidcVerifier, user::UserRepository,
+};
+
 pub struct AppState {
+    pub(crate) config: Arc<Config>,
+    pub(crate) key_ring: Arc<KeyRing>,
     pub redis_client: web::Data<RedisClient>,
-    pub dynamo_client: Arc<Client>,
-    pub bedrock_client: Arc<BedrockClient>,
+    pub(crate) user_repository: Arc<dyn UserRepository>,
+    pub(crate) entitlement_repository: Arc<dyn EntitlementRepository>,
+    pub(crate) api_key_repository: Arc<dyn ApiKeyRepository>,
+    pub(crate) llm_provider: Arc<dyn LlmProvider>,
+    pub(crate) mailer: Arc<dyn Mailer>,
+    pub(crate) oidc: Arc<OidcVerifier>,
 }
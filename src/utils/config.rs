@@ -0,0 +1,967 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use ipnet::IpNet;
+use serde::{de::DeserializeOwned, de::IntoDeserializer, Deserialize};
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+// Every setting of the server, loaded once at startup and kept in AppState. Values come from
+// the TOML file in CONFIG_FILE (config.toml by default, see config.example.toml), then env vars
+// override single values. Everything has a default except environment and redis.url.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    // "production" turns on the stricter checks, and names the DynamoDB table
+    pub(crate) environment: String,
+    pub(crate) server: ServerConfig,
+    pub(crate) redis: RedisConfig,
+    pub(crate) storage: StorageConfig,
+    pub(crate) jwt: JwtConfig,
+    pub(crate) llm: LlmConfig,
+    pub(crate) mail: MailConfig,
+    pub(crate) auth: AuthConfig,
+    pub(crate) api_keys: ApiKeyConfig,
+    pub(crate) oidc: OidcConfig,
+    pub(crate) revenuecat: RevenueCatConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerConfig {
+    pub(crate) address: String,
+    pub(crate) port: u16,
+    // stop the server after this long without requests, 0 keeps it running forever
+    pub(crate) idle_shutdown_seconds: u64,
+    pub(crate) idle_check_interval_seconds: u64,
+    // how long in-flight requests (e.g. long bedrock calls) get to finish on shutdown
+    pub(crate) shutdown_drain_seconds: u64,
+    // load balancers whose X-Forwarded-For is believed, anyone else could make it up.
+    // Addresses or CIDR ranges like 10.0.0.0/16
+    pub(crate) trusted_proxies: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RedisConfig {
+    pub(crate) url: String,
+    // per attempt to (re)connect
+    pub(crate) connection_timeout_ms: u64,
+    // a command that takes longer fails instead of holding up the request
+    pub(crate) response_timeout_ms: u64,
+    // how often a dropped connection is retried with backoff before the command fails,
+    // the next command starts over
+    pub(crate) reconnect_attempts: usize,
+    // cap on the backoff between those attempts
+    pub(crate) reconnect_max_delay_ms: u64,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        RedisConfig {
+            url: String::new(),
+            connection_timeout_ms: 2000,
+            response_timeout_ms: 2000,
+            reconnect_attempts: 5,
+            reconnect_max_delay_ms: 2000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum UserStore {
+    #[default]
+    Dynamodb,
+    // to run without AWS, nothing is persisted
+    Memory,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct StorageConfig {
+    pub(crate) user_store: UserStore,
+    // artizans_<environment> when left empty
+    pub(crate) table_name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct JwtConfig {
+    // HS256 secret, only used when keys_dir isn't set, which production doesn't allow
+    pub(crate) secret_key: Option<String>,
+    // directory of Ed25519 PEM files named <kid>.pem, private keys sign and verify, public keys only verify
+    pub(crate) keys_dir: Option<String>,
+    // which private key in keys_dir signs new tokens, optional if there is only one
+    pub(crate) active_kid: Option<String>,
+    // `iss` of every token we sign, tokens from anyone else are rejected
+    pub(crate) issuer: String,
+    // `aud` of access tokens
+    pub(crate) audience: String,
+    pub(crate) access_token_minutes: i64,
+    pub(crate) refresh_token_days: i64,
+    // bumps overwrite the cache right away, this only bounds staleness if that write fails
+    pub(crate) token_version_cache_seconds: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LlmProviderKind {
+    #[default]
+    Titan,
+    Anthropic,
+    Stub,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LlmConfig {
+    pub(crate) provider: LlmProviderKind,
+    // falls back to the provider's default model when unset
+    pub(crate) model_id: Option<String>,
+    // upper bound for maxTokens on a single /map request
+    pub(crate) max_tokens_limit: u32,
+    // per user, reset every day at 00:00 UTC
+    pub(crate) daily_request_quota: u64,
+    // input + output tokens per user, reset every day at 00:00 UTC
+    pub(crate) daily_token_quota: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MailerKind {
+    Smtp,
+    // only write mails to mail.file / stdout
+    #[default]
+    Log,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MailConfig {
+    pub(crate) mailer: MailerKind,
+    pub(crate) from: String,
+    pub(crate) file: Option<String>,
+    pub(crate) smtp_host: Option<String>,
+    // 587 uses STARTTLS, 465 implicit TLS
+    pub(crate) smtp_port: u16,
+    pub(crate) smtp_username: Option<String>,
+    pub(crate) smtp_password: Option<String>,
+    // base url of the frontend, links in emails point there. Without it mails contain just the token.
+    pub(crate) app_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AuthConfig {
+    // these become admins when they sign in with a verified email. Only needed to get the
+    // first admin, the rest can be promoted through /admin
+    pub(crate) admin_emails: Vec<String>,
+    // how long the link sent to a new email address stays valid
+    pub(crate) email_change_token_minutes: i64,
+    pub(crate) password_reset_token_minutes: i64,
+    pub(crate) email_verification_token_hours: i64,
+    // minimum time between two verification mails for the same user
+    pub(crate) verification_resend_seconds: u64,
+    // failed logins older than this are forgotten, see utils::login_attempts
+    pub(crate) login_failure_window_seconds: u64,
+    pub(crate) login_lockout_seconds: u64,
+    // cap for the doubling wait between failed logins before the lockout kicks in
+    pub(crate) login_max_delay_seconds: u64,
+    // time to scan the QR code and confirm with a first code
+    pub(crate) totp_enrollment_minutes: i64,
+    // time between the password and the second factor on login
+    pub(crate) mfa_challenge_minutes: i64,
+    // wrong codes per challenge, after that the password has to be entered again
+    pub(crate) mfa_challenge_attempts: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ApiKeyConfig {
+    pub(crate) max_per_user: usize,
+    // how precise last_used_at is, saves a DynamoDB write on every request made with a key
+    pub(crate) last_used_seconds: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OidcConfig {
+    // OAuth client ids ID tokens may be issued to (aud), sign in with Google is off without any
+    pub(crate) google_client_ids: Vec<String>,
+    // where Google's signing keys come from, an https url or a local JWKS file
+    pub(crate) google_jwks: String,
+    // bundle id of the app and/or services id of the website, sign in with Apple is off without any
+    pub(crate) apple_client_ids: Vec<String>,
+    pub(crate) apple_jwks: String,
+    // providers rotate their keys every few days and publish new ones well ahead
+    pub(crate) jwks_cache_seconds: u64,
+    // a token with an unknown kid refetches the keys early, but at most this often
+    pub(crate) jwks_refetch_seconds: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RevenueCatConfig {
+    // the Authorization header value configured for the webhook in the RevenueCat dashboard
+    pub(crate) webhook_secret: Option<String>,
+    // the webhook clears the cache right away, this only bounds staleness if that fails
+    pub(crate) entitlement_cache_seconds: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: "0.0.0.0".to_string(),
+            port: 5050,
+            idle_shutdown_seconds: 300,
+            idle_check_interval_seconds: 60,
+            shutdown_drain_seconds: 30,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub(crate) fn trusts_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .filter_map(|proxy| proxy_range(proxy))
+            .any(|range| range.contains(&ip))
+    }
+}
+
+// a single address is a range holding just itself
+fn proxy_range(value: &str) -> Option<IpNet> {
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            secret_key: None,
+            keys_dir: None,
+            active_kid: None,
+            issuer: "artizans".to_string(),
+            audience: "artizans-api".to_string(),
+            access_token_minutes: 15,
+            refresh_token_days: 30,
+            token_version_cache_seconds: 300,
+        }
+    }
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        LlmConfig {
+            provider: LlmProviderKind::default(),
+            model_id: None,
+            max_tokens_limit: 1000,
+            daily_request_quota: 100,
+            daily_token_quota: 50000,
+        }
+    }
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            mailer: MailerKind::default(),
+            from: "Artizans <no-reply@artizans.app>".to_string(),
+            file: None,
+            smtp_host: None,
+            smtp_port: 587,
+            smtp_username: None,
+            smtp_password: None,
+            app_url: None,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            admin_emails: Vec::new(),
+            email_change_token_minutes: 24 * 60,
+            password_reset_token_minutes: 30,
+            email_verification_token_hours: 48,
+            verification_resend_seconds: 60,
+            login_failure_window_seconds: 15 * 60,
+            login_lockout_seconds: 15 * 60,
+            login_max_delay_seconds: 60,
+            totp_enrollment_minutes: 10,
+            mfa_challenge_minutes: 5,
+            mfa_challenge_attempts: 5,
+        }
+    }
+}
+
+impl Default for ApiKeyConfig {
+    fn default() -> Self {
+        ApiKeyConfig {
+            max_per_user: 20,
+            last_used_seconds: 60,
+        }
+    }
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig {
+            google_client_ids: Vec::new(),
+            google_jwks: "https://www.googleapis.com/oauth2/v3/certs".to_string(),
+            apple_client_ids: Vec::new(),
+            apple_jwks: "https://appleid.apple.com/auth/keys".to_string(),
+            jwks_cache_seconds: 60 * 60,
+            jwks_refetch_seconds: 60,
+        }
+    }
+}
+
+impl Default for RevenueCatConfig {
+    fn default() -> Self {
+        RevenueCatConfig {
+            webhook_secret: None,
+            entitlement_cache_seconds: 300,
+        }
+    }
+}
+
+// every problem found while loading, so a bad deploy shows all of them at once
+#[derive(Debug)]
+pub(crate) struct ConfigError {
+    errors: Vec<String>,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for error in &self.errors {
+            write!(f, "\n  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// the env vars the config is read from, the process env outside tests
+type Vars = HashMap<String, String>;
+
+// reads the env var into `target` if it's set, parse failures are collected
+fn override_parsed<T>(vars: &Vars, target: &mut T, name: &str, errors: &mut Vec<String>)
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = vars.get(name) {
+        match value.trim().parse() {
+            Ok(parsed) => *target = parsed,
+            Err(err) => errors.push(format!("{}: can't parse {:?}: {}", name, value, err)),
+        }
+    }
+}
+
+fn override_optional(vars: &Vars, target: &mut Option<String>, name: &str) {
+    if let Some(value) = vars.get(name) {
+        *target = Some(value.clone()).filter(|value| !value.is_empty());
+    }
+}
+
+// one of the lowercase names the TOML file takes too, e.g. USER_STORE=memory
+fn override_choice<T: DeserializeOwned>(
+    vars: &Vars,
+    target: &mut T,
+    name: &str,
+    errors: &mut Vec<String>,
+) {
+    if let Some(value) = vars.get(name) {
+        let deserializer: serde::de::value::StrDeserializer<serde::de::value::Error> =
+            value.trim().into_deserializer();
+        match T::deserialize(deserializer) {
+            Ok(parsed) => *target = parsed,
+            Err(err) => errors.push(format!("{}: {}", name, err)),
+        }
+    }
+}
+
+// comma separated in env vars, a list in the file
+fn override_list(vars: &Vars, target: &mut Vec<String>, name: &str) {
+    if let Some(value) = vars.get(name) {
+        *target = value
+            .split(',')
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .collect();
+    }
+}
+
+fn require_positive<T: PartialOrd + Default>(errors: &mut Vec<String>, name: &str, value: T) {
+    if value <= T::default() {
+        errors.push(format!("{} must be greater than 0", name));
+    }
+}
+
+impl Config {
+    pub(crate) fn load() -> Result<Config, ConfigError> {
+        dotenv::dotenv().ok();
+
+        let vars: Vars = env::vars().collect();
+        // a missing config.toml is fine, a missing file someone asked for isn't
+        match vars.get("CONFIG_FILE") {
+            Some(path) => Config::from_sources(path, true, &vars),
+            None => Config::from_sources(DEFAULT_CONFIG_FILE, false, &vars),
+        }
+    }
+
+    // the file at `path`, then `vars` on top of it
+    fn from_sources(path: &str, explicit: bool, vars: &Vars) -> Result<Config, ConfigError> {
+        let mut errors = Vec::new();
+        let mut file_failed = false;
+        let mut config = if explicit || Path::new(path).exists() {
+            let parsed = std::fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|contents| toml::from_str(&contents).map_err(|err| err.to_string()));
+            match parsed {
+                Ok(config) => config,
+                Err(err) => {
+                    errors.push(format!("{}: {}", path, err.trim_end()));
+                    file_failed = true;
+                    Config::default()
+                }
+            }
+        } else {
+            Config::default()
+        };
+
+        config.apply_env(vars, &mut errors);
+        if config.storage.table_name.is_empty() {
+            config.storage.table_name = format!("artizans_{}", config.environment);
+        }
+        if let Some(app_url) = &mut config.mail.app_url {
+            *app_url = app_url.trim_end_matches('/').to_string();
+        }
+        // without the file the values aren't the ones that will be used, checking them would
+        // only add noise
+        if !file_failed {
+            config.validate(&mut errors);
+        }
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError { errors })
+        }
+    }
+
+    // the env var names are the ones the server used before there was a config file
+    fn apply_env(&mut self, vars: &Vars, errors: &mut Vec<String>) {
+        override_parsed(vars, &mut self.environment, "ENVIRONMENT", errors);
+
+        let server = &mut self.server;
+        override_parsed(vars, &mut server.address, "ADDRESS", errors);
+        override_parsed(vars, &mut server.port, "PORT", errors);
+        override_parsed(
+            vars,
+            &mut server.idle_shutdown_seconds,
+            "IDLE_SHUTDOWN_SECONDS",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut server.idle_check_interval_seconds,
+            "IDLE_CHECK_INTERVAL_SECONDS",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut server.shutdown_drain_seconds,
+            "SHUTDOWN_DRAIN_SECONDS",
+            errors,
+        );
+        override_list(vars, &mut server.trusted_proxies, "TRUSTED_PROXIES");
+
+        let redis = &mut self.redis;
+        override_parsed(vars, &mut redis.url, "REDIS_URL", errors);
+        override_parsed(
+            vars,
+            &mut redis.connection_timeout_ms,
+            "REDIS_CONNECTION_TIMEOUT_MS",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut redis.response_timeout_ms,
+            "REDIS_RESPONSE_TIMEOUT_MS",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut redis.reconnect_attempts,
+            "REDIS_RECONNECT_ATTEMPTS",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut redis.reconnect_max_delay_ms,
+            "REDIS_RECONNECT_MAX_DELAY_MS",
+            errors,
+        );
+
+        override_choice(vars, &mut self.storage.user_store, "USER_STORE", errors);
+        override_parsed(
+            vars,
+            &mut self.storage.table_name,
+            "DYNAMO_DB_TABLE_NAME",
+            errors,
+        );
+
+        let jwt = &mut self.jwt;
+        override_optional(vars, &mut jwt.secret_key, "JWT_SECRET_KEY");
+        override_optional(vars, &mut jwt.keys_dir, "JWT_KEYS_DIR");
+        override_optional(vars, &mut jwt.active_kid, "JWT_ACTIVE_KID");
+        override_parsed(vars, &mut jwt.issuer, "JWT_ISSUER", errors);
+        override_parsed(vars, &mut jwt.audience, "JWT_AUDIENCE", errors);
+        override_parsed(
+            vars,
+            &mut jwt.access_token_minutes,
+            "JWT_EXPIRY_MINUTES",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut jwt.refresh_token_days,
+            "REFRESH_TOKEN_EXPIRY_DAYS",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut jwt.token_version_cache_seconds,
+            "TOKEN_VERSION_CACHE_SECONDS",
+            errors,
+        );
+
+        let llm = &mut self.llm;
+        override_choice(vars, &mut llm.provider, "LLM_PROVIDER", errors);
+        override_optional(vars, &mut llm.model_id, "LLM_MODEL_ID");
+        override_parsed(vars, &mut llm.max_tokens_limit, "MAX_TOKENS_LIMIT", errors);
+        override_parsed(
+            vars,
+            &mut llm.daily_request_quota,
+            "DAILY_REQUEST_QUOTA",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut llm.daily_token_quota,
+            "DAILY_TOKEN_QUOTA",
+            errors,
+        );
+
+        let mail = &mut self.mail;
+        override_choice(vars, &mut mail.mailer, "MAILER", errors);
+        override_parsed(vars, &mut mail.from, "MAIL_FROM", errors);
+        override_optional(vars, &mut mail.file, "MAIL_FILE");
+        override_optional(vars, &mut mail.smtp_host, "SMTP_HOST");
+        override_parsed(vars, &mut mail.smtp_port, "SMTP_PORT", errors);
+        override_optional(vars, &mut mail.smtp_username, "SMTP_USERNAME");
+        override_optional(vars, &mut mail.smtp_password, "SMTP_PASSWORD");
+        override_optional(vars, &mut mail.app_url, "APP_URL");
+
+        let auth = &mut self.auth;
+        override_list(vars, &mut auth.admin_emails, "ADMIN_EMAILS");
+        override_parsed(
+            vars,
+            &mut auth.email_change_token_minutes,
+            "EMAIL_CHANGE_TOKEN_MINUTES",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut auth.password_reset_token_minutes,
+            "PASSWORD_RESET_TOKEN_MINUTES",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut auth.email_verification_token_hours,
+            "EMAIL_VERIFICATION_TOKEN_HOURS",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut auth.verification_resend_seconds,
+            "VERIFICATION_RESEND_SECONDS",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut auth.login_failure_window_seconds,
+            "LOGIN_FAILURE_WINDOW_SECONDS",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut auth.login_lockout_seconds,
+            "LOGIN_LOCKOUT_SECONDS",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut auth.login_max_delay_seconds,
+            "LOGIN_MAX_DELAY_SECONDS",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut auth.totp_enrollment_minutes,
+            "TOTP_ENROLLMENT_MINUTES",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut auth.mfa_challenge_minutes,
+            "MFA_CHALLENGE_MINUTES",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut auth.mfa_challenge_attempts,
+            "MFA_CHALLENGE_ATTEMPTS",
+            errors,
+        );
+
+        override_parsed(
+            vars,
+            &mut self.api_keys.max_per_user,
+            "MAX_API_KEYS_PER_USER",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut self.api_keys.last_used_seconds,
+            "API_KEY_LAST_USED_SECONDS",
+            errors,
+        );
+
+        let oidc = &mut self.oidc;
+        override_list(vars, &mut oidc.google_client_ids, "GOOGLE_CLIENT_IDS");
+        override_parsed(vars, &mut oidc.google_jwks, "GOOGLE_JWKS", errors);
+        override_list(vars, &mut oidc.apple_client_ids, "APPLE_CLIENT_IDS");
+        override_parsed(vars, &mut oidc.apple_jwks, "APPLE_JWKS", errors);
+        override_parsed(
+            vars,
+            &mut oidc.jwks_cache_seconds,
+            "OIDC_JWKS_CACHE_SECONDS",
+            errors,
+        );
+        override_parsed(
+            vars,
+            &mut oidc.jwks_refetch_seconds,
+            "OIDC_JWKS_REFETCH_SECONDS",
+            errors,
+        );
+
+        override_optional(
+            vars,
+            &mut self.revenuecat.webhook_secret,
+            "REVENUECAT_WEBHOOK_SECRET",
+        );
+        override_parsed(
+            vars,
+            &mut self.revenuecat.entitlement_cache_seconds,
+            "ENTITLEMENT_CACHE_SECONDS",
+            errors,
+        );
+    }
+
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.environment.trim().is_empty() {
+            errors.push("environment (ENVIRONMENT) must be set".to_string());
+        }
+        if self.redis.url.is_empty() {
+            errors.push("redis.url (REDIS_URL) must be set".to_string());
+        } else if !self.redis.url.starts_with("redis://")
+            && !self.redis.url.starts_with("rediss://")
+        {
+            errors.push("redis.url must be a redis:// or rediss:// url".to_string());
+        }
+        require_positive(
+            errors,
+            "redis.connection_timeout_ms",
+            self.redis.connection_timeout_ms,
+        );
+        require_positive(
+            errors,
+            "redis.response_timeout_ms",
+            self.redis.response_timeout_ms,
+        );
+        require_positive(
+            errors,
+            "redis.reconnect_max_delay_ms",
+            self.redis.reconnect_max_delay_ms,
+        );
+        if self.server.idle_shutdown_seconds > 0 {
+            require_positive(
+                errors,
+                "server.idle_check_interval_seconds",
+                self.server.idle_check_interval_seconds,
+            );
+        }
+        for proxy in &self.server.trusted_proxies {
+            if proxy_range(proxy).is_none() {
+                errors.push(format!(
+                    "server.trusted_proxies: {} is not an IP address or CIDR range",
+                    proxy
+                ));
+            }
+        }
+
+        let jwt = &self.jwt;
+        if jwt.issuer.is_empty() {
+            errors.push("jwt.issuer must not be empty".to_string());
+        }
+        if jwt.audience.is_empty() {
+            errors.push("jwt.audience must not be empty".to_string());
+        }
+        require_positive(errors, "jwt.access_token_minutes", jwt.access_token_minutes);
+        require_positive(errors, "jwt.refresh_token_days", jwt.refresh_token_days);
+        require_positive(
+            errors,
+            "jwt.token_version_cache_seconds",
+            jwt.token_version_cache_seconds,
+        );
+        if let Some(dir) = &jwt.keys_dir {
+            if !Path::new(dir).is_dir() {
+                errors.push(format!("jwt.keys_dir {} is not a directory", dir));
+            }
+        } else if self.is_production() {
+            // an HS256 secret would have to be shared with anything that checks our tokens
+            errors.push(
+                "jwt.keys_dir (JWT_KEYS_DIR) must be set in production, jwt.secret_key is for development"
+                    .to_string(),
+            );
+        }
+
+        let llm = &self.llm;
+        require_positive(errors, "llm.max_tokens_limit", llm.max_tokens_limit);
+        require_positive(errors, "llm.daily_request_quota", llm.daily_request_quota);
+        require_positive(errors, "llm.daily_token_quota", llm.daily_token_quota);
+
+        let mail = &self.mail;
+        if mail.mailer == MailerKind::Smtp && mail.smtp_host.is_none() {
+            errors
+                .push("mail.smtp_host (SMTP_HOST) must be set when the mailer is smtp".to_string());
+        }
+        if mail.smtp_username.is_some() != mail.smtp_password.is_some() {
+            errors.push("mail.smtp_username and mail.smtp_password go together".to_string());
+        }
+        if let Some(app_url) = &mail.app_url {
+            if !app_url.starts_with("https://") && !app_url.starts_with("http://") {
+                errors.push("mail.app_url must be an http(s) url".to_string());
+            }
+        }
+
+        let auth = &self.auth;
+        require_positive(
+            errors,
+            "auth.email_change_token_minutes",
+            auth.email_change_token_minutes,
+        );
+        require_positive(
+            errors,
+            "auth.password_reset_token_minutes",
+            auth.password_reset_token_minutes,
+        );
+        require_positive(
+            errors,
+            "auth.email_verification_token_hours",
+            auth.email_verification_token_hours,
+        );
+        require_positive(
+            errors,
+            "auth.login_failure_window_seconds",
+            auth.login_failure_window_seconds,
+        );
+        require_positive(
+            errors,
+            "auth.login_lockout_seconds",
+            auth.login_lockout_seconds,
+        );
+        require_positive(
+            errors,
+            "auth.login_max_delay_seconds",
+            auth.login_max_delay_seconds,
+        );
+        require_positive(
+            errors,
+            "auth.totp_enrollment_minutes",
+            auth.totp_enrollment_minutes,
+        );
+        require_positive(
+            errors,
+            "auth.mfa_challenge_minutes",
+            auth.mfa_challenge_minutes,
+        );
+        require_positive(
+            errors,
+            "auth.mfa_challenge_attempts",
+            auth.mfa_challenge_attempts,
+        );
+
+        require_positive(
+            errors,
+            "api_keys.last_used_seconds",
+            self.api_keys.last_used_seconds,
+        );
+        require_positive(
+            errors,
+            "oidc.jwks_cache_seconds",
+            self.oidc.jwks_cache_seconds,
+        );
+        require_positive(
+            errors,
+            "revenuecat.entitlement_cache_seconds",
+            self.revenuecat.entitlement_cache_seconds,
+        );
+        require_positive(errors, "api_keys.max_per_user", self.api_keys.max_per_user);
+    }
+
+    pub(crate) fn is_production(&self) -> bool {
+        self.environment == "production"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL: &str = r#"
+        environment = "development"
+        [redis]
+        url = "redis://127.0.0.1:6379"
+    "#;
+
+    // loads `toml` as the config file with just `vars` set
+    fn load(toml: &str, vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let path = env::temp_dir().join(format!("artizans-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, toml).unwrap();
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        let config = Config::from_sources(path.to_str().unwrap(), true, &vars);
+
+        std::fs::remove_file(path).unwrap();
+        config
+    }
+
+    fn load_errors(toml: &str, vars: &[(&str, &str)]) -> Vec<String> {
+        load(toml, vars).unwrap_err().errors
+    }
+
+    #[test]
+    fn defaults_fill_in_the_rest() {
+        let config = load(MINIMAL, &[]).unwrap();
+        assert_eq!(config.server.port, 5050);
+        assert_eq!(config.storage.table_name, "artizans_development");
+        assert!(!config.is_production());
+    }
+
+    #[test]
+    fn reports_every_invalid_field_at_once() {
+        let toml = r#"
+            environment = " "
+            [redis]
+            url = "http://127.0.0.1:6379"
+            [jwt]
+            access_token_minutes = 0
+            [mail]
+            app_url = "ftp://artizans.app"
+        "#;
+        let errors = load_errors(toml, &[("PORT", "eighty")]);
+        for expected in [
+            "PORT: can't parse \"eighty\"",
+            "environment (ENVIRONMENT) must be set",
+            "redis.url must be a redis:// or rediss:// url",
+            "jwt.access_token_minutes must be greater than 0",
+            "mail.app_url must be an http(s) url",
+        ] {
+            assert!(
+                errors.iter().any(|error| error.starts_with(expected)),
+                "{:?} missing from {:?}",
+                expected,
+                errors
+            );
+        }
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+    }
+
+    #[test]
+    fn unknown_keys_fail_the_file() {
+        let errors = load_errors(&format!("{}\nprot = 80", MINIMAL), &[]);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].contains("unknown field `prot`"), "{:?}", errors);
+    }
+
+    #[test]
+    fn env_vars_override_the_file() {
+        let toml = format!(
+            "{}\n[server]\nport = 5050\n[storage]\nuser_store = \"dynamodb\"",
+            MINIMAL
+        );
+        let config = load(
+            &toml,
+            &[
+                ("PORT", "6000"),
+                ("USER_STORE", "memory"),
+                ("REDIS_URL", "rediss://cache.internal:6380"),
+                ("GOOGLE_CLIENT_IDS", "web.apps, ios.apps,"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 6000);
+        assert_eq!(config.storage.user_store, UserStore::Memory);
+        assert_eq!(config.redis.url, "rediss://cache.internal:6380");
+        assert_eq!(config.oidc.google_client_ids, ["web.apps", "ios.apps"]);
+
+        let errors = load_errors(&toml, &[("USER_STORE", "postgres")]);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].starts_with("USER_STORE: "), "{:?}", errors);
+    }
+
+    #[test]
+    fn production_needs_signing_keys() {
+        let production = &[("ENVIRONMENT", "production")];
+        let errors = load_errors(MINIMAL, production);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].starts_with("jwt.keys_dir (JWT_KEYS_DIR) must be set in production"));
+
+        // however good the secret is
+        let secret = format!("{}\n[jwt]\nsecret_key = \"{}\"", MINIMAL, "a".repeat(64));
+        assert_eq!(load_errors(&secret, production).len(), 1);
+
+        let keys_dir = env::temp_dir();
+        let config = load(
+            &secret,
+            &[
+                ("ENVIRONMENT", "production"),
+                ("JWT_KEYS_DIR", keys_dir.to_str().unwrap()),
+            ],
+        )
+        .unwrap();
+        assert!(config.is_production());
+        assert_eq!(config.storage.table_name, "artizans_production");
+
+        // outside production the secret is enough
+        assert!(load(&secret, &[]).is_ok());
+    }
+}
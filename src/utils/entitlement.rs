@@ -0,0 +1,803 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use aws_sdk_dynamodb::{
+    operation::transact_write_items::TransactWriteItemsError,
+    types::{AttributeValue, Put, TransactWriteItem},
+    Client,
+};
+use redis::AsyncCommands;
+
+use crate::RedisClient;
+
+use super::config::RevenueCatConfig;
+
+// entitlement id ("pro", ...) -> state, as kept per user
+pub(crate) type Entitlements = HashMap<String, Entitlement>;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Entitlement {
+    pub(crate) product_id: String,
+    // None for lifetime purchases
+    pub(crate) expires_at_ms: Option<i64>,
+    pub(crate) will_renew: bool,
+    pub(crate) billing_issue: bool,
+    // event_timestamp_ms of the last event applied, older events are ignored
+    pub(crate) updated_at_ms: i64,
+}
+
+impl Entitlement {
+    pub(crate) fn is_active(&self, now_ms: i64) -> bool {
+        match self.expires_at_ms {
+            Some(expires_at) => expires_at > now_ms,
+            None => true,
+        }
+    }
+}
+
+// https://www.revenuecat.com/docs/integrations/webhooks/event-types-and-fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(crate) enum RevenueCatEventType {
+    InitialPurchase,
+    Renewal,
+    Cancellation,
+    Uncancellation,
+    Expiration,
+    BillingIssue,
+    ProductChange,
+    Transfer,
+    // TEST, SUBSCRIBER_ALIAS, ... are acknowledged but don't change entitlements
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct RevenueCatEvent {
+    pub(crate) id: String,
+    #[serde(rename = "type")]
+    pub(crate) event_type: RevenueCatEventType,
+    // our user id (USER#...), the app logs in to RevenueCat with it
+    pub(crate) app_user_id: Option<String>,
+    pub(crate) entitlement_ids: Option<Vec<String>>,
+    pub(crate) product_id: Option<String>,
+    pub(crate) new_product_id: Option<String>,
+    pub(crate) expiration_at_ms: Option<i64>,
+    pub(crate) event_timestamp_ms: i64,
+    #[serde(default)]
+    pub(crate) transferred_from: Vec<String>,
+    #[serde(default)]
+    pub(crate) transferred_to: Vec<String>,
+}
+
+impl RevenueCatEvent {
+    // every user whose entitlements this event can touch
+    pub(crate) fn affected_users(&self) -> Vec<String> {
+        let mut users: Vec<String> = self.app_user_id.iter().cloned().collect();
+        users.extend(self.transferred_from.iter().cloned());
+        users.extend(self.transferred_to.iter().cloned());
+        let mut seen = HashSet::new();
+        users.retain(|user| seen.insert(user.clone()));
+        users
+    }
+
+    // Applies the event to the current entitlements of the affected users.
+    // Only users whose entitlements actually changed are left in the returned map.
+    pub(crate) fn apply(
+        &self,
+        current: &HashMap<String, Entitlements>,
+    ) -> HashMap<String, Entitlements> {
+        let mut next = current.clone();
+        let entitlement_ids = self.entitlement_ids.clone().unwrap_or_default();
+        let timestamp = self.event_timestamp_ms;
+
+        if self.event_type == RevenueCatEventType::Transfer {
+            for from in &self.transferred_from {
+                let moved: Entitlements = next
+                    .get_mut(from)
+                    .map(|entitlements| {
+                        entitlements
+                            .drain()
+                            .filter(|(id, _)| {
+                                entitlement_ids.is_empty() || entitlement_ids.contains(id)
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                for to in &self.transferred_to {
+                    let target = next.entry(to.clone()).or_default();
+                    for (id, entitlement) in &moved {
+                        target.insert(
+                            id.clone(),
+                            Entitlement {
+                                updated_at_ms: timestamp,
+                                ..entitlement.clone()
+                            },
+                        );
+                    }
+                }
+            }
+        } else if let Some(user_id) = &self.app_user_id {
+            let entitlements = next.entry(user_id.clone()).or_default();
+            for id in &entitlement_ids {
+                // RevenueCat doesn't guarantee delivery order
+                if entitlements
+                    .get(id)
+                    .is_some_and(|existing| existing.updated_at_ms > timestamp)
+                {
+                    continue;
+                }
+                self.apply_to_entitlement(entitlements, id);
+            }
+        }
+
+        next.retain(|user_id, entitlements| current.get(user_id) != Some(entitlements));
+        next
+    }
+
+    fn apply_to_entitlement(&self, entitlements: &mut Entitlements, id: &str) {
+        let timestamp = self.event_timestamp_ms;
+        let product_id = self.product_id.clone().unwrap_or_default();
+        match self.event_type {
+            RevenueCatEventType::InitialPurchase | RevenueCatEventType::Renewal => {
+                entitlements.insert(
+                    id.to_string(),
+                    Entitlement {
+                        product_id,
+                        expires_at_ms: self.expiration_at_ms,
+                        will_renew: true,
+                        billing_issue: false,
+                        updated_at_ms: timestamp,
+                    },
+                );
+            }
+            other => {
+                let Some(entitlement) = entitlements.get_mut(id) else {
+                    return;
+                };
+                match other {
+                    // still active until it expires, it just won't renew
+                    RevenueCatEventType::Cancellation => entitlement.will_renew = false,
+                    RevenueCatEventType::Uncancellation => entitlement.will_renew = true,
+                    RevenueCatEventType::Expiration => {
+                        entitlement.expires_at_ms =
+                            Some(self.expiration_at_ms.unwrap_or(timestamp));
+                        entitlement.will_renew = false;
+                    }
+                    // store keeps retrying the charge, access stays until expiration
+                    RevenueCatEventType::BillingIssue => entitlement.billing_issue = true,
+                    // sent when the user switches plans, the following RENEWAL brings the new expiry
+                    RevenueCatEventType::ProductChange => {
+                        if let Some(new_product_id) = &self.new_product_id {
+                            entitlement.product_id = new_product_id.clone();
+                        }
+                    }
+                    _ => return,
+                }
+                entitlement.updated_at_ms = timestamp;
+            }
+        }
+    }
+}
+
+// A user's entitlements with the version they were read at. Every write bumps the version, and
+// only goes through if it's still the one read, so two webhooks for the same user can't
+// overwrite each other's changes.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StoredEntitlements {
+    pub(crate) entitlements: Entitlements,
+    // 0 until the first write
+    pub(crate) version: u64,
+}
+
+pub(crate) enum EventOutcome {
+    Applied,
+    // someone else processed the event first
+    AlreadyProcessed,
+    // a user's entitlements changed since they were read, read them again and retry
+    Conflict,
+}
+
+// Entitlement records live in the users table as ENTITLEMENTS#<user id> items, next to the
+// USER# items. Processed webhook events are remembered as RCEVENT#<event id> items.
+#[async_trait]
+pub(crate) trait EntitlementRepository: Send + Sync {
+    async fn get_entitlements(&self, user_id: &str) -> Result<StoredEntitlements>;
+    async fn is_event_processed(&self, event_id: &str) -> Result<bool>;
+    // Stores the new entitlements and marks the event as processed in one go. `updates` has
+    // every user the event touches, changed or not, each with the version it was computed from
+    // (see StoredEntitlements)
+    async fn apply_event(
+        &self,
+        event_id: &str,
+        updates: &HashMap<String, StoredEntitlements>,
+    ) -> Result<EventOutcome>;
+    async fn delete_entitlements(&self, user_id: &str) -> Result<()>;
+}
+
+fn cache_key(user_id: &str) -> String {
+    format!("entitlements:{}", user_id)
+}
+
+// Reads a user's entitlements through a short lived redis cache, so gated routes
+// don't hit DynamoDB on every request.
+pub(crate) async fn get_cached_entitlements(
+    redis_client: &RedisClient,
+    config: &RevenueCatConfig,
+    entitlement_repository: &dyn EntitlementRepository,
+    user_id: &str,
+) -> Result<Entitlements> {
+    let mut conn = redis_client.connection();
+    let cached: Option<String> = conn.get(cache_key(user_id)).await?;
+    if let Some(cached) = cached {
+        return Ok(serde_json::from_str(&cached)?);
+    }
+
+    let entitlements = entitlement_repository
+        .get_entitlements(user_id)
+        .await?
+        .entitlements;
+    conn.set_ex::<_, _, ()>(
+        cache_key(user_id),
+        serde_json::to_string(&entitlements)?,
+        config.entitlement_cache_seconds,
+    )
+    .await?;
+    Ok(entitlements)
+}
+
+pub(crate) async fn invalidate_entitlement_cache(
+    redis_client: &RedisClient,
+    user_ids: impl IntoIterator<Item = &String>,
+) -> Result<()> {
+    let keys: Vec<String> = user_ids
+        .into_iter()
+        .map(|user_id| cache_key(user_id))
+        .collect();
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let mut conn = redis_client.connection();
+    conn.del::<_, ()>(keys).await?;
+    Ok(())
+}
+
+fn entitlements_key(user_id: &str) -> String {
+    format!("ENTITLEMENTS#{}", user_id)
+}
+
+fn event_key(event_id: &str) -> String {
+    format!("RCEVENT#{}", event_id)
+}
+
+pub(crate) struct DynamoEntitlementRepository {
+    client: Client,
+    table_name: String,
+}
+
+impl DynamoEntitlementRepository {
+    pub(crate) fn new(client: Client, table_name: String) -> Self {
+        Self { client, table_name }
+    }
+}
+
+#[async_trait]
+impl EntitlementRepository for DynamoEntitlementRepository {
+    async fn get_entitlements(&self, user_id: &str) -> Result<StoredEntitlements> {
+        let result = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key("id", AttributeValue::S(entitlements_key(user_id)))
+            // the webhook writes back what it read here, conditioned on the version
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+
+        let Some(item) = result.item else {
+            return Ok(StoredEntitlements::default());
+        };
+        let entitlements = match item.get("entitlements").and_then(|value| value.as_s().ok()) {
+            Some(entitlements) => serde_json::from_str(entitlements)?,
+            None => Entitlements::new(),
+        };
+        // items from before versioning have none, they count as never written
+        let version = match item.get("version").and_then(|value| value.as_n().ok()) {
+            Some(version) => version.parse()?,
+            None => 0,
+        };
+        Ok(StoredEntitlements {
+            entitlements,
+            version,
+        })
+    }
+
+    async fn is_event_processed(&self, event_id: &str) -> Result<bool> {
+        let result = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key("id", AttributeValue::S(event_key(event_id)))
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(result.item.is_some())
+    }
+
+    async fn apply_event(
+        &self,
+        event_id: &str,
+        updates: &HashMap<String, StoredEntitlements>,
+    ) -> Result<EventOutcome> {
+        let mut event_item = HashMap::new();
+        event_item.insert("id".to_string(), AttributeValue::S(event_key(event_id)));
+        event_item.insert(
+            "processed_at".to_string(),
+            AttributeValue::S(chrono::Utc::now().to_rfc3339()),
+        );
+        // first item, so a replay fails the whole transaction on this condition
+        let mut items = vec![TransactWriteItem::builder()
+            .put(
+                Put::builder()
+                    .table_name(&self.table_name)
+                    .set_item(Some(event_item))
+                    .condition_expression("attribute_not_exists(id)")
+                    .build()?,
+            )
+            .build()];
+
+        for (user_id, update) in updates {
+            let mut item = HashMap::new();
+            item.insert(
+                "id".to_string(),
+                AttributeValue::S(entitlements_key(user_id)),
+            );
+            item.insert("user_id".to_string(), AttributeValue::S(user_id.clone()));
+            // kept as a JSON string, we always read and write the whole set at once
+            item.insert(
+                "entitlements".to_string(),
+                AttributeValue::S(serde_json::to_string(&update.entitlements)?),
+            );
+            item.insert(
+                "version".to_string(),
+                AttributeValue::N((update.version + 1).to_string()),
+            );
+            let put = Put::builder()
+                .table_name(&self.table_name)
+                .set_item(Some(item));
+            let put = if update.version == 0 {
+                put.condition_expression("attribute_not_exists(version)")
+            } else {
+                put.condition_expression("version = :version")
+                    .expression_attribute_values(
+                        ":version",
+                        AttributeValue::N(update.version.to_string()),
+                    )
+            };
+            items.push(TransactWriteItem::builder().put(put.build()?).build());
+        }
+
+        match self
+            .client
+            .transact_write_items()
+            .set_transact_items(Some(items))
+            .send()
+            .await
+        {
+            Ok(_) => Ok(EventOutcome::Applied),
+            Err(err) => match err.into_service_error() {
+                // one reason per item, in order, so the first is the event's
+                TransactWriteItemsError::TransactionCanceledException(err) => {
+                    let failed: Vec<bool> = err
+                        .cancellation_reasons()
+                        .iter()
+                        .map(|reason| reason.code() == Some("ConditionalCheckFailed"))
+                        .collect();
+                    match failed.split_first() {
+                        Some((true, _)) => Ok(EventOutcome::AlreadyProcessed),
+                        Some((false, users)) if users.contains(&true) => Ok(EventOutcome::Conflict),
+                        _ => Err(anyhow!(err)),
+                    }
+                }
+                err => Err(anyhow!(err)),
+            },
+        }
+    }
+
+    async fn delete_entitlements(&self, user_id: &str) -> Result<()> {
+        self.client
+            .delete_item()
+            .table_name(&self.table_name)
+            .key("id", AttributeValue::S(entitlements_key(user_id)))
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct InMemoryEntitlementRepository {
+    entitlements: Mutex<HashMap<String, StoredEntitlements>>,
+    processed_events: Mutex<HashSet<String>>,
+}
+
+#[async_trait]
+impl EntitlementRepository for InMemoryEntitlementRepository {
+    async fn get_entitlements(&self, user_id: &str) -> Result<StoredEntitlements> {
+        Ok(self
+            .entitlements
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn is_event_processed(&self, event_id: &str) -> Result<bool> {
+        Ok(self.processed_events.lock().unwrap().contains(event_id))
+    }
+
+    async fn apply_event(
+        &self,
+        event_id: &str,
+        updates: &HashMap<String, StoredEntitlements>,
+    ) -> Result<EventOutcome> {
+        // both locks for the whole check and write, like the DynamoDB transaction
+        let mut processed_events = self.processed_events.lock().unwrap();
+        let mut entitlements = self.entitlements.lock().unwrap();
+        if processed_events.contains(event_id) {
+            return Ok(EventOutcome::AlreadyProcessed);
+        }
+        let current_version =
+            |user_id: &str| entitlements.get(user_id).map_or(0, |stored| stored.version);
+        if updates
+            .iter()
+            .any(|(user_id, update)| current_version(user_id) != update.version)
+        {
+            return Ok(EventOutcome::Conflict);
+        }
+
+        processed_events.insert(event_id.to_string());
+        for (user_id, update) in updates {
+            entitlements.insert(
+                user_id.clone(),
+                StoredEntitlements {
+                    entitlements: update.entitlements.clone(),
+                    version: update.version + 1,
+                },
+            );
+        }
+        Ok(EventOutcome::Applied)
+    }
+
+    async fn delete_entitlements(&self, user_id: &str) -> Result<()> {
+        self.entitlements.lock().unwrap().remove(user_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const USER: &str = "USER#a";
+    const OTHER_USER: &str = "USER#b";
+    const HOUR_MS: i64 = 60 * 60 * 1000;
+
+    // the part of a webhook body we read, as RevenueCat sends it
+    fn event(event_type: &str, at_ms: i64, extra: serde_json::Value) -> RevenueCatEvent {
+        let mut event = json!({
+            "id": uuid::Uuid::new_v4().to_string(),
+            "type": event_type,
+            "app_user_id": USER,
+            "entitlement_ids": ["pro"],
+            "product_id": "pro_monthly",
+            "expiration_at_ms": at_ms + HOUR_MS,
+            "event_timestamp_ms": at_ms,
+        });
+        event
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(event).unwrap()
+    }
+
+    // what the webhook does: apply to the stored state, keep the changed users
+    fn apply(
+        state: &mut HashMap<String, Entitlements>,
+        event: &RevenueCatEvent,
+    ) -> HashMap<String, Entitlements> {
+        let updates = event.apply(state);
+        state.extend(updates.clone());
+        updates
+    }
+
+    fn pro(state: &HashMap<String, Entitlements>, user_id: &str) -> Option<Entitlement> {
+        state
+            .get(user_id)
+            .and_then(|entitlements| entitlements.get("pro").cloned())
+    }
+
+    fn purchased(at_ms: i64) -> HashMap<String, Entitlements> {
+        let mut state = HashMap::new();
+        apply(&mut state, &event("INITIAL_PURCHASE", at_ms, json!({})));
+        state
+    }
+
+    #[test]
+    fn initial_purchase_and_renewal_grant_until_expiration() {
+        let mut state = HashMap::new();
+        let updates = apply(&mut state, &event("INITIAL_PURCHASE", 1000, json!({})));
+        assert_eq!(updates.keys().collect::<Vec<_>>(), vec![USER]);
+        let entitlement = pro(&state, USER).unwrap();
+        assert_eq!(entitlement.product_id, "pro_monthly");
+        assert_eq!(entitlement.expires_at_ms, Some(1000 + HOUR_MS));
+        assert!(entitlement.will_renew);
+        assert!(entitlement.is_active(1000 + HOUR_MS - 1));
+        assert!(!entitlement.is_active(1000 + HOUR_MS));
+
+        apply(&mut state, &event("RENEWAL", 2000 + HOUR_MS, json!({})));
+        let entitlement = pro(&state, USER).unwrap();
+        assert_eq!(entitlement.expires_at_ms, Some(2000 + 2 * HOUR_MS));
+        assert_eq!(entitlement.updated_at_ms, 2000 + HOUR_MS);
+    }
+
+    #[test]
+    fn lifetime_purchases_never_expire() {
+        let mut state = HashMap::new();
+        apply(
+            &mut state,
+            &event(
+                "INITIAL_PURCHASE",
+                1000,
+                json!({ "expiration_at_ms": null }),
+            ),
+        );
+        assert!(pro(&state, USER).unwrap().is_active(i64::MAX));
+    }
+
+    #[test]
+    fn cancellation_keeps_access_until_expiration() {
+        let mut state = purchased(1000);
+        apply(&mut state, &event("CANCELLATION", 2000, json!({})));
+        let entitlement = pro(&state, USER).unwrap();
+        assert!(!entitlement.will_renew);
+        assert!(entitlement.is_active(2000));
+
+        apply(&mut state, &event("UNCANCELLATION", 3000, json!({})));
+        assert!(pro(&state, USER).unwrap().will_renew);
+    }
+
+    #[test]
+    fn expiration_ends_access() {
+        let mut state = purchased(1000);
+        apply(
+            &mut state,
+            &event("EXPIRATION", 5000, json!({ "expiration_at_ms": 4000 })),
+        );
+        let entitlement = pro(&state, USER).unwrap();
+        assert_eq!(entitlement.expires_at_ms, Some(4000));
+        assert!(!entitlement.will_renew);
+        assert!(!entitlement.is_active(5000));
+
+        // without an expiration the event time is used
+        let mut state = purchased(1000);
+        apply(
+            &mut state,
+            &event("EXPIRATION", 5000, json!({ "expiration_at_ms": null })),
+        );
+        assert_eq!(pro(&state, USER).unwrap().expires_at_ms, Some(5000));
+    }
+
+    #[test]
+    fn billing_issue_is_flagged_but_keeps_access() {
+        let mut state = purchased(1000);
+        apply(&mut state, &event("BILLING_ISSUE", 2000, json!({})));
+        let entitlement = pro(&state, USER).unwrap();
+        assert!(entitlement.billing_issue);
+        assert!(entitlement.is_active(2000));
+
+        // the next successful charge clears it
+        apply(&mut state, &event("RENEWAL", 3000, json!({})));
+        assert!(!pro(&state, USER).unwrap().billing_issue);
+    }
+
+    #[test]
+    fn product_change_switches_the_product() {
+        let mut state = purchased(1000);
+        apply(
+            &mut state,
+            &event(
+                "PRODUCT_CHANGE",
+                2000,
+                json!({ "new_product_id": "pro_yearly" }),
+            ),
+        );
+        let entitlement = pro(&state, USER).unwrap();
+        assert_eq!(entitlement.product_id, "pro_yearly");
+        // the expiry only moves with the following renewal
+        assert_eq!(entitlement.expires_at_ms, Some(1000 + HOUR_MS));
+    }
+
+    #[test]
+    fn transfer_moves_entitlements_to_the_new_user() {
+        let mut state = purchased(1000);
+        let updates = apply(
+            &mut state,
+            &event(
+                "TRANSFER",
+                2000,
+                json!({
+                    "app_user_id": null,
+                    "transferred_from": [USER],
+                    "transferred_to": [OTHER_USER],
+                }),
+            ),
+        );
+        assert_eq!(updates.len(), 2);
+        assert!(pro(&state, USER).is_none());
+        let moved = pro(&state, OTHER_USER).unwrap();
+        assert_eq!(moved.product_id, "pro_monthly");
+        assert_eq!(moved.updated_at_ms, 2000);
+    }
+
+    #[test]
+    fn events_for_missing_entitlements_change_nothing() {
+        // the webhook loads every affected user first, those without entitlements are empty
+        let mut state = HashMap::from([(USER.to_string(), Entitlements::new())]);
+        for event_type in [
+            "CANCELLATION",
+            "EXPIRATION",
+            "BILLING_ISSUE",
+            "PRODUCT_CHANGE",
+        ] {
+            assert!(apply(&mut state, &event(event_type, 1000, json!({}))).is_empty());
+        }
+        // acknowledged, but nothing to apply
+        let test_event = event("TEST", 1000, json!({}));
+        assert_eq!(test_event.event_type, RevenueCatEventType::Other);
+        assert!(apply(&mut state, &test_event).is_empty());
+    }
+
+    #[test]
+    fn older_events_are_ignored() {
+        let mut state = purchased(1000);
+        apply(&mut state, &event("RENEWAL", 5000, json!({})));
+        let before = pro(&state, USER).unwrap();
+
+        // delivered late, after the renewal
+        assert!(apply(&mut state, &event("CANCELLATION", 3000, json!({}))).is_empty());
+        assert!(apply(&mut state, &event("EXPIRATION", 4000, json!({}))).is_empty());
+        assert_eq!(pro(&state, USER).unwrap(), before);
+    }
+
+    #[test]
+    fn applying_an_event_again_changes_nothing() {
+        let mut state = purchased(1000);
+        let cancellation = event("CANCELLATION", 2000, json!({}));
+        assert!(!apply(&mut state, &cancellation).is_empty());
+        let after_first = state.clone();
+        assert!(apply(&mut state, &cancellation).is_empty());
+        assert_eq!(state, after_first);
+    }
+
+    // updates as the webhook passes them to apply_event, computed from `version`
+    fn at_version(
+        updates: HashMap<String, Entitlements>,
+        version: u64,
+    ) -> HashMap<String, StoredEntitlements> {
+        updates
+            .into_iter()
+            .map(|(user_id, entitlements)| {
+                (
+                    user_id,
+                    StoredEntitlements {
+                        entitlements,
+                        version,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn replayed_event_ids_are_only_stored_once() {
+        let repository = InMemoryEntitlementRepository::default();
+        let purchase = event("INITIAL_PURCHASE", 1000, json!({}));
+        let updates = purchase.apply(&HashMap::new());
+
+        assert!(!repository.is_event_processed(&purchase.id).await.unwrap());
+        assert!(matches!(
+            repository
+                .apply_event(&purchase.id, &at_version(updates.clone(), 0))
+                .await
+                .unwrap(),
+            EventOutcome::Applied
+        ));
+        assert!(repository.is_event_processed(&purchase.id).await.unwrap());
+
+        // a replay racing past is_event_processed still can't write
+        let mut cancelled = updates;
+        cancelled
+            .get_mut(USER)
+            .unwrap()
+            .get_mut("pro")
+            .unwrap()
+            .will_renew = false;
+        assert!(matches!(
+            repository
+                .apply_event(&purchase.id, &at_version(cancelled, 1))
+                .await
+                .unwrap(),
+            EventOutcome::AlreadyProcessed
+        ));
+        let stored = repository.get_entitlements(USER).await.unwrap();
+        assert!(stored.entitlements["pro"].will_renew);
+        assert_eq!(stored.version, 1);
+    }
+
+    #[actix_web::test]
+    async fn writes_from_a_stale_read_are_turned_down() {
+        let repository = InMemoryEntitlementRepository::default();
+        let purchase = event("INITIAL_PURCHASE", 1000, json!({}));
+        let cancellation = event("CANCELLATION", 2000, json!({}));
+
+        // both webhooks read the user before either wrote. The cancellation changes nothing
+        // then, but still goes back with the version it saw
+        let before = repository.get_entitlements(USER).await.unwrap();
+        let current = HashMap::from([(USER.to_string(), before.entitlements)]);
+        assert!(cancellation.apply(&current).is_empty());
+        assert!(matches!(
+            repository
+                .apply_event(
+                    &purchase.id,
+                    &at_version(purchase.apply(&current), before.version)
+                )
+                .await
+                .unwrap(),
+            EventOutcome::Applied
+        ));
+        assert!(matches!(
+            repository
+                .apply_event(
+                    &cancellation.id,
+                    &at_version(current.clone(), before.version)
+                )
+                .await
+                .unwrap(),
+            EventOutcome::Conflict
+        ));
+        assert!(!repository
+            .is_event_processed(&cancellation.id)
+            .await
+            .unwrap());
+
+        // the retry reads the purchase and applies on top of it
+        let stored = repository.get_entitlements(USER).await.unwrap();
+        let current = HashMap::from([(USER.to_string(), stored.entitlements)]);
+        assert!(matches!(
+            repository
+                .apply_event(
+                    &cancellation.id,
+                    &at_version(cancellation.apply(&current), stored.version)
+                )
+                .await
+                .unwrap(),
+            EventOutcome::Applied
+        ));
+        let pro = &repository
+            .get_entitlements(USER)
+            .await
+            .unwrap()
+            .entitlements["pro"];
+        assert_eq!(pro.product_id, "pro_monthly");
+        assert!(!pro.will_renew);
+    }
+}
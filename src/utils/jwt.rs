@@ -5,25 +5,37 @@ use actix_web::HttpMessage;
 use anyhow::Result;
 use chrono::Duration;
 use chrono::Utc;
-use jsonwebtoken::decode;
-use jsonwebtoken::encode;
-use jsonwebtoken::DecodingKey;
-use jsonwebtoken::EncodingKey;
-use jsonwebtoken::Header;
 use jsonwebtoken::TokenData;
-use jsonwebtoken::Validation;
 use redis::AsyncCommands;
 
 use crate::RedisClient;
 
-use super::environment_variables;
+use super::{
+    api_response::{ApiResponse, ErrorCode},
+    config::JwtConfig,
+    jwt_keys::{KeyRing, TokenError},
+    user::Role,
+};
 
 #[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
 pub struct Claims {
     pub exp: usize,
     pub iat: usize,
+    // defaulted so tokens without them fail validation as invalid claims rather than as malformed
+    #[serde(default)]
+    pub iss: String,
+    #[serde(default)]
+    pub aud: String,
     pub email: String,
     pub id: String,
+    // unique per token
+    pub jti: String,
+    // the session (utils::session) the token belongs to
+    pub sid: String,
+    // the user's token_version when this was issued, see utils::token_version
+    pub ver: u64,
+    #[serde(default)]
+    pub role: Role,
 }
 
 impl FromRequest for Claims {
@@ -37,55 +49,66 @@ impl FromRequest for Claims {
     ) -> future::Ready<Result<Claims, actix_web::Error>> {
         match req.extensions().get::<Claims>() {
             Some(claim) => future::ready(Ok(claim.clone())),
-            None => future::ready(Err(actix_web::error::ErrorBadRequest("Bad Claims"))),
+            None => future::ready(Err(actix_web::Error::from(ApiResponse::error(
+                400,
+                ErrorCode::BadClaims,
+                "Bad Claims",
+            )))),
         }
     }
 }
 
-pub fn encode_jwt(email: String, id: String) -> Result<String> {
+pub fn encode_jwt(
+    key_ring: &KeyRing,
+    config: &JwtConfig,
+    email: String,
+    id: String,
+    session_id: String,
+    token_version: u64,
+    role: Role,
+) -> Result<String> {
     let now = Utc::now();
-    let expire = Duration::hours((super::global_variables::JWT_EXPIRY).clone());
+    let expire = Duration::minutes(config.access_token_minutes);
 
     let claims = Claims {
         exp: (now + expire).timestamp() as usize,
         iat: now.timestamp() as usize,
+        iss: key_ring.issuer().to_string(),
+        aud: config.audience.clone(),
         email,
         id,
+        jti: uuid::Uuid::new_v4().to_string(),
+        sid: session_id,
+        ver: token_version,
+        role,
     };
 
-    let secret = (*environment_variables::JWT_SECRET_KEY).clone();
-
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_ref()),
-    )
-    .map_err(anyhow::Error::from)
+    key_ring.encode(&claims)
 }
 
-pub fn decode_jwt(jwt: String) -> Result<TokenData<Claims>> {
-    let secret = (*environment_variables::JWT_SECRET_KEY).clone();
-    let claim_data: Result<TokenData<Claims>> = decode(
-        &jwt,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &Validation::default(),
-    )
-    .map_err(anyhow::Error::from);
-    claim_data
+pub fn decode_jwt(
+    key_ring: &KeyRing,
+    config: &JwtConfig,
+    jwt: &str,
+) -> std::result::Result<TokenData<Claims>, TokenError> {
+    key_ring.decode(jwt, &config.audience)
 }
 
-// Function to add a token to the blacklist
-pub async fn add_to_blacklist(redis_client: &RedisClient, token: &str) -> Result<()> {
-    let expiry = Duration::hours((super::global_variables::JWT_EXPIRY).clone()).num_seconds();
-    let mut conn = redis_client.get_async_connection().await?;
-    conn.set_ex(format!("blacklist:{}", token), "1", expiry as u64)
+// Blacklists a single access token by its jti, for as long as the token would be valid
+pub async fn add_to_blacklist(
+    redis_client: &RedisClient,
+    config: &JwtConfig,
+    jti: &str,
+) -> Result<()> {
+    let expiry = Duration::minutes(config.access_token_minutes).num_seconds();
+    let mut conn = redis_client.connection();
+    conn.set_ex::<_, _, ()>(format!("blacklist:{}", jti), "1", expiry as u64)
         .await?;
     Ok(())
 }
 
-// Function to check if a token is blacklisted
-pub async fn is_blacklisted(redis_client: &RedisClient, token: &str) -> Result<bool> {
-    let mut conn = redis_client.get_async_connection().await?;
-    let result: Option<String> = conn.get(format!("blacklist:{}", token)).await?;
+pub async fn is_blacklisted(redis_client: &RedisClient, jti: &str) -> Result<bool> {
+    let mut conn = redis_client.connection();
+    let result: Option<String> = conn.get(format!("blacklist:{}", jti)).await?;
     Ok(result.is_some())
 }
@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    errors::ErrorKind,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation,
+};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::config::JwtConfig;
+
+// Why a token was rejected, so callers can tell an expired session from a forged token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    // not a JWT, no kid, or claims of the wrong shape
+    Malformed,
+    // signed with a key we don't know or don't trust anymore
+    InvalidSignature,
+    Expired,
+    // wrong iss/aud, or a token that isn't valid yet
+    InvalidClaims,
+}
+
+impl TokenError {
+    pub fn description(&self) -> &'static str {
+        match self {
+            TokenError::Malformed => "The token is malformed",
+            TokenError::InvalidSignature => "The token signature is invalid",
+            TokenError::Expired => "The token has expired",
+            TokenError::InvalidClaims => "The token was not issued for this service",
+        }
+    }
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+impl From<jsonwebtoken::errors::Error> for TokenError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        match err.kind() {
+            ErrorKind::ExpiredSignature => TokenError::Expired,
+            ErrorKind::InvalidSignature | ErrorKind::InvalidAlgorithm => {
+                TokenError::InvalidSignature
+            }
+            ErrorKind::InvalidIssuer
+            | ErrorKind::InvalidAudience
+            | ErrorKind::InvalidSubject
+            | ErrorKind::ImmatureSignature
+            | ErrorKind::MissingRequiredClaim(_) => TokenError::InvalidClaims,
+            _ => TokenError::Malformed,
+        }
+    }
+}
+
+// DER prefix of an Ed25519 SubjectPublicKeyInfo, the raw 32 byte key follows
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+const HMAC_KID: &str = "hs256";
+
+struct VerificationKey {
+    algorithm: Algorithm,
+    decoding_key: DecodingKey,
+    // None for the HMAC secret, which must never be published
+    jwk: Option<Jwk>,
+}
+
+// Signs with one key and verifies with all of them, picked by the token's `kid`. To rotate,
+// add the new private key, point jwt.active_kid at it and keep the old one (its public half
+// is enough) until the last token signed with it has expired.
+pub struct KeyRing {
+    issuer: String,
+    signing_kid: String,
+    signing_algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    verification_keys: HashMap<String, VerificationKey>,
+}
+
+fn pem_to_der(pem: &str) -> Result<Vec<u8>> {
+    let body: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .map(str::trim)
+        .collect();
+    Ok(STANDARD.decode(body)?)
+}
+
+fn ed25519_verification_key(kid: &str, public_key: &[u8]) -> VerificationKey {
+    VerificationKey {
+        algorithm: Algorithm::EdDSA,
+        decoding_key: DecodingKey::from_ed_der(public_key),
+        jwk: Some(Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(KeyAlgorithm::EdDSA),
+                key_id: Some(kid.to_string()),
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(public_key),
+            }),
+        }),
+    }
+}
+
+impl KeyRing {
+    // that production has a keys_dir is checked with the rest of the config
+    pub(crate) fn from_config(config: &JwtConfig) -> Result<Self> {
+        let mut key_ring = if let Some(dir) = config.keys_dir.as_deref() {
+            Self::from_dir(Path::new(dir), config.active_kid.as_deref())?
+        } else if let Some(secret) = config.secret_key.as_deref() {
+            Self::from_secret(secret)
+        } else {
+            println!(
+                "[!] no JWT keys configured, using a throwaway key. Tokens won't survive a restart"
+            );
+            Self::ephemeral()?
+        };
+        key_ring.issuer = config.issuer.clone();
+        Ok(key_ring)
+    }
+
+    fn from_secret(secret: &str) -> Self {
+        let mut verification_keys = HashMap::new();
+        verification_keys.insert(
+            HMAC_KID.to_string(),
+            VerificationKey {
+                algorithm: Algorithm::HS256,
+                decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+                jwk: None,
+            },
+        );
+        KeyRing {
+            issuer: String::new(),
+            signing_kid: HMAC_KID.to_string(),
+            signing_algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            verification_keys,
+        }
+    }
+
+    fn ephemeral() -> Result<Self> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| anyhow!("Failed to generate an Ed25519 key"))?;
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+            .map_err(|err| anyhow!("Failed to parse generated key: {}", err))?;
+        let kid = format!("ephemeral-{}", uuid::Uuid::new_v4().simple());
+
+        let mut verification_keys = HashMap::new();
+        verification_keys.insert(
+            kid.clone(),
+            ed25519_verification_key(&kid, key_pair.public_key().as_ref()),
+        );
+        Ok(KeyRing {
+            issuer: String::new(),
+            signing_kid: kid,
+            signing_algorithm: Algorithm::EdDSA,
+            encoding_key: EncodingKey::from_ed_der(pkcs8.as_ref()),
+            verification_keys,
+        })
+    }
+
+    // every <kid>.pem in the directory, PKCS#8 private keys or SPKI public keys
+    fn from_dir(dir: &Path, active_kid: Option<&str>) -> Result<Self> {
+        let mut signing_keys = HashMap::new();
+        let mut verification_keys = HashMap::new();
+
+        for entry in fs::read_dir(dir).with_context(|| format!("Can't read {}", dir.display()))? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("pem") {
+                continue;
+            }
+            let kid = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or_else(|| anyhow!("Bad key file name {}", path.display()))?
+                .to_string();
+            let pem = fs::read_to_string(&path)
+                .with_context(|| format!("Can't read {}", path.display()))?;
+            let der = pem_to_der(&pem).with_context(|| format!("Bad PEM in {}", path.display()))?;
+
+            let public_key = if pem.contains("BEGIN PRIVATE KEY") {
+                // openssl genpkey writes PKCS#8 v1, which the checked parser rejects
+                let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der).map_err(|err| {
+                    anyhow!("{} is not an Ed25519 private key: {}", path.display(), err)
+                })?;
+                signing_keys.insert(kid.clone(), EncodingKey::from_ed_der(&der));
+                key_pair.public_key().as_ref().to_vec()
+            } else if pem.contains("BEGIN PUBLIC KEY") {
+                match der.strip_prefix(&ED25519_SPKI_PREFIX[..]) {
+                    Some(public_key) if public_key.len() == 32 => public_key.to_vec(),
+                    _ => bail!("{} is not an Ed25519 public key", path.display()),
+                }
+            } else {
+                bail!(
+                    "{} holds neither a private nor a public key",
+                    path.display()
+                );
+            };
+
+            verification_keys.insert(kid.clone(), ed25519_verification_key(&kid, &public_key));
+        }
+
+        let signing_kid = match active_kid {
+            Some(kid) => kid.to_string(),
+            None if signing_keys.len() == 1 => signing_keys.keys().next().unwrap().clone(),
+            None => bail!(
+                "Found {} private keys in {}, set jwt.active_kid to pick one",
+                signing_keys.len(),
+                dir.display()
+            ),
+        };
+        let encoding_key = signing_keys
+            .remove(&signing_kid)
+            .ok_or_else(|| anyhow!("No private key {}.pem in {}", signing_kid, dir.display()))?;
+
+        Ok(KeyRing {
+            issuer: String::new(),
+            signing_kid,
+            signing_algorithm: Algorithm::EdDSA,
+            encoding_key,
+            verification_keys,
+        })
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    pub fn signing_kid(&self) -> &str {
+        &self.signing_kid
+    }
+
+    pub fn signing_algorithm(&self) -> Algorithm {
+        self.signing_algorithm
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String> {
+        let mut header = Header::new(self.signing_algorithm);
+        header.kid = Some(self.signing_kid.clone());
+        encode(&header, claims, &self.encoding_key).map_err(anyhow::Error::from)
+    }
+
+    // The key (and with it the algorithm) comes from our own list by kid, never from the header's alg.
+    // Besides the signature and exp, iss must be ours and aud the given audience
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        audience: &str,
+    ) -> std::result::Result<TokenData<T>, TokenError> {
+        let kid = decode_header(token)?.kid.ok_or(TokenError::Malformed)?;
+        let key = self
+            .verification_keys
+            .get(&kid)
+            .ok_or(TokenError::InvalidSignature)?;
+
+        let mut validation = Validation::new(key.algorithm);
+        validation.set_issuer(&[self.issuer.as_str()]);
+        validation.set_audience(&[audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+
+        Ok(decode(token, &key.decoding_key, &validation)?)
+    }
+
+    // public keys for /.well-known/jwks.json, empty when signing with the HMAC secret
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .verification_keys
+                .values()
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct TestClaims {
+        sub: String,
+        iss: String,
+        aud: String,
+        exp: usize,
+    }
+
+    const AUDIENCE: &str = "test";
+
+    fn claims() -> TestClaims {
+        TestClaims {
+            sub: "USER#a".to_string(),
+            iss: JwtConfig::default().issuer,
+            aud: AUDIENCE.to_string(),
+            exp: (chrono::Utc::now().timestamp() + 60) as usize,
+        }
+    }
+
+    fn load(config: JwtConfig) -> Result<KeyRing> {
+        KeyRing::from_config(&config)
+    }
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        format!(
+            "-----BEGIN {label}-----\n{}\n-----END {label}-----\n",
+            STANDARD.encode(der)
+        )
+    }
+
+    // a fresh directory holding the given keys, private ones as <kid>.pem and public only
+    // ones as <kid>.pem with just the SPKI
+    fn key_dir(private: &[&str], public: &[&str]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("jwt-keys-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        for (kid, private) in private
+            .iter()
+            .map(|kid| (kid, true))
+            .chain(public.iter().map(|kid| (kid, false)))
+        {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            let contents = if private {
+                pem("PRIVATE KEY", pkcs8.as_ref())
+            } else {
+                let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+                let spki = [&ED25519_SPKI_PREFIX[..], key_pair.public_key().as_ref()].concat();
+                pem("PUBLIC KEY", &spki)
+            };
+            fs::write(dir.join(format!("{}.pem", kid)), contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn signs_with_the_active_key_and_publishes_all_public_keys() {
+        let dir = key_dir(&["old", "new"], &["retired"]);
+        let keys_dir = Some(dir.to_string_lossy().to_string());
+        assert!(load(JwtConfig {
+            keys_dir: keys_dir.clone(),
+            ..Default::default()
+        })
+        .is_err());
+        let key_ring = load(JwtConfig {
+            keys_dir,
+            active_kid: Some("new".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let token = key_ring.encode(&claims()).unwrap();
+        assert_eq!(decode_header(&token).unwrap().kid.as_deref(), Some("new"));
+        assert_eq!(
+            key_ring
+                .decode::<TestClaims>(&token, AUDIENCE)
+                .unwrap()
+                .claims,
+            claims()
+        );
+
+        let mut kids: Vec<String> = key_ring
+            .jwks()
+            .keys
+            .into_iter()
+            .filter_map(|jwk| jwk.common.key_id)
+            .collect();
+        kids.sort();
+        assert_eq!(kids, ["new", "old", "retired"]);
+    }
+
+    #[test]
+    fn rejects_tokens_from_other_keys() {
+        let key_ring = load(JwtConfig::default()).unwrap();
+        let other = load(JwtConfig::default()).unwrap();
+        let token = other.encode(&claims()).unwrap();
+        assert_eq!(
+            key_ring.decode::<TestClaims>(&token, AUDIENCE).unwrap_err(),
+            TokenError::InvalidSignature
+        );
+
+        // an HS256 token signed with a published public key must not pass as EdDSA
+        let jwk = key_ring.jwks().keys.remove(0);
+        let AlgorithmParameters::OctetKeyPair(params) = &jwk.algorithm else {
+            panic!("not an Ed25519 key");
+        };
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(key_ring.signing_kid().to_string());
+        let forged = encode(
+            &header,
+            &claims(),
+            &EncodingKey::from_secret(&URL_SAFE_NO_PAD.decode(&params.x).unwrap()),
+        )
+        .unwrap();
+        assert_eq!(
+            key_ring
+                .decode::<TestClaims>(&forged, AUDIENCE)
+                .unwrap_err(),
+            TokenError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn tells_why_a_token_was_rejected() {
+        let key_ring = load(JwtConfig::default()).unwrap();
+        let decode = |claims: TestClaims| {
+            key_ring
+                .decode::<TestClaims>(&key_ring.encode(&claims).unwrap(), AUDIENCE)
+                .unwrap_err()
+        };
+
+        // well past the default leeway of a minute
+        let expired = TestClaims {
+            exp: (chrono::Utc::now().timestamp() - 120) as usize,
+            ..claims()
+        };
+        assert_eq!(decode(expired), TokenError::Expired);
+        let other_issuer = TestClaims {
+            iss: "someone-else".to_string(),
+            ..claims()
+        };
+        assert_eq!(decode(other_issuer), TokenError::InvalidClaims);
+        let other_audience = TestClaims {
+            aud: "another-api".to_string(),
+            ..claims()
+        };
+        assert_eq!(decode(other_audience), TokenError::InvalidClaims);
+        assert_eq!(
+            key_ring
+                .decode::<TestClaims>("not.a.token", AUDIENCE)
+                .unwrap_err(),
+            TokenError::Malformed
+        );
+    }
+
+    #[test]
+    fn never_publishes_the_hmac_secret() {
+        let key_ring = load(JwtConfig {
+            secret_key: Some("test-secret-that-is-at-least-32-chars".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        let token = key_ring.encode(&claims()).unwrap();
+        assert_eq!(
+            key_ring
+                .decode::<TestClaims>(&token, AUDIENCE)
+                .unwrap()
+                .claims,
+            claims()
+        );
+        assert!(key_ring.jwks().keys.is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use aws_sdk_bedrockruntime::Client as BedrockClient;
+use serde_json::{json, Value};
+
+use super::{
+    invoke_bedrock, invoke_bedrock_stream, map_chunks, Generation, GenerationRequest,
+    GenerationStream, LlmProvider, StreamEvent, StreamSummary,
+};
+
+pub(crate) const DEFAULT_MODEL_ID: &str = "anthropic.claude-3-haiku-20240307-v1:0";
+const ANTHROPIC_VERSION: &str = "bedrock-2023-05-31";
+
+#[derive(serde::Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    stop_reason: Option<String>,
+    usage: Usage,
+}
+
+#[derive(serde::Deserialize)]
+struct ContentBlock {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Usage {
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+// streaming events, see https://docs.anthropic.com/en/api/messages-streaming
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum MessagesStreamEvent {
+    MessageStart {
+        message: MessageStart,
+    },
+    ContentBlockDelta {
+        delta: ContentDelta,
+    },
+    MessageDelta {
+        delta: MessageDelta,
+        usage: OutputUsage,
+    },
+    MessageStop,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(serde::Deserialize)]
+struct MessageStart {
+    usage: Usage,
+}
+
+#[derive(serde::Deserialize)]
+struct ContentDelta {
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(serde::Deserialize)]
+struct MessageDelta {
+    stop_reason: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct OutputUsage {
+    output_tokens: u32,
+}
+
+#[derive(Default)]
+struct AnthropicStreamState {
+    stop_reason: Option<String>,
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+fn parse_chunk(state: &mut AnthropicStreamState, chunk: Value) -> Result<Vec<StreamEvent>> {
+    Ok(match serde_json::from_value(chunk)? {
+        MessagesStreamEvent::MessageStart { message } => {
+            state.input_tokens = message.usage.input_tokens;
+            state.output_tokens = message.usage.output_tokens;
+            vec![]
+        }
+        MessagesStreamEvent::ContentBlockDelta { delta } if !delta.text.is_empty() => {
+            vec![StreamEvent::Delta(delta.text)]
+        }
+        MessagesStreamEvent::MessageDelta { delta, usage } => {
+            state.stop_reason = delta.stop_reason;
+            state.output_tokens = usage.output_tokens;
+            vec![]
+        }
+        MessagesStreamEvent::MessageStop => vec![StreamEvent::Done(StreamSummary {
+            stop_reason: state.stop_reason.take(),
+            input_tokens: state.input_tokens,
+            output_tokens: state.output_tokens,
+        })],
+        _ => vec![],
+    })
+}
+
+fn request_body(request: &GenerationRequest) -> Value {
+    json!({
+        "anthropic_version": ANTHROPIC_VERSION,
+        "max_tokens": request.max_tokens,
+        "messages": [
+            {
+                "role": "user",
+                "content": [{ "type": "text", "text": request.prompt }]
+            }
+        ]
+    })
+}
+
+fn parse_response(response: Value) -> Result<Generation> {
+    let response: MessagesResponse = serde_json::from_value(response)?;
+    Ok(Generation {
+        text: response
+            .content
+            .into_iter()
+            .filter(|block| block.kind == "text")
+            .map(|block| block.text)
+            .collect(),
+        stop_reason: response.stop_reason,
+        input_tokens: response.usage.input_tokens,
+        output_tokens: response.usage.output_tokens,
+    })
+}
+
+// Anthropic models on bedrock, using the Messages API request shape
+pub(crate) struct AnthropicProvider {
+    bedrock_client: Arc<BedrockClient>,
+    model_id: String,
+}
+
+impl AnthropicProvider {
+    pub(crate) fn new(bedrock_client: Arc<BedrockClient>, model_id: String) -> Self {
+        Self {
+            bedrock_client,
+            model_id,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    async fn generate(&self, request: &GenerationRequest) -> Result<Generation> {
+        parse_response(
+            invoke_bedrock(&self.bedrock_client, &self.model_id, &request_body(request)).await?,
+        )
+    }
+
+    async fn generate_stream(&self, request: &GenerationRequest) -> Result<GenerationStream> {
+        let chunks =
+            invoke_bedrock_stream(&self.bedrock_client, &self.model_id, &request_body(request))
+                .await?;
+        Ok(map_chunks(
+            chunks,
+            AnthropicStreamState::default(),
+            parse_chunk,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_the_messages_request() {
+        let body = request_body(&GenerationRequest {
+            prompt: "draw a cat".to_string(),
+            max_tokens: 42,
+        });
+        assert_eq!(
+            body,
+            json!({
+                "anthropic_version": "bedrock-2023-05-31",
+                "max_tokens": 42,
+                "messages": [{
+                    "role": "user",
+                    "content": [{ "type": "text", "text": "draw a cat" }]
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn parses_the_response() {
+        let generation = parse_response(json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "content": [
+                { "type": "text", "text": "A cat, " },
+                { "type": "tool_use", "id": "t", "name": "x", "input": {} },
+                { "type": "text", "text": "drawn." }
+            ],
+            "stop_reason": "max_tokens",
+            "usage": { "input_tokens": 4, "output_tokens": 7 }
+        }))
+        .unwrap();
+        assert_eq!(generation.text, "A cat, drawn.");
+        assert_eq!(generation.stop_reason.as_deref(), Some("max_tokens"));
+        assert_eq!(generation.input_tokens, 4);
+        assert_eq!(generation.output_tokens, 7);
+    }
+
+    #[test]
+    fn parses_stream_events() {
+        let chunks = [
+            json!({
+                "type": "message_start",
+                "message": { "id": "msg_1", "usage": { "input_tokens": 4, "output_tokens": 1 } }
+            }),
+            json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "text", "text": "" } }),
+            json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": "A cat" } }),
+            json!({ "type": "ping" }),
+            json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": ", drawn." } }),
+            json!({ "type": "content_block_stop", "index": 0 }),
+            json!({
+                "type": "message_delta",
+                "delta": { "stop_reason": "end_turn" },
+                "usage": { "output_tokens": 7 }
+            }),
+            json!({ "type": "message_stop" }),
+        ];
+
+        let mut state = AnthropicStreamState::default();
+        let events: Vec<StreamEvent> = chunks
+            .into_iter()
+            .flat_map(|chunk| parse_chunk(&mut state, chunk).unwrap())
+            .collect();
+        match &events[..] {
+            [StreamEvent::Delta(first), StreamEvent::Delta(second), StreamEvent::Done(summary)] => {
+                assert_eq!(format!("{}{}", first, second), "A cat, drawn.");
+                assert_eq!(summary.stop_reason.as_deref(), Some("end_turn"));
+                assert_eq!(summary.input_tokens, 4);
+                assert_eq!(summary.output_tokens, 7);
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+
+        assert!(parse_chunk(&mut state, json!({ "no": "type" })).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use aws_sdk_bedrockruntime::{primitives::Blob, types::ResponseStream, Client as BedrockClient};
+use futures_util::{
+    future,
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use serde_json::Value;
+
+use super::config::{LlmConfig, LlmProviderKind};
+
+pub mod anthropic;
+pub mod stub;
+pub mod titan;
+
+#[derive(Debug, Clone)]
+pub(crate) struct GenerationRequest {
+    pub(crate) prompt: String,
+    pub(crate) max_tokens: u32,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Generation {
+    pub(crate) text: String,
+    pub(crate) stop_reason: Option<String>,
+    pub(crate) input_tokens: u32,
+    pub(crate) output_tokens: u32,
+}
+
+// sent once at the end of a stream
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StreamSummary {
+    pub(crate) stop_reason: Option<String>,
+    pub(crate) input_tokens: u32,
+    pub(crate) output_tokens: u32,
+}
+
+#[derive(Debug)]
+pub(crate) enum StreamEvent {
+    Delta(String),
+    Done(StreamSummary),
+}
+
+// dropping the stream drops the bedrock event receiver, which cancels the upstream request
+pub(crate) type GenerationStream = BoxStream<'static, Result<StreamEvent>>;
+
+// One implementation per model family. Handlers only talk to this trait,
+// the concrete provider is picked from llm.provider / llm.model_id at startup.
+#[async_trait]
+pub(crate) trait LlmProvider: Send + Sync {
+    fn model_id(&self) -> &str;
+    async fn generate(&self, request: &GenerationRequest) -> Result<Generation>;
+    async fn generate_stream(&self, request: &GenerationRequest) -> Result<GenerationStream>;
+}
+
+pub(crate) fn provider_from_config(
+    config: &LlmConfig,
+    bedrock_client: Arc<BedrockClient>,
+) -> Arc<dyn LlmProvider> {
+    let model_id = config.model_id.clone();
+    match config.provider {
+        LlmProviderKind::Titan => Arc::new(titan::TitanProvider::new(
+            bedrock_client,
+            model_id.unwrap_or(titan::DEFAULT_MODEL_ID.to_string()),
+        )),
+        LlmProviderKind::Anthropic => Arc::new(anthropic::AnthropicProvider::new(
+            bedrock_client,
+            model_id.unwrap_or(anthropic::DEFAULT_MODEL_ID.to_string()),
+        )),
+        LlmProviderKind::Stub => Arc::new(stub::StubProvider),
+    }
+}
+
+// shared by the bedrock backed providers, sends a JSON body and parses the JSON answer
+pub(crate) async fn invoke_bedrock(
+    bedrock_client: &BedrockClient,
+    model_id: &str,
+    request_body: &Value,
+) -> Result<Value> {
+    let result = bedrock_client
+        .invoke_model()
+        .model_id(model_id)
+        .content_type("application/json")
+        .accept("application/json")
+        .body(Blob::new(serde_json::to_vec(request_body)?))
+        .send()
+        .await
+        .map_err(|err| anyhow!("{:#?}", err))?;
+
+    serde_json::from_slice(result.body().as_ref()).map_err(anyhow::Error::from)
+}
+
+// streaming counterpart of invoke_bedrock, yields every chunk's JSON payload
+pub(crate) async fn invoke_bedrock_stream(
+    bedrock_client: &BedrockClient,
+    model_id: &str,
+    request_body: &Value,
+) -> Result<BoxStream<'static, Result<Value>>> {
+    let result = bedrock_client
+        .invoke_model_with_response_stream()
+        .model_id(model_id)
+        .content_type("application/json")
+        .accept("application/json")
+        .body(Blob::new(serde_json::to_vec(request_body)?))
+        .send()
+        .await
+        .map_err(|err| anyhow!("{:#?}", err))?;
+
+    let chunks = stream::unfold(Some(result.body), |receiver| async move {
+        let mut receiver = receiver?;
+        loop {
+            match receiver.recv().await {
+                Ok(Some(ResponseStream::Chunk(part))) => {
+                    let Some(bytes) = part.bytes() else { continue };
+                    let chunk = serde_json::from_slice(bytes.as_ref()).map_err(anyhow::Error::from);
+                    return Some((chunk, Some(receiver)));
+                }
+                Ok(Some(_)) => continue,
+                Ok(None) => return None,
+                // stop after the first error, the receiver is unusable afterwards
+                Err(err) => return Some((Err(anyhow!("{:#?}", err)), None)),
+            }
+        }
+    });
+    Ok(chunks.boxed())
+}
+
+// turns raw chunks into StreamEvents, `parse` may keep state (e.g. token counts) between chunks
+pub(crate) fn map_chunks<S: Send + 'static>(
+    chunks: BoxStream<'static, Result<Value>>,
+    state: S,
+    parse: fn(&mut S, Value) -> Result<Vec<StreamEvent>>,
+) -> GenerationStream {
+    chunks
+        .scan(state, move |state, chunk| {
+            future::ready(Some(chunk.and_then(|chunk| parse(state, chunk))))
+        })
+        .flat_map(|events| {
+            stream::iter(match events {
+                Ok(events) => events.into_iter().map(Ok).collect(),
+                Err(err) => vec![Err(err)],
+            })
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_bedrockruntime::config::{BehaviorVersion, Config as BedrockConfig};
+    use serde_json::json;
+
+    use super::*;
+
+    // never sends anything, the tests don't call bedrock
+    fn bedrock_client() -> Arc<BedrockClient> {
+        Arc::new(BedrockClient::from_conf(
+            BedrockConfig::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .build(),
+        ))
+    }
+
+    fn provider(provider: LlmProviderKind, model_id: Option<&str>) -> Arc<dyn LlmProvider> {
+        let config = LlmConfig {
+            provider,
+            model_id: model_id.map(str::to_string),
+            ..Default::default()
+        };
+        provider_from_config(&config, bedrock_client())
+    }
+
+    #[test]
+    fn picks_the_configured_provider() {
+        assert_eq!(
+            provider(LlmProviderKind::Titan, None).model_id(),
+            titan::DEFAULT_MODEL_ID
+        );
+        assert_eq!(
+            provider(LlmProviderKind::Anthropic, None).model_id(),
+            anthropic::DEFAULT_MODEL_ID
+        );
+        assert_eq!(
+            provider(LlmProviderKind::Anthropic, Some("anthropic.claude-x")).model_id(),
+            "anthropic.claude-x"
+        );
+        assert_eq!(provider(LlmProviderKind::Stub, None).model_id(), "stub");
+    }
+
+    #[actix_web::test]
+    async fn map_chunks_keeps_state_and_passes_errors_on() {
+        // counts chunks, every chunk becomes a delta with the count so far
+        fn parse(count: &mut u32, chunk: Value) -> Result<Vec<StreamEvent>> {
+            *count += 1;
+            let text = chunk["text"].as_str().ok_or_else(|| anyhow!("no text"))?;
+            Ok(vec![StreamEvent::Delta(format!("{}{}", count, text))])
+        }
+
+        let chunks = stream::iter(vec![
+            Ok(json!({ "text": "a" })),
+            Ok(json!({ "text": "b" })),
+            Ok(json!({})),
+            Err(anyhow!("upstream failed")),
+        ])
+        .boxed();
+        let events: Vec<Result<StreamEvent>> = map_chunks(chunks, 0, parse).collect().await;
+
+        assert!(matches!(&events[0], Ok(StreamEvent::Delta(text)) if text == "1a"));
+        assert!(matches!(&events[1], Ok(StreamEvent::Delta(text)) if text == "2b"));
+        assert_eq!(events[2].as_ref().unwrap_err().to_string(), "no text");
+        assert_eq!(
+            events[3].as_ref().unwrap_err().to_string(),
+            "upstream failed"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+
+use super::{
+    Generation, GenerationRequest, GenerationStream, LlmProvider, StreamEvent, StreamSummary,
+};
+
+// Deterministic provider for tests and local runs, never calls bedrock.
+// Echoes the prompt back one word per token, cut off at max_tokens.
+pub(crate) struct StubProvider;
+
+impl StubProvider {
+    fn words(request: &GenerationRequest) -> (Vec<String>, StreamSummary) {
+        let words: Vec<String> = std::iter::once("echo:")
+            .chain(request.prompt.split_whitespace())
+            .map(str::to_string)
+            .collect();
+        let truncated = words.len() > request.max_tokens as usize;
+        let output: Vec<String> = words
+            .into_iter()
+            .take(request.max_tokens as usize)
+            .collect();
+
+        let summary = StreamSummary {
+            stop_reason: Some(if truncated { "max_tokens" } else { "end_turn" }.to_string()),
+            input_tokens: request.prompt.split_whitespace().count() as u32,
+            output_tokens: output.len() as u32,
+        };
+        (output, summary)
+    }
+}
+
+#[async_trait]
+impl LlmProvider for StubProvider {
+    fn model_id(&self) -> &str {
+        "stub"
+    }
+
+    async fn generate(&self, request: &GenerationRequest) -> Result<Generation> {
+        let (output, summary) = Self::words(request);
+        Ok(Generation {
+            text: output.join(" "),
+            stop_reason: summary.stop_reason,
+            input_tokens: summary.input_tokens,
+            output_tokens: summary.output_tokens,
+        })
+    }
+
+    async fn generate_stream(&self, request: &GenerationRequest) -> Result<GenerationStream> {
+        let (output, summary) = Self::words(request);
+        let deltas = output
+            .into_iter()
+            .enumerate()
+            .map(|(i, word)| StreamEvent::Delta(if i == 0 { word } else { format!(" {}", word) }));
+        Ok(stream::iter(
+            deltas
+                .chain(std::iter::once(StreamEvent::Done(summary)))
+                .map(Ok),
+        )
+        .boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(prompt: &str, max_tokens: u32) -> GenerationRequest {
+        GenerationRequest {
+            prompt: prompt.to_string(),
+            max_tokens,
+        }
+    }
+
+    #[actix_web::test]
+    async fn echoes_the_prompt() {
+        let generation = StubProvider
+            .generate(&request("draw  a\ncat", 100))
+            .await
+            .unwrap();
+        assert_eq!(generation.text, "echo: draw a cat");
+        assert_eq!(generation.stop_reason.as_deref(), Some("end_turn"));
+        assert_eq!(generation.input_tokens, 3);
+        assert_eq!(generation.output_tokens, 4);
+    }
+
+    #[actix_web::test]
+    async fn stops_at_max_tokens() {
+        let generation = StubProvider
+            .generate(&request("one two three four", 3))
+            .await
+            .unwrap();
+        assert_eq!(generation.text, "echo: one two");
+        assert_eq!(generation.stop_reason.as_deref(), Some("max_tokens"));
+        assert_eq!(generation.output_tokens, 3);
+
+        // exactly enough room is not a truncation
+        let generation = StubProvider.generate(&request("one two", 3)).await.unwrap();
+        assert_eq!(generation.stop_reason.as_deref(), Some("end_turn"));
+    }
+
+    #[actix_web::test]
+    async fn streams_the_same_text() {
+        let request = request("one two three four", 3);
+        let generation = StubProvider.generate(&request).await.unwrap();
+        let events: Vec<StreamEvent> = StubProvider
+            .generate_stream(&request)
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        let mut text = String::new();
+        let mut summary = None;
+        for event in events {
+            assert!(summary.is_none(), "nothing may follow Done");
+            match event {
+                StreamEvent::Delta(delta) => text.push_str(&delta),
+                StreamEvent::Done(done) => summary = Some(done),
+            }
+        }
+        let summary = summary.unwrap();
+        assert_eq!(text, generation.text);
+        assert_eq!(summary.stop_reason, generation.stop_reason);
+        assert_eq!(summary.input_tokens, generation.input_tokens);
+        assert_eq!(summary.output_tokens, generation.output_tokens);
+    }
+}
@@ -0,0 +1,200 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use aws_sdk_bedrockruntime::Client as BedrockClient;
+use serde_json::{json, Value};
+
+use super::{
+    invoke_bedrock, invoke_bedrock_stream, map_chunks, Generation, GenerationRequest,
+    GenerationStream, LlmProvider, StreamEvent, StreamSummary,
+};
+
+pub(crate) const DEFAULT_MODEL_ID: &str = "amazon.titan-text-express-v1";
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TitanResponse {
+    input_text_token_count: u32,
+    results: Vec<TitanResult>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TitanResult {
+    token_count: u32,
+    output_text: String,
+    completion_reason: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TitanChunk {
+    #[serde(default)]
+    output_text: String,
+    input_text_token_count: Option<u32>,
+    total_output_text_token_count: Option<u32>,
+    completion_reason: Option<String>,
+}
+
+#[derive(Default)]
+struct TitanStreamState {
+    input_tokens: u32,
+}
+
+fn parse_chunk(state: &mut TitanStreamState, chunk: Value) -> Result<Vec<StreamEvent>> {
+    let chunk: TitanChunk = serde_json::from_value(chunk)?;
+    if let Some(input_tokens) = chunk.input_text_token_count {
+        state.input_tokens = input_tokens;
+    }
+
+    let mut events = Vec::new();
+    if !chunk.output_text.is_empty() {
+        events.push(StreamEvent::Delta(chunk.output_text));
+    }
+    if chunk.completion_reason.is_some() {
+        events.push(StreamEvent::Done(StreamSummary {
+            stop_reason: chunk.completion_reason,
+            input_tokens: state.input_tokens,
+            output_tokens: chunk.total_output_text_token_count.unwrap_or_default(),
+        }));
+    }
+    Ok(events)
+}
+
+fn request_body(request: &GenerationRequest) -> Value {
+    json!({
+        "inputText": request.prompt,
+        "textGenerationConfig": {
+            "maxTokenCount": request.max_tokens,
+        }
+    })
+}
+
+fn parse_response(response: Value) -> Result<Generation> {
+    let response: TitanResponse = serde_json::from_value(response)?;
+    let result = response
+        .results
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("Titan returned no results"))?;
+
+    Ok(Generation {
+        text: result.output_text,
+        stop_reason: result.completion_reason,
+        input_tokens: response.input_text_token_count,
+        output_tokens: result.token_count,
+    })
+}
+
+// Amazon Titan text models, using the inputText / textGenerationConfig request shape
+pub(crate) struct TitanProvider {
+    bedrock_client: Arc<BedrockClient>,
+    model_id: String,
+}
+
+impl TitanProvider {
+    pub(crate) fn new(bedrock_client: Arc<BedrockClient>, model_id: String) -> Self {
+        Self {
+            bedrock_client,
+            model_id,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for TitanProvider {
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    async fn generate(&self, request: &GenerationRequest) -> Result<Generation> {
+        parse_response(
+            invoke_bedrock(&self.bedrock_client, &self.model_id, &request_body(request)).await?,
+        )
+    }
+
+    async fn generate_stream(&self, request: &GenerationRequest) -> Result<GenerationStream> {
+        let chunks =
+            invoke_bedrock_stream(&self.bedrock_client, &self.model_id, &request_body(request))
+                .await?;
+        Ok(map_chunks(chunks, TitanStreamState::default(), parse_chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_the_titan_request() {
+        let body = request_body(&GenerationRequest {
+            prompt: "draw a cat".to_string(),
+            max_tokens: 42,
+        });
+        assert_eq!(
+            body,
+            json!({
+                "inputText": "draw a cat",
+                "textGenerationConfig": { "maxTokenCount": 42 }
+            })
+        );
+    }
+
+    #[test]
+    fn parses_the_response() {
+        let generation = parse_response(json!({
+            "inputTextTokenCount": 4,
+            "results": [{
+                "tokenCount": 7,
+                "outputText": "A cat, drawn.",
+                "completionReason": "FINISH"
+            }]
+        }))
+        .unwrap();
+        assert_eq!(generation.text, "A cat, drawn.");
+        assert_eq!(generation.stop_reason.as_deref(), Some("FINISH"));
+        assert_eq!(generation.input_tokens, 4);
+        assert_eq!(generation.output_tokens, 7);
+
+        assert!(parse_response(json!({ "inputTextTokenCount": 4, "results": [] })).is_err());
+    }
+
+    #[test]
+    fn parses_stream_chunks() {
+        let mut state = TitanStreamState::default();
+        let events = parse_chunk(
+            &mut state,
+            json!({
+                "outputText": "A cat",
+                "index": 0,
+                "inputTextTokenCount": 4,
+                "totalOutputTextTokenCount": 2,
+                "completionReason": null
+            }),
+        )
+        .unwrap();
+        assert!(matches!(&events[..], [StreamEvent::Delta(text)] if text == "A cat"));
+
+        let events = parse_chunk(
+            &mut state,
+            json!({
+                "outputText": ", drawn.",
+                "index": 0,
+                "totalOutputTextTokenCount": 7,
+                "completionReason": "LENGTH",
+                "amazon-bedrock-invocationMetrics": { "inputTokenCount": 4 }
+            }),
+        )
+        .unwrap();
+        match &events[..] {
+            [StreamEvent::Delta(text), StreamEvent::Done(summary)] => {
+                assert_eq!(text, ", drawn.");
+                assert_eq!(summary.stop_reason.as_deref(), Some("LENGTH"));
+                assert_eq!(summary.input_tokens, 4);
+                assert_eq!(summary.output_tokens, 7);
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+}
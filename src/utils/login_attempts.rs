@@ -0,0 +1,382 @@
+use anyhow::Result;
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+use sha256::digest;
+
+use crate::RedisClient;
+
+use super::{config::AuthConfig, user::normalize_email};
+
+// Failed logins are counted per email, against guessing one account's password, and per IP,
+// against trying leaked credentials for many accounts. Each failure is a sorted set member
+// scored by its time, so the count always covers exactly the last window.
+struct Limits {
+    scope: &'static str,
+    // from this many failures on, every retry has to wait twice as long as the one before
+    delay_after: u64,
+    // this many failures lock the scope for auth.login_lockout_seconds
+    lockout_after: u64,
+}
+
+const EMAIL_LIMITS: Limits = Limits {
+    scope: "email",
+    delay_after: 3,
+    lockout_after: 10,
+};
+
+// one IP can be a whole office or carrier NAT, so it gets more room
+const IP_LIMITS: Limits = Limits {
+    scope: "ip",
+    delay_after: 20,
+    lockout_after: 100,
+};
+
+fn failures_key(limits: &Limits, id: &str) -> String {
+    format!("login_failures:{}:{}", limits.scope, id)
+}
+
+fn lockout_key(limits: &Limits, id: &str) -> String {
+    format!("login_lockout:{}:{}", limits.scope, id)
+}
+
+// hashed so raw addresses don't end up in redis. Handlers normalize the email before the user
+// lookup, doing it here too keeps the count right for any caller that doesn't
+fn email_id(email: &str) -> String {
+    digest(normalize_email(email))
+}
+
+// (limits, id) pairs for one attempt, the IP one only if we know where the request came from
+fn scopes(email: &str, ip: Option<&str>) -> Vec<(&'static Limits, String)> {
+    let mut scopes = vec![(&EMAIL_LIMITS, email_id(email))];
+    if let Some(ip) = ip {
+        scopes.push((&IP_LIMITS, ip.to_string()));
+    }
+    scopes
+}
+
+fn delay_seconds(config: &AuthConfig, limits: &Limits, failures: u64) -> u64 {
+    if failures < limits.delay_after {
+        return 0;
+    }
+    let doublings = (failures - limits.delay_after).min(u32::MAX as u64) as u32;
+    2u64.saturating_pow(doublings)
+        .min(config.login_max_delay_seconds)
+}
+
+fn window_start_ms(config: &AuthConfig, now_ms: i64) -> i64 {
+    now_ms - (config.login_failure_window_seconds * 1000) as i64
+}
+
+// How long one scope makes an attempt wait, given the state before it: seconds left on a
+// lockout, else the failures in the window and when the last of them happened
+fn wait_seconds(
+    config: &AuthConfig,
+    limits: &Limits,
+    now_ms: i64,
+    locked_for: i64,
+    failures: u64,
+    last_failure_ms: Option<i64>,
+) -> Option<u64> {
+    if locked_for > 0 {
+        return Some(locked_for as u64);
+    }
+    let delay_ms = (delay_seconds(config, limits, failures) * 1000) as i64;
+    let wait_ms = last_failure_ms
+        .map(|at| at + delay_ms - now_ms)
+        .unwrap_or(0);
+    (wait_ms > 0).then(|| (wait_ms as u64).div_ceil(1000))
+}
+
+pub(crate) enum Reservation {
+    Allowed(LoginAttempt),
+    // locked out or delayed for this many more seconds
+    RetryAfter(u64),
+}
+
+// An attempt let through by reserve_login_attempt. It's already counted as a failure, so
+// attempts running at the same time see it. Only `succeeded` or `released` take it back, an
+// attempt that ends any other way stays a failure.
+pub(crate) struct LoginAttempt {
+    member: String,
+    scopes: Vec<(&'static Limits, String)>,
+}
+
+// Checks the limits and reserves the attempt in one step per scope, before the password (or
+// the second factor) is checked, so a locked account can't be probed any further and parallel guesses can't all get
+// in before the first of them is recorded.
+pub(crate) async fn reserve_login_attempt(
+    redis_client: &RedisClient,
+    config: &AuthConfig,
+    email: &str,
+    ip: Option<&str>,
+) -> Result<Reservation> {
+    let mut conn = redis_client.connection();
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let attempt = LoginAttempt {
+        member: format!("{}-{}", now_ms, uuid::Uuid::new_v4().simple()),
+        scopes: scopes(email, ip),
+    };
+
+    let mut retry_after = None;
+    for (limits, id) in &attempt.scopes {
+        let failures_key = failures_key(limits, id);
+        // MULTI, so the count and last failure are read right before adding this attempt
+        let (locked_for, failures, last_failure): (i64, u64, Vec<(String, f64)>) = redis::pipe()
+            .atomic()
+            .ttl(lockout_key(limits, id))
+            .zrembyscore(&failures_key, "-inf", window_start_ms(config, now_ms))
+            .ignore()
+            .zcard(&failures_key)
+            .zrange_withscores(&failures_key, -1, -1)
+            .zadd(&failures_key, &attempt.member, now_ms)
+            .ignore()
+            .expire(&failures_key, config.login_failure_window_seconds as i64)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+
+        let last_failure_ms = last_failure.first().map(|(_, at)| *at as i64);
+        if let Some(wait) = wait_seconds(
+            config,
+            limits,
+            now_ms,
+            locked_for,
+            failures,
+            last_failure_ms,
+        ) {
+            retry_after = Some(retry_after.unwrap_or(0).max(wait));
+        }
+    }
+
+    match retry_after {
+        Some(retry_after) => {
+            // turned away before the password, so it doesn't count
+            let mut pipe = redis::pipe();
+            for (limits, id) in &attempt.scopes {
+                pipe.zrem(failures_key(limits, id), &attempt.member)
+                    .ignore();
+            }
+            pipe.query_async::<()>(&mut conn).await?;
+            Ok(Reservation::RetryAfter(retry_after))
+        }
+        None => Ok(Reservation::Allowed(attempt)),
+    }
+}
+
+impl LoginAttempt {
+    // The password was wrong, the reservation stays as the failure. Returns true if this one
+    // locked the account, so the owner can be told; later failures while it's locked never
+    // get here.
+    pub(crate) async fn failed(
+        self,
+        redis_client: &RedisClient,
+        config: &AuthConfig,
+    ) -> Result<bool> {
+        let mut conn = redis_client.connection();
+
+        let mut account_locked = false;
+        for (limits, id) in &self.scopes {
+            let failures_key = failures_key(limits, id);
+            let failures: u64 = conn.zcard(&failures_key).await?;
+            if failures < limits.lockout_after {
+                continue;
+            }
+
+            // NX so only the failure that starts the lockout reports it. The count starts over
+            // once it ends
+            let locked: Option<String> = conn
+                .set_options(
+                    lockout_key(limits, id),
+                    "1",
+                    SetOptions::default()
+                        .conditional_set(ExistenceCheck::NX)
+                        .with_expiration(SetExpiry::EX(config.login_lockout_seconds)),
+                )
+                .await?;
+            conn.del::<_, ()>(&failures_key).await?;
+
+            if locked.is_some() {
+                println!(
+                    "[!] locked logins for {} {} after {} failures",
+                    limits.scope, id, failures
+                );
+                account_locked |= limits.scope == EMAIL_LIMITS.scope;
+            }
+        }
+        Ok(account_locked)
+    }
+
+    // The password was right: the email's failures are forgotten, the IP only loses this
+    // attempt, one valid account shouldn't reset its count
+    pub(crate) async fn succeeded(self, redis_client: &RedisClient) -> Result<()> {
+        let mut conn = redis_client.connection();
+        let mut pipe = redis::pipe();
+        for (limits, id) in &self.scopes {
+            let failures_key = failures_key(limits, id);
+            if limits.scope == EMAIL_LIMITS.scope {
+                pipe.del(failures_key).ignore();
+            } else {
+                pipe.zrem(failures_key, &self.member).ignore();
+            }
+        }
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+
+    // The password was right but the second factor is still to come. Only this attempt is
+    // taken back, the failures before it stay until the whole login succeeds, so the codes
+    // can't be guessed by logging in again each time the challenge runs out
+    pub(crate) async fn released(self, redis_client: &RedisClient) -> Result<()> {
+        let mut conn = redis_client.connection();
+        let mut pipe = redis::pipe();
+        for (limits, id) in &self.scopes {
+            pipe.zrem(failures_key(limits, id), &self.member).ignore();
+        }
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+}
+
+// when the account goes away
+pub(crate) async fn clear_login_failures(redis_client: &RedisClient, email: &str) -> Result<()> {
+    let mut conn = redis_client.connection();
+    conn.del::<_, ()>(failures_key(&EMAIL_LIMITS, &email_id(email)))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{test_config, test_redis, unique_email};
+
+    #[test]
+    fn delays_double_from_the_threshold_up_to_the_cap() {
+        let config = AuthConfig {
+            login_max_delay_seconds: 60,
+            ..Default::default()
+        };
+        let delays: Vec<_> = (0..=10)
+            .map(|failures| delay_seconds(&config, &EMAIL_LIMITS, failures))
+            .collect();
+        assert_eq!(delays, [0, 0, 0, 1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(delay_seconds(&config, &IP_LIMITS, 19), 0);
+        assert_eq!(delay_seconds(&config, &IP_LIMITS, 20), 1);
+        // no overflow however many there are
+        assert_eq!(delay_seconds(&config, &EMAIL_LIMITS, u64::MAX), 60);
+    }
+
+    #[test]
+    fn waits_for_the_lockout_or_the_delay_since_the_last_failure() {
+        let config = AuthConfig::default();
+        let now_ms = 1_000_000;
+        let wait = |locked_for, failures, last_failure_ms| {
+            wait_seconds(
+                &config,
+                &EMAIL_LIMITS,
+                now_ms,
+                locked_for,
+                failures,
+                last_failure_ms,
+            )
+        };
+
+        assert_eq!(wait(-2, 0, None), None);
+        assert_eq!(wait(-2, 2, Some(now_ms)), None);
+        // 4 failures wait 2s, rounded up
+        assert_eq!(wait(-2, 4, Some(now_ms - 500)), Some(2));
+        assert_eq!(wait(-2, 4, Some(now_ms - 2000)), None);
+        assert_eq!(wait(300, 0, None), Some(300));
+    }
+
+    #[actix_web::test]
+    async fn the_tenth_failure_locks_the_account() {
+        let redis_client = test_redis(&test_config().redis).await;
+        // without delays, only the lockout holds attempts back
+        let config = AuthConfig {
+            login_max_delay_seconds: 0,
+            ..Default::default()
+        };
+        let email = unique_email();
+
+        for failure in 1..=EMAIL_LIMITS.lockout_after {
+            let Reservation::Allowed(attempt) =
+                reserve_login_attempt(&redis_client, &config, &email, None)
+                    .await
+                    .unwrap()
+            else {
+                panic!("failure {} was turned away", failure);
+            };
+            let locked = attempt.failed(&redis_client, &config).await.unwrap();
+            assert_eq!(locked, failure == EMAIL_LIMITS.lockout_after);
+        }
+
+        // the same address in another case is the same account
+        let reservation =
+            reserve_login_attempt(&redis_client, &config, &email.to_uppercase(), None)
+                .await
+                .unwrap();
+        match reservation {
+            Reservation::RetryAfter(retry_after) => {
+                assert!(retry_after > config.login_lockout_seconds - 5)
+            }
+            Reservation::Allowed(_) => panic!("a locked account was let through"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn a_success_forgets_the_failures() {
+        let redis_client = test_redis(&test_config().redis).await;
+        let config = AuthConfig {
+            login_max_delay_seconds: 0,
+            ..Default::default()
+        };
+        let email = unique_email();
+
+        for _ in 0..EMAIL_LIMITS.lockout_after - 1 {
+            let Reservation::Allowed(attempt) =
+                reserve_login_attempt(&redis_client, &config, &email, None)
+                    .await
+                    .unwrap()
+            else {
+                panic!("turned away before the lockout");
+            };
+            attempt.failed(&redis_client, &config).await.unwrap();
+        }
+        let Reservation::Allowed(attempt) =
+            reserve_login_attempt(&redis_client, &config, &email, None)
+                .await
+                .unwrap()
+        else {
+            panic!("turned away before the lockout");
+        };
+        attempt.succeeded(&redis_client).await.unwrap();
+
+        // so the next failure is the first one again
+        let Reservation::Allowed(attempt) =
+            reserve_login_attempt(&redis_client, &config, &email, None)
+                .await
+                .unwrap()
+        else {
+            panic!("turned away after a success");
+        };
+        assert!(!attempt.failed(&redis_client, &config).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn parallel_attempts_count_against_each_other() {
+        let redis_client = test_redis(&test_config().redis).await;
+        let config = AuthConfig::default();
+        let email = unique_email();
+
+        // all checked before any of them could have failed, only those below the delay get in
+        let reservations = futures_util::future::join_all(
+            (0..10).map(|_| reserve_login_attempt(&redis_client, &config, &email, None)),
+        )
+        .await;
+        let allowed = reservations
+            .into_iter()
+            .filter(|reservation| matches!(reservation, Ok(Reservation::Allowed(_))))
+            .count();
+        assert_eq!(allowed as u64, EMAIL_LIMITS.delay_after);
+    }
+}
@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+
+use super::{Email, Mailer};
+
+// Appends every mail to mail.file, or prints it when that isn't set. Nothing leaves the
+// machine, so flows like registration can be run locally without a mail server.
+pub(crate) struct FileMailer {
+    path: Option<PathBuf>,
+    // keeps concurrent mails from interleaving in the file
+    lock: Mutex<()>,
+}
+
+impl FileMailer {
+    pub(crate) fn new(path: Option<String>) -> Self {
+        Self {
+            path: path.map(PathBuf::from),
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        let rendered = format!(
+            "To: {}\nSubject: {}\n\n{}\n---\n",
+            email.to, email.subject, email.body
+        );
+
+        let Some(path) = &self.path else {
+            println!("[+] mail\n{}", rendered);
+            return Ok(());
+        };
+
+        let _guard = self.lock.lock().await;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(rendered.as_bytes()).await?;
+        // tokio writes in the background, make sure the mail is in the file before we return
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn appends_every_mail_to_the_file() {
+        let path = std::env::temp_dir().join(format!("mails-{}.txt", uuid::Uuid::new_v4()));
+        let mailer = FileMailer::new(Some(path.to_string_lossy().to_string()));
+        for subject in ["first", "second"] {
+            mailer
+                .send(&Email {
+                    to: "ada@example.com".to_string(),
+                    subject: subject.to_string(),
+                    body: "hello".to_string(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            written,
+            "To: ada@example.com\nSubject: first\n\nhello\n---\nTo: ada@example.com\nSubject: second\n\nhello\n---\n"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+
+use super::config::{MailConfig, MailerKind};
+
+pub mod file;
+pub mod smtp;
+
+// plain text only for now
+#[derive(Debug, Clone)]
+pub(crate) struct Email {
+    pub(crate) to: String,
+    pub(crate) subject: String,
+    pub(crate) body: String,
+}
+
+// Everything that sends mail goes through this, the implementation is picked from mail.mailer at startup
+#[async_trait]
+pub(crate) trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<()>;
+}
+
+pub(crate) fn mailer_from_config(config: &MailConfig) -> Result<Arc<dyn Mailer>> {
+    match config.mailer {
+        MailerKind::Smtp => {
+            let host = config
+                .smtp_host
+                .as_deref()
+                .ok_or_else(|| anyhow!("mail.smtp_host must be set for the smtp mailer"))?;
+            let credentials = config
+                .smtp_username
+                .clone()
+                .zip(config.smtp_password.clone());
+            Ok(Arc::new(smtp::SmtpMailer::new(
+                host,
+                config.smtp_port,
+                credentials,
+                &config.from,
+            )?))
+        }
+        MailerKind::Log => Ok(Arc::new(file::FileMailer::new(config.file.clone()))),
+    }
+}
+
+// Sends outside the request, failures only get logged. Keeps slow SMTP servers out of response
+// times, and forgot-password takes equally long whether or not the account exists.
+pub(crate) fn send_in_background(mailer: Arc<dyn Mailer>, email: Email) {
+    actix_web::rt::spawn(async move {
+        if let Err(err) = mailer.send(&email).await {
+            println!(
+                "[-] failed to send \"{}\" to {}: {}",
+                email.subject, email.to, err
+            );
+        }
+    });
+}
+
+fn link_or_token(config: &MailConfig, path: &str, token: &str) -> String {
+    match config.app_url.as_deref() {
+        Some(app_url) => format!("{}{}?token={}", app_url, path, token),
+        None => format!("Your code: {}", token),
+    }
+}
+
+pub(crate) fn verification_email(config: &MailConfig, to: &str, name: &str, token: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Confirm your email address".to_string(),
+        body: format!(
+            "Hi {},\n\nwelcome to Artizans! Please confirm your email address:\n\n{}\n\nIf you didn't sign up, you can ignore this email.\n",
+            name,
+            link_or_token(config, "/verify-email", token)
+        ),
+    }
+}
+
+pub(crate) fn email_change_email(config: &MailConfig, to: &str, name: &str, token: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Confirm your new email address".to_string(),
+        body: format!(
+            "Hi {},\n\nplease confirm that you want to use this address for your Artizans account:\n\n{}\n\nIf you didn't ask for this, you can ignore this email.\n",
+            name,
+            link_or_token(config, "/confirm-email", token)
+        ),
+    }
+}
+
+pub(crate) fn password_reset_email(
+    config: &MailConfig,
+    to: &str,
+    name: &str,
+    token: &str,
+) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Hi {},\n\nsomeone asked to reset the password of your Artizans account. If that was you:\n\n{}\n\nOtherwise you can ignore this email, your password stays the same.\n",
+            name,
+            link_or_token(config, "/reset-password", token)
+        ),
+    }
+}
+
+pub(crate) fn account_locked_email(to: &str, name: &str, minutes: u64) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Sign ins to your account were paused".to_string(),
+        body: format!(
+            "Hi {},\n\nthere were several failed attempts to sign in to your Artizans account, so we paused sign ins for {} minutes.\n\nIf that was you, try again later or reset your password. If it wasn't, someone may be guessing your password, consider changing it to one you don't use anywhere else.\n",
+            name, minutes
+        ),
+    }
+}
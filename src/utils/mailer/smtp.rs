@@ -0,0 +1,54 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+use super::{Email, Mailer};
+
+pub(crate) struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub(crate) fn new(
+        host: &str,
+        port: u16,
+        credentials: Option<(String, String)>,
+        from: &str,
+    ) -> Result<Self> {
+        // 465 is TLS from the first byte, anything else upgrades with STARTTLS
+        let mut builder = if port == 465 {
+            AsyncSmtpTransport::<Tokio1Executor>::relay(host)?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+        }
+        .port(port);
+
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: from.parse()?,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse()?)
+            .subject(&email.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body.clone())?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,487 @@
+use anyhow::{anyhow, Result};
+use chrono::Duration;
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use sha256::digest;
+
+use crate::RedisClient;
+
+use super::{
+    config::AuthConfig,
+    one_time_token::{consume_token, issue_token, peek_token},
+    password::constant_time_eq,
+    user::{User, UserRepository},
+};
+
+// TOTP as in RFC 6238 with the parameters every authenticator app defaults to:
+// HMAC-SHA1, 6 digits, 30 second steps
+const TOTP_STEP_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+// codes from the step before and after still count, for clock drift and slow typing
+const TOTP_SKEW_STEPS: i64 = 1;
+const TOTP_SECRET_BYTES: usize = 20;
+const TOTP_ISSUER: &str = "Artizans";
+
+const RECOVERY_CODE_COUNT: usize = 10;
+// base32 characters, 50 bits each
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+const MFA_CHALLENGE_PURPOSE: &str = "mfa_challenge";
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer
+            .iter()
+            .fold(0u64, |bits, byte| bits << 8 | *byte as u64);
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            encoded.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut bits = 0u64;
+    let mut bit_count = 0;
+    for c in encoded.trim_end_matches('=').chars() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a as char == c.to_ascii_uppercase())?;
+        bits = bits << 5 | value as u64;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            bytes.push((bits >> bit_count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| anyhow!("Failed to generate random bytes"))?;
+    Ok(bytes)
+}
+
+// base32, the form authenticator apps take
+pub(crate) fn generate_totp_secret() -> Result<String> {
+    Ok(base32_encode(&random_bytes(TOTP_SECRET_BYTES)?))
+}
+
+// what authenticator apps read from the QR code
+pub(crate) fn otpauth_uri(secret: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        TOTP_ISSUER,
+        percent_encode(account),
+        secret,
+        TOTP_ISSUER,
+        TOTP_DIGITS,
+        TOTP_STEP_SECONDS
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn totp_at(key: &hmac::Key, step: i64) -> String {
+    let tag = hmac::sign(key, &step.to_be_bytes());
+    let hash = tag.as_ref();
+    // dynamic truncation, RFC 4226 section 5.3
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]) % 10u32.pow(TOTP_DIGITS);
+    format!("{:0width$}", code, width = TOTP_DIGITS as usize)
+}
+
+// the time step `code` belongs to, None if it's wrong
+fn matching_step(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize {
+        return None;
+    }
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &base32_decode(secret)?);
+    let current = now / TOTP_STEP_SECONDS;
+    (current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS)
+        .find(|step| constant_time_eq(totp_at(&key, *step).as_bytes(), code.as_bytes()))
+}
+
+// Checks a code from the user's authenticator. Each code is accepted once, so one read over
+// someone's shoulder can't be replayed
+pub(crate) async fn verify_totp_code(
+    redis_client: &RedisClient,
+    user_id: &str,
+    secret: &str,
+    code: &str,
+) -> Result<bool> {
+    let Some(step) = matching_step(secret, code, chrono::Utc::now().timestamp()) else {
+        return Ok(false);
+    };
+    let mut conn = redis_client.connection();
+    let first_use: Option<String> = conn
+        .set_options(
+            format!("totp_used:{}:{}", user_id, step),
+            "1",
+            SetOptions::default()
+                .conditional_set(ExistenceCheck::NX)
+                .with_expiration(SetExpiry::EX(
+                    (TOTP_STEP_SECONDS * (2 * TOTP_SKEW_STEPS + 1)) as u64,
+                )),
+        )
+        .await?;
+    Ok(first_use.is_some())
+}
+
+// Returns the codes to show the user once, and the hashes to store. They're random enough
+// that sha256 is plenty
+pub(crate) fn generate_recovery_codes() -> Result<(Vec<String>, Vec<String>)> {
+    let mut codes = Vec::new();
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let code = base32_encode(&random_bytes(RECOVERY_CODE_LENGTH * 5 / 8)?).to_lowercase();
+        let (first, second) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+        codes.push(format!("{}-{}", first, second));
+    }
+    let hashes = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    Ok((codes, hashes))
+}
+
+// dashes, spaces and case don't matter when typing a code in
+pub(crate) fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    digest(normalized)
+}
+
+fn enrollment_key(user_id: &str) -> String {
+    format!("totp_enrollment:{}", user_id)
+}
+
+// The secret waits here until the user proves their app has it, replacing any earlier attempt
+pub(crate) async fn store_pending_enrollment(
+    redis_client: &RedisClient,
+    config: &AuthConfig,
+    user_id: &str,
+    secret: &str,
+) -> Result<()> {
+    let mut conn = redis_client.connection();
+    conn.set_ex::<_, _, ()>(
+        enrollment_key(user_id),
+        secret,
+        Duration::minutes(config.totp_enrollment_minutes).num_seconds() as u64,
+    )
+    .await?;
+    Ok(())
+}
+
+pub(crate) async fn pending_enrollment(
+    redis_client: &RedisClient,
+    user_id: &str,
+) -> Result<Option<String>> {
+    let mut conn = redis_client.connection();
+    Ok(conn.get(enrollment_key(user_id)).await?)
+}
+
+pub(crate) async fn clear_pending_enrollment(
+    redis_client: &RedisClient,
+    user_id: &str,
+) -> Result<()> {
+    let mut conn = redis_client.connection();
+    conn.del::<_, ()>(enrollment_key(user_id)).await?;
+    Ok(())
+}
+
+// What a password login that still needs the second factor hands back, see
+// auth_handlers::login_mfa
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MfaChallenge {
+    pub(crate) user_id: String,
+    // a password change or logout-all in between voids the challenge
+    pub(crate) token_version: u64,
+    pub(crate) device: Option<String>,
+}
+
+pub(crate) async fn issue_mfa_challenge(
+    redis_client: &RedisClient,
+    config: &AuthConfig,
+    challenge: &MfaChallenge,
+) -> Result<String> {
+    issue_token(
+        redis_client,
+        MFA_CHALLENGE_PURPOSE,
+        challenge,
+        Duration::minutes(config.mfa_challenge_minutes),
+    )
+    .await
+}
+
+// stays valid through wrong codes, until reserve_mfa_guess runs out
+pub(crate) async fn get_mfa_challenge(
+    redis_client: &RedisClient,
+    token: &str,
+) -> Result<Option<MfaChallenge>> {
+    peek_token(redis_client, MFA_CHALLENGE_PURPOSE, token).await
+}
+
+// after the right code. None if another request completed it first
+pub(crate) async fn complete_mfa_challenge(
+    redis_client: &RedisClient,
+    token: &str,
+) -> Result<Option<MfaChallenge>> {
+    consume_token(redis_client, MFA_CHALLENGE_PURPOSE, token).await
+}
+
+// Counts a guess at the challenge before the code is checked, so parallel guesses can't all get
+// in under the limit. False once it used up auth.mfa_challenge_attempts, the challenge is
+// dropped then and the user has to log in again
+pub(crate) async fn reserve_mfa_guess(
+    redis_client: &RedisClient,
+    config: &AuthConfig,
+    token: &str,
+) -> Result<bool> {
+    let key = format!("mfa_challenge_guesses:{}", digest(token));
+    let mut conn = redis_client.connection();
+    let (guesses,): (u64,) = redis::pipe()
+        .atomic()
+        .incr(&key, 1)
+        .expire(
+            &key,
+            Duration::minutes(config.mfa_challenge_minutes).num_seconds(),
+        )
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+    if guesses > config.mfa_challenge_attempts {
+        complete_mfa_challenge(redis_client, token).await?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+// the second factor a request brings, exactly one of the two
+pub(crate) enum SecondFactor<'a> {
+    Totp(&'a str),
+    RecoveryCode(&'a str),
+}
+
+impl<'a> SecondFactor<'a> {
+    pub(crate) fn from_fields(
+        code: Option<&'a str>,
+        recovery_code: Option<&'a str>,
+    ) -> Option<Self> {
+        match (code, recovery_code) {
+            (Some(code), None) => Some(SecondFactor::Totp(code)),
+            (None, Some(recovery_code)) => Some(SecondFactor::RecoveryCode(recovery_code)),
+            _ => None,
+        }
+    }
+}
+
+// false for a wrong or reused code, and for users without two-factor
+pub(crate) async fn verify_second_factor(
+    redis_client: &RedisClient,
+    user_repository: &dyn UserRepository,
+    user: &User,
+    factor: &SecondFactor<'_>,
+) -> Result<bool> {
+    let Some(secret) = &user.totp_secret else {
+        return Ok(false);
+    };
+    match factor {
+        SecondFactor::Totp(code) => verify_totp_code(redis_client, &user.id, secret, code).await,
+        SecondFactor::RecoveryCode(code) => {
+            let used = user_repository
+                .use_recovery_code(&user.id, &hash_recovery_code(code))
+                .await?;
+            if used {
+                println!("[!] {} signed in with a recovery code", user.id);
+            }
+            Ok(used)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{test_config, test_redis};
+    use crate::utils::user::InMemoryUserRepository;
+
+    // "12345678901234567890", the SHA-1 seed of RFC 6238 appendix B
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn code_at(secret: &str, time: i64) -> String {
+        let key = hmac::Key::new(
+            hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            &base32_decode(secret).unwrap(),
+        );
+        totp_at(&key, time / TOTP_STEP_SECONDS)
+    }
+
+    #[test]
+    fn base32_matches_rfc_4648() {
+        for (plain, encoded) in [
+            ("", ""),
+            ("f", "MY======"),
+            ("fo", "MZXQ===="),
+            ("foo", "MZXW6==="),
+            ("foob", "MZXW6YQ="),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI======"),
+        ] {
+            // we never write padding, but accept it
+            assert_eq!(
+                base32_encode(plain.as_bytes()),
+                encoded.trim_end_matches('=')
+            );
+            assert_eq!(base32_decode(encoded).unwrap(), plain.as_bytes());
+            assert_eq!(
+                base32_decode(&encoded.trim_end_matches('=').to_lowercase()).unwrap(),
+                plain.as_bytes()
+            );
+        }
+        assert_eq!(base32_encode(b"12345678901234567890"), RFC_SECRET);
+        assert!(base32_decode("MZ1W").is_none());
+    }
+
+    #[test]
+    fn totp_matches_rfc_6238_sha1_vectors() {
+        // the RFC lists 8 digit codes, ours are their last 6
+        for (time, code) in [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ] {
+            assert_eq!(code_at(RFC_SECRET, time), code[2..], "at {}", time);
+        }
+    }
+
+    #[test]
+    fn codes_match_one_step_either_way() {
+        let now = 1111111111;
+        let step = now / TOTP_STEP_SECONDS;
+        for offset in -TOTP_SKEW_STEPS..=TOTP_SKEW_STEPS {
+            let time = now + offset * TOTP_STEP_SECONDS;
+            let code = code_at(RFC_SECRET, time);
+            assert_eq!(matching_step(RFC_SECRET, &code, now), Some(step + offset));
+        }
+        for offset in [-2, 2] {
+            let code = code_at(RFC_SECRET, now + offset * TOTP_STEP_SECONDS);
+            assert_eq!(matching_step(RFC_SECRET, &code, now), None);
+        }
+    }
+
+    #[test]
+    fn codes_are_trimmed_and_checked_for_length() {
+        let now = 1234567890;
+        let code = code_at(RFC_SECRET, now);
+        assert!(matching_step(RFC_SECRET, &format!(" {}\n", code), now).is_some());
+        assert!(matching_step(RFC_SECRET, &code[1..], now).is_none());
+        assert!(matching_step(RFC_SECRET, &format!("{}0", code), now).is_none());
+        assert!(matching_step("not base32!", &code, now).is_none());
+    }
+
+    #[actix_web::test]
+    async fn totp_codes_work_once() {
+        let redis_client = test_redis(&test_config().redis).await;
+        let user_id = uuid::Uuid::new_v4().to_string();
+        let secret = generate_totp_secret().unwrap();
+        let code = code_at(&secret, chrono::Utc::now().timestamp());
+
+        assert!(verify_totp_code(&redis_client, &user_id, &secret, &code)
+            .await
+            .unwrap());
+        assert!(!verify_totp_code(&redis_client, &user_id, &secret, &code)
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn secrets_are_20_bytes_of_base32() {
+        let secret = generate_totp_secret().unwrap();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(base32_decode(&secret).unwrap().len(), TOTP_SECRET_BYTES);
+    }
+
+    #[actix_web::test]
+    async fn recovery_codes_work_once() {
+        let repository = InMemoryUserRepository::default();
+        let user = User {
+            id: "USER#mfa".to_string(),
+            name: "Test".to_string(),
+            email: "mfa@example.com".to_string(),
+            password: String::new(),
+            verified: true,
+            token_version: 0,
+            role: Default::default(),
+            disabled: false,
+            identities: Vec::new(),
+            totp_secret: None,
+            recovery_codes: Vec::new(),
+            bio: None,
+            avatar_url: None,
+            pending_email: None,
+        };
+        repository.create_user(&user).await.unwrap();
+
+        let (codes, hashes) = generate_recovery_codes().unwrap();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        repository
+            .enable_totp(&user.id, RFC_SECRET, &hashes)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // typed with another case and without the dash
+        let typed = codes[0].replace('-', " ").to_uppercase();
+        assert!(repository
+            .use_recovery_code(&user.id, &hash_recovery_code(&typed))
+            .await
+            .unwrap());
+        assert!(!repository
+            .use_recovery_code(&user.id, &hash_recovery_code(&codes[0]))
+            .await
+            .unwrap());
+        // the others are untouched
+        assert!(repository
+            .use_recovery_code(&user.id, &hash_recovery_code(&codes[1]))
+            .await
+            .unwrap());
+        assert!(!repository
+            .use_recovery_code(&user.id, &hash_recovery_code("aaaaa-bbbbb"))
+            .await
+            .unwrap());
+    }
+}
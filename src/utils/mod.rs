@@ -1,6 +1,23 @@
+pub mod api_key;
 pub mod api_response;
 pub mod app_state;
-pub mod environment_variables;
-pub mod global_variables;
+pub mod config;
+pub mod entitlement;
 pub mod jwt;
+pub mod jwt_keys;
+pub mod llm;
+pub mod login_attempts;
+pub mod mailer;
+pub mod mfa;
+pub mod oidc;
+pub mod one_time_token;
+pub mod password;
+pub mod principal;
+pub mod quota;
+pub mod redis_client;
+pub mod refresh_token;
+pub mod session;
+pub mod shutdown;
+pub mod throttle;
+pub mod token_version;
 pub mod user;
@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::{Deserialize, Deserializer};
+use sha256::digest;
+use tokio::sync::RwLock;
+
+use super::{config::OidcConfig, jwt_keys::TokenError};
+
+// identity providers users can sign in with instead of a password
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Provider {
+    Apple,
+    Google,
+}
+
+impl Provider {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "apple" => Some(Provider::Apple),
+            "google" => Some(Provider::Google),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Provider::Apple => "apple",
+            Provider::Google => "google",
+        }
+    }
+
+    fn issuers(&self) -> &'static [&'static str] {
+        match self {
+            Provider::Apple => &["https://appleid.apple.com"],
+            // Google uses both forms
+            Provider::Google => &["https://accounts.google.com", "accounts.google.com"],
+        }
+    }
+}
+
+// who the provider says signed in
+pub(crate) struct Identity {
+    pub(crate) provider: Provider,
+    // stable per user and provider, unlike the email
+    pub(crate) subject: String,
+    pub(crate) email: Option<String>,
+    pub(crate) email_verified: bool,
+    pub(crate) name: Option<String>,
+}
+
+pub(crate) enum OidcError {
+    NotConfigured,
+    InvalidToken(TokenError),
+    // the provider's keys couldn't be loaded, not the client's fault
+    KeysUnavailable(anyhow::Error),
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    email: Option<String>,
+    #[serde(default, deserialize_with = "bool_or_string")]
+    email_verified: bool,
+    name: Option<String>,
+    nonce: Option<String>,
+}
+
+// Apple sends email_verified as "true"/"false"
+fn bool_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrString {
+        Bool(bool),
+        String(String),
+    }
+    Ok(match BoolOrString::deserialize(deserializer)? {
+        BoolOrString::Bool(value) => value,
+        BoolOrString::String(value) => value == "true",
+    })
+}
+
+struct CachedKeys {
+    keys: JwkSet,
+    fetched_at: Instant,
+}
+
+struct ProviderKeys {
+    // accepted `aud` values
+    client_ids: Vec<String>,
+    // https url, or a JWKS file for local runs and tests
+    jwks_source: String,
+    cache: RwLock<Option<CachedKeys>>,
+}
+
+// Verifies ID tokens from the providers that have client ids configured, with their keys
+// cached in memory
+pub(crate) struct OidcVerifier {
+    http: reqwest::Client,
+    providers: HashMap<Provider, ProviderKeys>,
+    cache_for: Duration,
+    refetch_after: Duration,
+}
+
+impl OidcVerifier {
+    pub(crate) fn from_config(config: &OidcConfig) -> Result<Self> {
+        let mut providers = HashMap::new();
+        for (provider, client_ids, jwks_source) in [
+            (
+                Provider::Google,
+                &config.google_client_ids,
+                &config.google_jwks,
+            ),
+            (
+                Provider::Apple,
+                &config.apple_client_ids,
+                &config.apple_jwks,
+            ),
+        ] {
+            if client_ids.is_empty() {
+                continue;
+            }
+            providers.insert(
+                provider,
+                ProviderKeys {
+                    client_ids: client_ids.clone(),
+                    jwks_source: jwks_source.clone(),
+                    cache: RwLock::new(None),
+                },
+            );
+        }
+
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(OidcVerifier {
+            http,
+            providers,
+            cache_for: Duration::from_secs(config.jwks_cache_seconds),
+            refetch_after: Duration::from_secs(config.jwks_refetch_seconds),
+        })
+    }
+
+    pub(crate) fn enabled_providers(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.providers.keys().map(Provider::as_str).collect();
+        names.sort();
+        names
+    }
+
+    // `nonce` is what the app generated for this sign in. Apple gets its sha256, Google the
+    // value itself, so either is accepted. Tokens carrying a nonce need it.
+    pub(crate) async fn verify(
+        &self,
+        provider: Provider,
+        id_token: &str,
+        nonce: Option<&str>,
+    ) -> Result<Identity, OidcError> {
+        let keys = self
+            .providers
+            .get(&provider)
+            .ok_or(OidcError::NotConfigured)?;
+
+        let header = decode_header(id_token).map_err(|err| OidcError::InvalidToken(err.into()))?;
+        // both sign with RS256, so anything else is forged
+        if header.alg != Algorithm::RS256 {
+            return Err(OidcError::InvalidToken(TokenError::InvalidSignature));
+        }
+        let kid = header
+            .kid
+            .ok_or(OidcError::InvalidToken(TokenError::Malformed))?;
+        let jwk = self
+            .find_key(provider, keys, &kid)
+            .await
+            .map_err(OidcError::KeysUnavailable)?
+            .ok_or(OidcError::InvalidToken(TokenError::InvalidSignature))?;
+        let decoding_key = DecodingKey::from_jwk(&jwk).map_err(|err| {
+            OidcError::KeysUnavailable(anyhow!("Bad {} key {}: {}", provider.as_str(), kid, err))
+        })?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_issuer(provider.issuers());
+        validation.set_audience(&keys.client_ids);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = decode::<IdTokenClaims>(id_token, &decoding_key, &validation)
+            .map_err(|err| OidcError::InvalidToken(err.into()))?
+            .claims;
+
+        match (nonce, &claims.nonce) {
+            (None, None) => {}
+            (Some(nonce), Some(expected)) if *expected == nonce || *expected == digest(nonce) => {}
+            _ => return Err(OidcError::InvalidToken(TokenError::InvalidClaims)),
+        }
+
+        Ok(Identity {
+            provider,
+            subject: claims.sub,
+            email: claims.email,
+            email_verified: claims.email_verified,
+            name: claims.name,
+        })
+    }
+
+    // An unknown kid is usually a key published after our last fetch, so it refetches early,
+    // at most every oidc.jwks_refetch_seconds
+    async fn find_key(
+        &self,
+        provider: Provider,
+        keys: &ProviderKeys,
+        kid: &str,
+    ) -> Result<Option<Jwk>> {
+        let (cache_for, refetch_after) = (self.cache_for, self.refetch_after);
+
+        if let Some(cached) = keys.cache.read().await.as_ref() {
+            let age = cached.fetched_at.elapsed();
+            let key = cached.keys.find(kid);
+            if age < cache_for && (key.is_some() || age < refetch_after) {
+                return Ok(key.cloned());
+            }
+        }
+
+        let mut cache = keys.cache.write().await;
+        // someone else may have fetched while we waited for the lock
+        if let Some(cached) = cache.as_ref() {
+            if cached.fetched_at.elapsed() < refetch_after {
+                return Ok(cached.keys.find(kid).cloned());
+            }
+        }
+
+        match self.fetch_keys(&keys.jwks_source).await {
+            Ok(fetched) => {
+                let key = fetched.find(kid).cloned();
+                *cache = Some(CachedKeys {
+                    keys: fetched,
+                    fetched_at: Instant::now(),
+                });
+                Ok(key)
+            }
+            // keys stay valid for a while after rotation, old ones beat failing every sign in
+            Err(err) => match cache.as_ref() {
+                Some(cached) => {
+                    println!(
+                        "[-] failed to refresh {} keys, using cached ones: {}",
+                        provider.as_str(),
+                        err
+                    );
+                    Ok(cached.keys.find(kid).cloned())
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    async fn fetch_keys(&self, source: &str) -> Result<JwkSet> {
+        let body = if source.starts_with("https://") || source.starts_with("http://") {
+            self.http
+                .get(source)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?
+        } else {
+            tokio::fs::read_to_string(source)
+                .await
+                .with_context(|| format!("Failed to read {}", source))?
+        };
+        serde_json::from_str(&body).with_context(|| format!("{} is not a JWKS", source))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::Header;
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::{id_token, test_oidc_config, OIDC_KEY, OIDC_OTHER_KEY};
+
+    fn verifier() -> OidcVerifier {
+        OidcVerifier::from_config(&test_oidc_config()).unwrap()
+    }
+
+    // why verify turned the token down
+    async fn rejection(provider: Provider, id_token: &str, nonce: Option<&str>) -> TokenError {
+        match verifier().verify(provider, id_token, nonce).await {
+            Ok(identity) => panic!("accepted a token for {}", identity.subject),
+            Err(OidcError::InvalidToken(err)) => err,
+            Err(OidcError::NotConfigured) => panic!("{} isn't configured", provider.as_str()),
+            Err(OidcError::KeysUnavailable(err)) => panic!("no keys: {}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_tokens_signed_with_a_published_key() {
+        let token = id_token(OIDC_KEY, "g-1", "ada@example.com", json!({ "name": "Ada" }));
+        let identity = verifier()
+            .verify(Provider::Google, &token, None)
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(identity.provider, Provider::Google);
+        assert_eq!(identity.subject, "g-1");
+        assert_eq!(identity.email.as_deref(), Some("ada@example.com"));
+        assert!(identity.email_verified);
+        assert_eq!(identity.name.as_deref(), Some("Ada"));
+    }
+
+    #[tokio::test]
+    async fn rejects_tokens_signed_with_other_keys() {
+        let forged = id_token(OIDC_OTHER_KEY, "g-1", "ada@example.com", json!({}));
+        assert_eq!(
+            rejection(Provider::Google, &forged, None).await,
+            TokenError::InvalidSignature
+        );
+
+        // a kid the JWKS doesn't have
+        let token = id_token(OIDC_KEY, "g-1", "ada@example.com", json!({}));
+        let (header, rest) = token.split_once('.').unwrap();
+        let mut unknown: Header =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).unwrap()).unwrap();
+        unknown.kid = Some("rotated-away".to_string());
+        let header = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&unknown).unwrap());
+        assert_eq!(
+            rejection(Provider::Google, &format!("{}.{}", header, rest), None).await,
+            TokenError::InvalidSignature
+        );
+    }
+
+    #[tokio::test]
+    async fn checks_issuer_audience_and_expiry() {
+        let now = chrono::Utc::now().timestamp();
+        let cases = [
+            (
+                json!({ "iss": "https://evil.example.com" }),
+                TokenError::InvalidClaims,
+            ),
+            (
+                json!({ "aud": "com.someone.else" }),
+                TokenError::InvalidClaims,
+            ),
+            // past the 60s leeway
+            (json!({ "exp": now - 120 }), TokenError::Expired),
+        ];
+        for (overrides, expected) in cases {
+            let token = id_token(OIDC_KEY, "g-1", "ada@example.com", overrides.clone());
+            assert_eq!(
+                rejection(Provider::Google, &token, None).await,
+                expected,
+                "{}",
+                overrides
+            );
+        }
+
+        // Google's issuer is no good for Apple, and the other way around
+        let token = id_token(OIDC_KEY, "g-1", "ada@example.com", json!({}));
+        assert_eq!(
+            rejection(Provider::Apple, &token, None).await,
+            TokenError::InvalidClaims
+        );
+        let token = id_token(
+            OIDC_KEY,
+            "a-1",
+            "ada@example.com",
+            json!({ "iss": "https://appleid.apple.com" }),
+        );
+        assert!(verifier()
+            .verify(Provider::Apple, &token, None)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn reads_unverified_emails_in_both_forms() {
+        for email_verified in [json!(false), json!("false")] {
+            let token = id_token(
+                OIDC_KEY,
+                "a-1",
+                "ada@example.com",
+                json!({ "iss": "https://appleid.apple.com", "email_verified": email_verified }),
+            );
+            let identity = verifier()
+                .verify(Provider::Apple, &token, None)
+                .await
+                .ok()
+                .unwrap();
+            assert!(!identity.email_verified);
+        }
+        let token = id_token(
+            OIDC_KEY,
+            "a-1",
+            "ada@example.com",
+            json!({ "iss": "https://appleid.apple.com", "email_verified": "true" }),
+        );
+        let identity = verifier()
+            .verify(Provider::Apple, &token, None)
+            .await
+            .ok()
+            .unwrap();
+        assert!(identity.email_verified);
+    }
+
+    #[tokio::test]
+    async fn tokens_with_a_nonce_need_it() {
+        let token = id_token(
+            OIDC_KEY,
+            "a-1",
+            "ada@example.com",
+            json!({ "nonce": digest("app-nonce") }),
+        );
+        assert!(verifier()
+            .verify(Provider::Google, &token, Some("app-nonce"))
+            .await
+            .is_ok());
+        assert_eq!(
+            rejection(Provider::Google, &token, None).await,
+            TokenError::InvalidClaims
+        );
+        assert_eq!(
+            rejection(Provider::Google, &token, Some("other-nonce")).await,
+            TokenError::InvalidClaims
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::Result;
+use chrono::Duration;
+use redis::AsyncCommands;
+use serde::{de::DeserializeOwned, Serialize};
+use sha256::digest;
+
+use crate::RedisClient;
+
+use super::refresh_token::generate_token;
+
+// Tokens we mail out (confirm an email change, ...). Redis only sees the sha256 of the
+// token, and the payload can be taken out exactly once.
+fn token_key(purpose: &str, token: &str) -> String {
+    format!("{}:{}", purpose, digest(token))
+}
+
+pub(crate) async fn issue_token(
+    redis_client: &RedisClient,
+    purpose: &str,
+    payload: &impl Serialize,
+    ttl: Duration,
+) -> Result<String> {
+    let token = generate_token();
+    let mut conn = redis_client.connection();
+    conn.set_ex::<_, _, ()>(
+        token_key(purpose, &token),
+        serde_json::to_string(payload)?,
+        ttl.num_seconds() as u64,
+    )
+    .await?;
+    Ok(token)
+}
+
+// reads the payload but leaves the token usable, None like consume_token
+pub(crate) async fn peek_token<T: DeserializeOwned>(
+    redis_client: &RedisClient,
+    purpose: &str,
+    token: &str,
+) -> Result<Option<T>> {
+    let mut conn = redis_client.connection();
+    let payload: Option<String> = conn.get(token_key(purpose, token)).await?;
+    payload
+        .map(|payload| serde_json::from_str(&payload))
+        .transpose()
+        .map_err(anyhow::Error::from)
+}
+
+// None for unknown, expired or already used tokens
+pub(crate) async fn consume_token<T: DeserializeOwned>(
+    redis_client: &RedisClient,
+    purpose: &str,
+    token: &str,
+) -> Result<Option<T>> {
+    let key = token_key(purpose, token);
+    let mut conn = redis_client.connection();
+    // GET and DEL in one transaction, so a token racing with itself is only accepted once
+    let (payload,): (Option<String>,) = redis::pipe()
+        .atomic()
+        .get(&key)
+        .del(&key)
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+
+    payload
+        .map(|payload| serde_json::from_str(&payload))
+        .transpose()
+        .map_err(anyhow::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{test_config, test_redis};
+
+    #[actix_web::test]
+    async fn tokens_are_taken_out_once() {
+        let redis_client = test_redis(&test_config().redis).await;
+        let token = issue_token(
+            &redis_client,
+            "test",
+            &"ada@example.com",
+            Duration::minutes(1),
+        )
+        .await
+        .unwrap();
+
+        // the purpose is part of the key, a token only works where it was meant for
+        let other: Option<String> = consume_token(&redis_client, "other", &token).await.unwrap();
+        assert_eq!(other, None);
+
+        let payload: Option<String> = consume_token(&redis_client, "test", &token).await.unwrap();
+        assert_eq!(payload.as_deref(), Some("ada@example.com"));
+        let payload: Option<String> = consume_token(&redis_client, "test", &token).await.unwrap();
+        assert_eq!(payload, None);
+    }
+}
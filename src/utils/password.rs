@@ -0,0 +1,56 @@
+use anyhow::Result;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use sha256::digest;
+
+pub(crate) enum PasswordCheck {
+    Valid,
+    // password matched a legacy unsalted sha256 hash and should be re-hashed
+    ValidLegacy,
+    Invalid,
+}
+
+// hashes password with argon2id and a random salt, returns PHC string like $argon2id$v=19$...
+pub(crate) fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| anyhow::anyhow!("Failed to hash password: {}", err))
+}
+
+pub(crate) fn verify_password(password: &str, stored_hash: &str) -> Result<PasswordCheck> {
+    if !stored_hash.starts_with('$') {
+        // users registered before argon2 have a plain sha256 hex digest stored
+        return Ok(
+            if constant_time_eq(digest(password).as_bytes(), stored_hash.as_bytes()) {
+                PasswordCheck::ValidLegacy
+            } else {
+                PasswordCheck::Invalid
+            },
+        );
+    }
+
+    let parsed = PasswordHash::new(stored_hash)
+        .map_err(|err| anyhow::anyhow!("Failed to parse password hash: {}", err))?;
+    match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+        Ok(()) => Ok(PasswordCheck::Valid),
+        Err(argon2::password_hash::Error::Password) => Ok(PasswordCheck::Invalid),
+        Err(err) => Err(anyhow::anyhow!("Failed to verify password: {}", err)),
+    }
+}
+
+// None if the password is acceptable, otherwise what's wrong with it
+pub(crate) fn password_problem(password: &str) -> Option<&'static str> {
+    match password.chars().count() {
+        0..=7 => Some("must be at least 8 characters"),
+        129.. => Some("must be at most 128 characters"),
+        _ => None,
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,95 @@
+use std::fmt::Display;
+use std::future;
+
+use actix_web::{FromRequest, HttpMessage};
+
+use super::{
+    api_key::ApiKeyScope,
+    api_response::{ApiResponse, ErrorCode},
+    jwt::Claims,
+    user::Role,
+};
+
+// Who sent a request, however they authenticated. check_auth_middleware puts one in every
+// request it lets through. Handlers on routes that take API keys use it, the others can use
+// Claims, which only requests with a token have.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub id: String,
+    // the current one for API keys, the one from when it was issued for tokens
+    pub role: Role,
+    pub method: AuthMethod,
+}
+
+#[derive(Debug, Clone)]
+pub enum AuthMethod {
+    // an access token, its Claims are in the request too
+    Token,
+    // keys aren't tied to a session, they work until they're revoked
+    ApiKey {
+        key_id: String,
+        scopes: Vec<ApiKeyScope>,
+    },
+}
+
+impl From<&Claims> for Principal {
+    fn from(claims: &Claims) -> Self {
+        Principal {
+            id: claims.id.clone(),
+            role: claims.role,
+            method: AuthMethod::Token,
+        }
+    }
+}
+
+impl Principal {
+    // tokens can do whatever the role allows, keys only what they were made for
+    pub fn has_scope(&self, scope: ApiKeyScope) -> bool {
+        match &self.method {
+            AuthMethod::Token => true,
+            AuthMethod::ApiKey { scopes, .. } => scopes.contains(&scope),
+        }
+    }
+
+    // for what a key mustn't do whatever its scopes, like making more keys
+    pub fn api_key_error(&self) -> Option<ApiResponse> {
+        match &self.method {
+            AuthMethod::Token => None,
+            AuthMethod::ApiKey { .. } => Some(ApiResponse::error(
+                403,
+                ErrorCode::ApiKeyNotAccepted,
+                "API keys can't do this, use a Bearer token",
+            )),
+        }
+    }
+}
+
+// for logs, says which key did it
+impl Display for Principal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.method {
+            AuthMethod::Token => write!(f, "{}", self.id),
+            AuthMethod::ApiKey { key_id, .. } => write!(f, "{} (API key {})", self.id, key_id),
+        }
+    }
+}
+
+impl FromRequest for Principal {
+    type Error = actix_web::Error;
+
+    type Future = future::Ready<Result<Self, Self::Error>>;
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> future::Ready<Result<Principal, actix_web::Error>> {
+        match req.extensions().get::<Principal>() {
+            Some(principal) => future::ready(Ok(principal.clone())),
+            None => future::ready(Err(actix_web::Error::from(ApiResponse::error(
+                401,
+                ErrorCode::MissingAuthorization,
+                "Unauthorized",
+            )))),
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::Result;
+use chrono::{DateTime, Days, Utc};
+use redis::AsyncCommands;
+
+use crate::RedisClient;
+
+use super::config::LlmConfig;
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct QuotaStatus {
+    pub(crate) requests_remaining: u64,
+    pub(crate) tokens_remaining: u64,
+    pub(crate) reset_at: DateTime<Utc>,
+}
+
+impl QuotaStatus {
+    pub(crate) fn seconds_until_reset(&self) -> i64 {
+        (self.reset_at - Utc::now()).num_seconds().max(0)
+    }
+}
+
+// what the user used so far today, for the data export
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct QuotaUsage {
+    pub(crate) day: String,
+    pub(crate) requests: u64,
+    pub(crate) tokens: u64,
+}
+
+pub(crate) enum QuotaCheck {
+    Allowed(QuotaSlot),
+    Exceeded(QuotaStatus),
+}
+
+// A request counted by reserve_request. Tokens are charged to the day it was counted on, so
+// a stream running past midnight settles against the counters it reserved from
+#[derive(Clone)]
+pub(crate) struct QuotaSlot {
+    user_id: String,
+    day: String,
+    reset_at: DateTime<Utc>,
+}
+
+// quotas are per UTC day, counters live until the next midnight
+fn current_window() -> (String, DateTime<Utc>) {
+    let now = Utc::now();
+    let reset_at = now
+        .date_naive()
+        .checked_add_days(Days::new(1))
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+    (now.format("%Y-%m-%d").to_string(), reset_at)
+}
+
+fn requests_key(user_id: &str, day: &str) -> String {
+    format!("quota:{}:{}:requests", user_id, day)
+}
+
+fn tokens_key(user_id: &str, day: &str) -> String {
+    format!("quota:{}:{}:tokens", user_id, day)
+}
+
+// Counts one LLM request against the user's daily quota. Record the tokens on the slot once
+// the generation is done, token usage is only known afterwards.
+pub(crate) async fn reserve_request(
+    redis_client: &RedisClient,
+    config: &LlmConfig,
+    user_id: &str,
+) -> Result<QuotaCheck> {
+    let (day, reset_at) = current_window();
+    let requests_key = requests_key(user_id, &day);
+    let mut conn = redis_client.connection();
+
+    let (requests_used, tokens_used): (u64, Option<u64>) = redis::pipe()
+        .atomic()
+        .incr(&requests_key, 1)
+        .expire_at(&requests_key, reset_at.timestamp())
+        .ignore()
+        .get(tokens_key(user_id, &day))
+        .query_async(&mut conn)
+        .await?;
+    let tokens_used = tokens_used.unwrap_or_default();
+
+    let status = QuotaStatus {
+        requests_remaining: config.daily_request_quota.saturating_sub(requests_used),
+        tokens_remaining: config.daily_token_quota.saturating_sub(tokens_used),
+        reset_at,
+    };
+
+    if requests_used > config.daily_request_quota || tokens_used >= config.daily_token_quota {
+        return Ok(QuotaCheck::Exceeded(status));
+    }
+    Ok(QuotaCheck::Allowed(QuotaSlot {
+        user_id: user_id.to_string(),
+        day,
+        reset_at,
+    }))
+}
+
+impl QuotaSlot {
+    // negative hands back part of a reservation. Once the day is over the EXPIREAT is in the
+    // past and drops the counter, which is gone by then anyway
+    pub(crate) async fn record_tokens(
+        &self,
+        redis_client: &RedisClient,
+        tokens: i64,
+    ) -> Result<()> {
+        let tokens_key = tokens_key(&self.user_id, &self.day);
+        let mut conn = redis_client.connection();
+        redis::pipe()
+            .atomic()
+            .incr(&tokens_key, tokens)
+            .ignore()
+            .expire_at(&tokens_key, self.reset_at.timestamp())
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    // the provider failed, so the request doesn't count
+    pub(crate) async fn refund(&self, redis_client: &RedisClient) -> Result<()> {
+        let requests_key = requests_key(&self.user_id, &self.day);
+        let mut conn = redis_client.connection();
+        redis::pipe()
+            .atomic()
+            .decr(&requests_key, 1)
+            .ignore()
+            .expire_at(&requests_key, self.reset_at.timestamp())
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+}
+
+pub(crate) async fn current_usage(redis_client: &RedisClient, user_id: &str) -> Result<QuotaUsage> {
+    let (day, _) = current_window();
+    let mut conn = redis_client.connection();
+    let (requests, tokens): (Option<u64>, Option<u64>) = redis::pipe()
+        .get(requests_key(user_id, &day))
+        .get(tokens_key(user_id, &day))
+        .query_async(&mut conn)
+        .await?;
+    Ok(QuotaUsage {
+        day,
+        requests: requests.unwrap_or_default(),
+        tokens: tokens.unwrap_or_default(),
+    })
+}
+
+// counters expire at midnight, so only today's can still be around
+pub(crate) async fn clear_usage(redis_client: &RedisClient, user_id: &str) -> Result<()> {
+    let (day, _) = current_window();
+    let mut conn = redis_client.connection();
+    conn.del::<_, ()>(&[requests_key(user_id, &day), tokens_key(user_id, &day)])
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{test_config, test_redis};
+
+    #[actix_web::test]
+    async fn spent_tokens_use_up_the_quota() {
+        let config = test_config();
+        let redis_client = test_redis(&config.redis).await;
+        let user_id = uuid::Uuid::new_v4().to_string();
+
+        let QuotaCheck::Allowed(slot) = reserve_request(&redis_client, &config.llm, &user_id)
+            .await
+            .unwrap()
+        else {
+            panic!("the first request was turned away");
+        };
+        slot.record_tokens(&redis_client, config.llm.daily_token_quota as i64)
+            .await
+            .unwrap();
+
+        let QuotaCheck::Exceeded(status) = reserve_request(&redis_client, &config.llm, &user_id)
+            .await
+            .unwrap()
+        else {
+            panic!("the quota wasn't used up");
+        };
+        assert_eq!(
+            status.requests_remaining,
+            config.llm.daily_request_quota - 2
+        );
+        assert_eq!(status.tokens_remaining, 0);
+        assert!(status.seconds_until_reset() <= 24 * 60 * 60);
+    }
+
+    #[actix_web::test]
+    async fn reservations_settle_and_refunds_give_the_request_back() {
+        let config = test_config();
+        let redis_client = test_redis(&config.redis).await;
+        let user_id = uuid::Uuid::new_v4().to_string();
+
+        let QuotaCheck::Allowed(slot) = reserve_request(&redis_client, &config.llm, &user_id)
+            .await
+            .unwrap()
+        else {
+            panic!("the first request was turned away");
+        };
+        slot.record_tokens(&redis_client, 200).await.unwrap();
+        slot.record_tokens(&redis_client, 30 - 200).await.unwrap();
+        let usage = current_usage(&redis_client, &user_id).await.unwrap();
+        assert_eq!((usage.requests, usage.tokens), (1, 30));
+
+        let QuotaCheck::Allowed(slot) = reserve_request(&redis_client, &config.llm, &user_id)
+            .await
+            .unwrap()
+        else {
+            panic!("the second request was turned away");
+        };
+        slot.refund(&redis_client).await.unwrap();
+        let usage = current_usage(&redis_client, &user_id).await.unwrap();
+        assert_eq!(usage.requests, 1);
+    }
+}
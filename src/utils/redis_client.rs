@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+
+use super::config::RedisConfig;
+
+// how long a health check waits for PING, also while a reconnect is still going on
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RedisHealth {
+    pub(crate) healthy: bool,
+    pub(crate) latency_ms: Option<u64>,
+    pub(crate) error: Option<String>,
+}
+
+// One connection shared by every request, commands are multiplexed over it. When it drops,
+// the manager reconnects in the background with exponential backoff (see [redis] in the
+// config), commands in the meantime wait for it or fail.
+pub struct RedisClient {
+    connection: ConnectionManager,
+    // result of the last health check, so only changes get logged
+    healthy: AtomicBool,
+}
+
+impl RedisClient {
+    // fails if redis can't be reached within the configured attempts
+    pub(crate) async fn connect(config: &RedisConfig) -> Result<Self> {
+        let client = redis::Client::open(config.url.as_str())?;
+        let manager_config = ConnectionManagerConfig::new()
+            .set_number_of_retries(config.reconnect_attempts)
+            .set_max_delay(config.reconnect_max_delay_ms)
+            .set_connection_timeout(Duration::from_millis(config.connection_timeout_ms))
+            .set_response_timeout(Duration::from_millis(config.response_timeout_ms));
+        let connection = ConnectionManager::new_with_config(client, manager_config).await?;
+        Ok(Self {
+            connection,
+            healthy: AtomicBool::new(true),
+        })
+    }
+
+    // cheap, every clone uses the same connection
+    pub fn connection(&self) -> ConnectionManager {
+        self.connection.clone()
+    }
+
+    // PINGs redis for readiness checks. A ping that finds the connection dropped also starts
+    // the reconnect
+    pub(crate) async fn health(&self) -> RedisHealth {
+        let started = Instant::now();
+        let mut conn = self.connection();
+        let ping_cmd = redis::cmd("PING");
+        let ping = ping_cmd.query_async::<String>(&mut conn);
+        let health = match tokio::time::timeout(PING_TIMEOUT, ping).await {
+            Ok(Ok(_)) => RedisHealth {
+                healthy: true,
+                latency_ms: Some(started.elapsed().as_millis() as u64),
+                error: None,
+            },
+            Ok(Err(err)) => RedisHealth {
+                healthy: false,
+                latency_ms: None,
+                error: Some(err.to_string()),
+            },
+            Err(_) => RedisHealth {
+                healthy: false,
+                latency_ms: None,
+                error: Some("PING timed out".to_string()),
+            },
+        };
+
+        let was_healthy = self.healthy.swap(health.healthy, Ordering::Relaxed);
+        if was_healthy != health.healthy {
+            match &health.error {
+                None => println!("[+] redis is reachable again"),
+                Some(err) => println!("[-] redis is unreachable: {}", err),
+            }
+        }
+        health
+    }
+}
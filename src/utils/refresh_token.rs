@@ -0,0 +1,316 @@
+use anyhow::Result;
+use chrono::Duration;
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+use sha256::digest;
+
+use crate::RedisClient;
+
+use super::{
+    api_key::ApiKeyRepository,
+    config::JwtConfig,
+    jwt::encode_jwt,
+    jwt_keys::KeyRing,
+    session::{extend_session, is_session_active, revoke_all_sessions, revoke_session},
+    token_version::{bump_token_version, current_token_version},
+    user::{Role, UserRepository},
+};
+
+// what we keep in redis for every refresh token we hand out, keyed by the token's sha256.
+// All tokens rotated from one login share a session, see utils::session
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct RefreshTokenRecord {
+    pub(crate) user_id: String,
+    pub(crate) email: String,
+    pub(crate) session_id: String,
+    pub(crate) token_version: u64,
+    // role changes bump token_version, so this can't go stale
+    #[serde(default)]
+    pub(crate) role: Role,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TokenPair {
+    pub(crate) access_token: String,
+    pub(crate) refresh_token: String,
+}
+
+pub(crate) enum RefreshResult {
+    Rotated(TokenPair),
+    // unknown, expired or revoked refresh token
+    Invalid,
+    // token was already rotated once, the whole session got revoked
+    ReuseDetected,
+}
+
+fn refresh_token_ttl(config: &JwtConfig) -> u64 {
+    Duration::days(config.refresh_token_days).num_seconds() as u64
+}
+
+fn token_key(token: &str) -> String {
+    format!("refresh_token:{}", digest(token))
+}
+
+fn rotated_key(token: &str) -> String {
+    format!("refresh_token_rotated:{}", digest(token))
+}
+
+pub(crate) fn generate_token() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+// Issues an access token plus a refresh token for a session from session::create_session
+pub(crate) async fn issue_token_pair(
+    redis_client: &RedisClient,
+    key_ring: &KeyRing,
+    config: &JwtConfig,
+    record: RefreshTokenRecord,
+) -> Result<TokenPair> {
+    let refresh_token = generate_token();
+    let mut conn = redis_client.connection();
+    conn.set_ex::<_, _, ()>(
+        token_key(&refresh_token),
+        serde_json::to_string(&record)?,
+        refresh_token_ttl(config),
+    )
+    .await?;
+
+    Ok(TokenPair {
+        access_token: encode_jwt(
+            key_ring,
+            config,
+            record.email,
+            record.user_id,
+            record.session_id,
+            record.token_version,
+            record.role,
+        )?,
+        refresh_token,
+    })
+}
+
+// Exchanges a refresh token for a new pair. Every refresh token can be used exactly once,
+// presenting it a second time means it leaked, so the whole session is revoked.
+pub(crate) async fn rotate_refresh_token(
+    redis_client: &RedisClient,
+    key_ring: &KeyRing,
+    config: &JwtConfig,
+    user_repository: &dyn UserRepository,
+    refresh_token: &str,
+) -> Result<RefreshResult> {
+    let mut conn = redis_client.connection();
+
+    let record: Option<String> = conn.get(token_key(refresh_token)).await?;
+    let record: RefreshTokenRecord = match record {
+        Some(record) => serde_json::from_str(&record)?,
+        None => return Ok(RefreshResult::Invalid),
+    };
+
+    if !is_session_active(redis_client, &record.session_id).await? {
+        return Ok(RefreshResult::Invalid);
+    }
+
+    // issued before a password change, ban, ... or the user is gone
+    let current_version =
+        current_token_version(redis_client, config, user_repository, &record.user_id).await?;
+    if current_version != Some(record.token_version) {
+        return Ok(RefreshResult::Invalid);
+    }
+
+    // SET NX makes sure only one request can rotate a given token
+    let first_use: Option<String> = conn
+        .set_options(
+            rotated_key(refresh_token),
+            "1",
+            SetOptions::default()
+                .conditional_set(ExistenceCheck::NX)
+                .with_expiration(SetExpiry::EX(refresh_token_ttl(config))),
+        )
+        .await?;
+    if first_use.is_none() {
+        revoke_session(redis_client, &record.user_id, &record.session_id).await?;
+        return Ok(RefreshResult::ReuseDetected);
+    }
+
+    if !extend_session(redis_client, config, &record.user_id, &record.session_id).await? {
+        return Ok(RefreshResult::Invalid);
+    }
+
+    let pair = issue_token_pair(redis_client, key_ring, config, record).await?;
+    Ok(RefreshResult::Rotated(pair))
+}
+
+// Logs the user out everywhere: no refresh token can be rotated and no access token issued
+// so far is accepted anymore. API keys go too, they'd outlive every session otherwise
+pub(crate) async fn revoke_all_user_tokens(
+    redis_client: &RedisClient,
+    config: &JwtConfig,
+    user_repository: &dyn UserRepository,
+    api_key_repository: &dyn ApiKeyRepository,
+    user_id: &str,
+) -> Result<()> {
+    revoke_all_sessions(redis_client, user_id).await?;
+    bump_token_version(redis_client, config, user_repository, user_id).await?;
+    api_key_repository.delete_user_api_keys(user_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{test_config, test_redis};
+    use crate::utils::{
+        session::{create_session, SessionInfo},
+        token_version::bump_token_version,
+        user::{InMemoryUserRepository, Role, User},
+    };
+
+    struct Login {
+        user_id: String,
+        session_id: String,
+        pair: TokenPair,
+    }
+
+    // what issuing and rotating need
+    struct Setup {
+        redis_client: RedisClient,
+        key_ring: KeyRing,
+        config: JwtConfig,
+        users: InMemoryUserRepository,
+    }
+
+    impl Setup {
+        async fn new() -> Self {
+            let config = test_config();
+            Setup {
+                redis_client: test_redis(&config.redis).await,
+                key_ring: KeyRing::from_config(&config.jwt).unwrap(),
+                config: config.jwt,
+                users: InMemoryUserRepository::default(),
+            }
+        }
+
+        async fn log_in(&self) -> Login {
+            let user = User {
+                id: format!("USER#{}", uuid::Uuid::new_v4()),
+                name: "Ada".to_string(),
+                email: "ada@example.com".to_string(),
+                password: String::new(),
+                verified: true,
+                token_version: 0,
+                bio: None,
+                avatar_url: None,
+                pending_email: None,
+                role: Role::User,
+                disabled: false,
+                identities: Vec::new(),
+                totp_secret: None,
+                recovery_codes: Vec::new(),
+            };
+            self.users.create_user(&user).await.unwrap();
+            let info = SessionInfo {
+                device: None,
+                ip: None,
+                user_agent: None,
+            };
+            let session_id = create_session(&self.redis_client, &self.config, &user.id, info)
+                .await
+                .unwrap();
+            let record = RefreshTokenRecord {
+                user_id: user.id.clone(),
+                email: user.email,
+                session_id: session_id.clone(),
+                token_version: user.token_version,
+                role: user.role,
+            };
+            let pair = issue_token_pair(&self.redis_client, &self.key_ring, &self.config, record)
+                .await
+                .unwrap();
+            Login {
+                user_id: user.id,
+                session_id,
+                pair,
+            }
+        }
+
+        async fn rotate(&self, refresh_token: &str) -> RefreshResult {
+            rotate_refresh_token(
+                &self.redis_client,
+                &self.key_ring,
+                &self.config,
+                &self.users,
+                refresh_token,
+            )
+            .await
+            .unwrap()
+        }
+    }
+
+    #[actix_web::test]
+    async fn refresh_tokens_work_once() {
+        let setup = Setup::new().await;
+        let login = setup.log_in().await;
+
+        let RefreshResult::Rotated(rotated) = setup.rotate(&login.pair.refresh_token).await else {
+            panic!("a fresh refresh token wasn't rotated");
+        };
+        assert_ne!(rotated.refresh_token, login.pair.refresh_token);
+
+        // the old one again means it leaked, which ends the session for everyone holding one
+        assert!(matches!(
+            setup.rotate(&login.pair.refresh_token).await,
+            RefreshResult::ReuseDetected
+        ));
+        assert!(matches!(
+            setup.rotate(&rotated.refresh_token).await,
+            RefreshResult::Invalid
+        ));
+        assert!(matches!(
+            setup.rotate("never-issued").await,
+            RefreshResult::Invalid
+        ));
+    }
+
+    #[actix_web::test]
+    async fn revoked_sessions_cant_refresh() {
+        let setup = Setup::new().await;
+        let login = setup.log_in().await;
+        let other_login = setup.log_in().await;
+
+        assert!(
+            revoke_session(&setup.redis_client, &login.user_id, &login.session_id)
+                .await
+                .unwrap()
+        );
+        assert!(matches!(
+            setup.rotate(&login.pair.refresh_token).await,
+            RefreshResult::Invalid
+        ));
+        assert!(matches!(
+            setup.rotate(&other_login.pair.refresh_token).await,
+            RefreshResult::Rotated(_)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn a_new_token_version_ends_every_session() {
+        let setup = Setup::new().await;
+        let login = setup.log_in().await;
+
+        bump_token_version(
+            &setup.redis_client,
+            &setup.config,
+            &setup.users,
+            &login.user_id,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            setup.rotate(&login.pair.refresh_token).await,
+            RefreshResult::Invalid
+        ));
+    }
+}
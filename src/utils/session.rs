@@ -0,0 +1,324 @@
+use actix_web::{
+    http::header::{HeaderName, USER_AGENT},
+    HttpRequest,
+};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use redis::AsyncCommands;
+
+use crate::RedisClient;
+
+use super::config::{JwtConfig, ServerConfig};
+
+// A session is one login on one device. Its id is the refresh token family and goes into every
+// access token as `sid`, so deleting the session logs that device out right away.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SessionRecord {
+    user_id: String,
+    device: Option<String>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+// where a login came from, shown back to the user in the session list
+pub(crate) struct SessionInfo {
+    pub(crate) device: Option<String>,
+    pub(crate) ip: Option<String>,
+    pub(crate) user_agent: Option<String>,
+}
+
+impl SessionInfo {
+    // `device` is the name the client gave itself, e.g. "Pixel 8"
+    pub(crate) fn from_request(
+        req: &HttpRequest,
+        config: &ServerConfig,
+        device: Option<String>,
+    ) -> Self {
+        SessionInfo {
+            device,
+            ip: client_ip(req, config),
+            user_agent: req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string()),
+        }
+    }
+}
+
+// The address the request came from. Behind one of server.trusted_proxies that's the last
+// X-Forwarded-For hop the proxies didn't add themselves, otherwise the header is ignored since
+// a client could send any address in it and dodge the per-IP login limits.
+pub(crate) fn client_ip(req: &HttpRequest, config: &ServerConfig) -> Option<String> {
+    let mut ip = req.peer_addr()?.ip();
+    let hops: Vec<&str> = req
+        .headers()
+        .get_all(HeaderName::from_static("x-forwarded-for"))
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    // each proxy appends who it got the request from, so walk back until it isn't one of ours
+    for hop in hops.iter().rev() {
+        if !config.trusts_proxy(ip) {
+            break;
+        }
+        match hop.parse() {
+            Ok(hop) => ip = hop,
+            Err(_) => break,
+        }
+    }
+    Some(ip.to_string())
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Session {
+    pub(crate) id: String,
+    pub(crate) device: Option<String>,
+    pub(crate) ip: Option<String>,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) last_seen_at: DateTime<Utc>,
+    // the session the request was made with
+    pub(crate) current: bool,
+}
+
+// sessions live as long as their refresh token, every refresh extends them
+fn session_ttl(config: &JwtConfig) -> u64 {
+    Duration::days(config.refresh_token_days).num_seconds() as u64
+}
+
+fn session_key(session_id: &str) -> String {
+    format!("session:{}", session_id)
+}
+
+// kept apart from the record so the middleware can update it without a read-modify-write
+fn last_seen_key(session_id: &str) -> String {
+    format!("session_last_seen:{}", session_id)
+}
+
+fn user_sessions_key(user_id: &str) -> String {
+    format!("user_sessions:{}", user_id)
+}
+
+pub(crate) async fn create_session(
+    redis_client: &RedisClient,
+    config: &JwtConfig,
+    user_id: &str,
+    info: SessionInfo,
+) -> Result<String> {
+    let session_id = uuid::Uuid::new_v4().to_string();
+    let now = Utc::now();
+    let record = SessionRecord {
+        user_id: user_id.to_string(),
+        device: info.device,
+        ip: info.ip,
+        user_agent: info.user_agent,
+        created_at: now,
+    };
+
+    let mut conn = redis_client.connection();
+    redis::pipe()
+        .atomic()
+        .set_ex(
+            session_key(&session_id),
+            serde_json::to_string(&record)?,
+            session_ttl(config),
+        )
+        .ignore()
+        .set_ex(
+            last_seen_key(&session_id),
+            now.timestamp(),
+            session_ttl(config),
+        )
+        .ignore()
+        .sadd(user_sessions_key(user_id), &session_id)
+        .ignore()
+        .expire(user_sessions_key(user_id), session_ttl(config) as i64)
+        .ignore()
+        .query_async::<()>(&mut conn)
+        .await?;
+    Ok(session_id)
+}
+
+// Called when the session's refresh token is rotated. false if the session is gone.
+pub(crate) async fn extend_session(
+    redis_client: &RedisClient,
+    config: &JwtConfig,
+    user_id: &str,
+    session_id: &str,
+) -> Result<bool> {
+    let mut conn = redis_client.connection();
+    let (extended,): (bool,) = redis::pipe()
+        .atomic()
+        .expire(session_key(session_id), session_ttl(config) as i64)
+        .expire(last_seen_key(session_id), session_ttl(config) as i64)
+        .ignore()
+        .expire(user_sessions_key(user_id), session_ttl(config) as i64)
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+    Ok(extended)
+}
+
+// Used by check_auth_middleware on every request: false once the session was revoked or expired
+pub(crate) async fn touch_session(redis_client: &RedisClient, session_id: &str) -> Result<bool> {
+    let mut conn = redis_client.connection();
+    let (active,): (bool,) = redis::pipe()
+        .exists(session_key(session_id))
+        .set_options(
+            last_seen_key(session_id),
+            Utc::now().timestamp(),
+            // XX: a revoked session doesn't get its last-seen key back
+            redis::SetOptions::default()
+                .conditional_set(redis::ExistenceCheck::XX)
+                .with_expiration(redis::SetExpiry::KEEPTTL),
+        )
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+    Ok(active)
+}
+
+pub(crate) async fn is_session_active(
+    redis_client: &RedisClient,
+    session_id: &str,
+) -> Result<bool> {
+    let mut conn = redis_client.connection();
+    Ok(conn.exists(session_key(session_id)).await?)
+}
+
+pub(crate) async fn list_sessions(
+    redis_client: &RedisClient,
+    user_id: &str,
+    current_session_id: &str,
+) -> Result<Vec<Session>> {
+    let mut conn = redis_client.connection();
+    let session_ids: Vec<String> = conn.smembers(user_sessions_key(user_id)).await?;
+
+    let mut sessions = Vec::new();
+    let mut expired = Vec::new();
+    for session_id in session_ids {
+        let (record, last_seen): (Option<String>, Option<i64>) = redis::pipe()
+            .get(session_key(&session_id))
+            .get(last_seen_key(&session_id))
+            .query_async(&mut conn)
+            .await?;
+        let Some(record) = record else {
+            expired.push(session_id);
+            continue;
+        };
+        let record: SessionRecord = serde_json::from_str(&record)?;
+        sessions.push(Session {
+            current: session_id == current_session_id,
+            id: session_id,
+            device: record.device,
+            ip: record.ip,
+            user_agent: record.user_agent,
+            created_at: record.created_at,
+            last_seen_at: last_seen
+                .and_then(|last_seen| DateTime::from_timestamp(last_seen, 0))
+                .unwrap_or(record.created_at),
+        });
+    }
+
+    // sessions that expired on their own are still in the set
+    if !expired.is_empty() {
+        conn.srem::<_, _, ()>(user_sessions_key(user_id), expired)
+            .await?;
+    }
+
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen_at));
+    Ok(sessions)
+}
+
+// false if there is no such session for this user
+pub(crate) async fn revoke_session(
+    redis_client: &RedisClient,
+    user_id: &str,
+    session_id: &str,
+) -> Result<bool> {
+    let mut conn = redis_client.connection();
+    let owned: bool = conn
+        .sismember(user_sessions_key(user_id), session_id)
+        .await?;
+    if !owned {
+        return Ok(false);
+    }
+
+    redis::pipe()
+        .atomic()
+        .del(&[session_key(session_id), last_seen_key(session_id)])
+        .ignore()
+        .srem(user_sessions_key(user_id), session_id)
+        .ignore()
+        .query_async::<()>(&mut conn)
+        .await?;
+    Ok(true)
+}
+
+pub(crate) async fn revoke_all_sessions(redis_client: &RedisClient, user_id: &str) -> Result<()> {
+    let mut conn = redis_client.connection();
+    let session_ids: Vec<String> = conn.smembers(user_sessions_key(user_id)).await?;
+    let mut keys: Vec<String> = session_ids
+        .iter()
+        .flat_map(|session_id| [session_key(session_id), last_seen_key(session_id)])
+        .collect();
+    keys.push(user_sessions_key(user_id));
+    conn.del::<_, ()>(keys).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn ip_of(peer: &str, forwarded_for: Option<&str>, trusted_proxies: &[&str]) -> Option<String> {
+        let config = ServerConfig {
+            trusted_proxies: trusted_proxies.iter().map(|ip| ip.to_string()).collect(),
+            ..ServerConfig::default()
+        };
+        let mut request =
+            TestRequest::default().peer_addr(format!("{}:4000", peer).parse().unwrap());
+        if let Some(forwarded_for) = forwarded_for {
+            request = request.insert_header(("X-Forwarded-For", forwarded_for));
+        }
+        client_ip(&request.to_http_request(), &config)
+    }
+
+    #[test]
+    fn forwarded_for_only_counts_behind_a_trusted_proxy() {
+        assert_eq!(ip_of("203.0.113.9", None, &[]).unwrap(), "203.0.113.9");
+        assert_eq!(
+            ip_of("203.0.113.9", Some("198.51.100.1"), &[]).unwrap(),
+            "203.0.113.9"
+        );
+        assert_eq!(
+            ip_of("10.0.0.2", Some("198.51.100.1"), &["10.0.0.2"]).unwrap(),
+            "198.51.100.1"
+        );
+    }
+
+    #[test]
+    fn made_up_hops_in_front_of_the_proxy_are_skipped() {
+        // the client sent "1.2.3.4" itself, the proxy appended the address it really came from
+        assert_eq!(
+            ip_of("10.0.0.2", Some("1.2.3.4, 198.51.100.1"), &["10.0.0.2"]).unwrap(),
+            "198.51.100.1"
+        );
+        // two of our proxies in a row, trusted as a range
+        assert_eq!(
+            ip_of(
+                "10.0.0.2",
+                Some("1.2.3.4, 198.51.100.1, 10.0.1.2"),
+                &["10.0.0.0/16"]
+            )
+            .unwrap(),
+            "198.51.100.1"
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::dev::ServerHandle;
+
+use crate::routes::middlewares::inactivity_middleware::LastActivityTime;
+
+// "5 minutes", "90 seconds", ...
+fn describe(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        1 => "1 second".to_string(),
+        s if s % 3600 == 0 => format!("{} hour(s)", s / 3600),
+        s if s % 60 == 0 => format!("{} minute(s)", s / 60),
+        s => format!("{} seconds", s),
+    }
+}
+
+// Both the idle watchdog and the signal handler end up here. stop(true) stops accepting
+// new connections and waits for in-flight requests, up to the server's shutdown_timeout.
+async fn shutdown(server_handle: ServerHandle, reason: String) {
+    println!("[-] {}. Shutting down gracefully.", reason);
+    server_handle.stop(true).await;
+}
+
+pub fn spawn_idle_watchdog(
+    server_handle: ServerHandle,
+    last_activity: Arc<LastActivityTime>,
+    idle_timeout: Duration,
+    check_interval: Duration,
+) {
+    if idle_timeout.is_zero() {
+        println!("[+] idle shutdown disabled");
+        return;
+    }
+
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::time::sleep(check_interval).await;
+            let idle_for = last_activity.0.lock().unwrap().elapsed();
+            if idle_for > idle_timeout {
+                shutdown(
+                    server_handle,
+                    format!("No activity for {}", describe(idle_timeout)),
+                )
+                .await;
+                break;
+            }
+        }
+    });
+}
+
+pub fn spawn_signal_handler(server_handle: ServerHandle) {
+    actix_web::rt::spawn(async move {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut sigterm =
+                signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => shutdown(server_handle, "Received SIGINT".to_string()).await,
+                _ = sigterm.recv() => shutdown(server_handle, "Received SIGTERM".to_string()).await,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+            shutdown(server_handle, "Received Ctrl-C".to_string()).await;
+        }
+    });
+}
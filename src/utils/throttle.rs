@@ -0,0 +1,45 @@
+use anyhow::Result;
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+
+use crate::RedisClient;
+
+// Allows one action per `seconds` for the given key. Ok(None) means go ahead,
+// Ok(Some(n)) means try again in n seconds.
+pub(crate) async fn check_cooldown(
+    redis_client: &RedisClient,
+    key: &str,
+    seconds: u64,
+) -> Result<Option<u64>> {
+    let mut conn = redis_client.connection();
+    let acquired: Option<String> = conn
+        .set_options(
+            key,
+            "1",
+            SetOptions::default()
+                .conditional_set(ExistenceCheck::NX)
+                .with_expiration(SetExpiry::EX(seconds)),
+        )
+        .await?;
+    if acquired.is_some() {
+        return Ok(None);
+    }
+
+    let ttl: i64 = conn.ttl(key).await?;
+    Ok(Some(ttl.max(1) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{test_config, test_redis};
+
+    #[actix_web::test]
+    async fn allows_one_action_per_cooldown() {
+        let redis_client = test_redis(&test_config().redis).await;
+        let key = format!("cooldown:test:{}", uuid::Uuid::new_v4());
+
+        assert_eq!(check_cooldown(&redis_client, &key, 60).await.unwrap(), None);
+        let retry_after = check_cooldown(&redis_client, &key, 60).await.unwrap();
+        assert!(matches!(retry_after, Some(1..=60)), "{:?}", retry_after);
+    }
+}
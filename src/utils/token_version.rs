@@ -0,0 +1,66 @@
+use anyhow::Result;
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+
+use crate::RedisClient;
+
+use super::{config::JwtConfig, user::UserRepository};
+
+// Every access and refresh token carries the user's token_version from when it was issued.
+// Bumping the version invalidates all of them at once, including ones we never stored anywhere.
+fn cache_key(user_id: &str) -> String {
+    format!("token_version:{}", user_id)
+}
+
+// None if the user doesn't exist (anymore)
+pub(crate) async fn current_token_version(
+    redis_client: &RedisClient,
+    config: &JwtConfig,
+    user_repository: &dyn UserRepository,
+    user_id: &str,
+) -> Result<Option<u64>> {
+    let mut conn = redis_client.connection();
+    let cached: Option<u64> = conn.get(cache_key(user_id)).await?;
+    if cached.is_some() {
+        return Ok(cached);
+    }
+
+    let Some(user) = user_repository.get_user_from_id(user_id).await? else {
+        return Ok(None);
+    };
+    // NX so a slow read can't overwrite a version a concurrent bump just cached
+    conn.set_options::<_, _, ()>(
+        cache_key(user_id),
+        user.token_version,
+        SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::EX(config.token_version_cache_seconds)),
+    )
+    .await?;
+    Ok(Some(user.token_version))
+}
+
+pub(crate) async fn bump_token_version(
+    redis_client: &RedisClient,
+    config: &JwtConfig,
+    user_repository: &dyn UserRepository,
+    user_id: &str,
+) -> Result<()> {
+    let Some(version) = user_repository.increment_token_version(user_id).await? else {
+        return Ok(());
+    };
+    let mut conn = redis_client.connection();
+    conn.set_ex::<_, _, ()>(
+        cache_key(user_id),
+        version,
+        config.token_version_cache_seconds,
+    )
+    .await?;
+    Ok(())
+}
+
+// Drops the cached version once the user is deleted, so their tokens find no user at all
+pub(crate) async fn forget_token_version(redis_client: &RedisClient, user_id: &str) -> Result<()> {
+    let mut conn = redis_client.connection();
+    conn.del::<_, ()>(cache_key(user_id)).await?;
+    Ok(())
+}
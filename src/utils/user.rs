@@ -1,42 +1,913 @@
 use anyhow::Result;
 use std::collections::HashMap;
+use std::sync::Mutex;
 
 use anyhow::anyhow;
-use aws_sdk_dynamodb::types::AttributeValue;
-use std::sync::Arc;
-
-use aws_sdk_dynamodb::{operation::query::QueryOutput, Client};
-
-use super::global_variables::DYNAMO_DB_TABLE_NAME;
-
-pub(crate) async fn get_user_from_email(
-    dynamo_client: &Arc<Client>,
-    email: String,
-) -> Result<QueryOutput> {
-    let table_name = DYNAMO_DB_TABLE_NAME.clone();
-    dynamo_client
-        .query()
-        .table_name(table_name)
-        .index_name("EmailIndex") // Assuming you've created a GSI named "EmailIndex"
-        .key_condition_expression("email = :email")
-        .expression_attribute_values(":email", AttributeValue::S(email))
-        .select(aws_sdk_dynamodb::types::Select::AllAttributes)
-        .send()
-        .await
-        .map_err(anyhow::Error::from)
-}
-
-#[derive(Debug)]
+use async_trait::async_trait;
+use aws_sdk_dynamodb::operation::update_item::UpdateItemError;
+use aws_sdk_dynamodb::types::{
+    AttributeValue, Delete, Put, ReturnValue, TransactWriteItem, Update,
+};
+use aws_sdk_dynamodb::Client;
+
+// Everything handlers need from user storage. AppState holds it as a trait object so
+// the server can run against DynamoDB or entirely in memory (USER_STORE=memory).
+#[async_trait]
+pub(crate) trait UserRepository: Send + Sync {
+    async fn get_user_from_id(&self, id: &str) -> Result<Option<User>>;
+    async fn get_user_from_email(&self, email: &str) -> Result<Option<User>>;
+    async fn create_user(&self, user: &User) -> Result<()>;
+    async fn update_user_password(&self, id: &str, password_hash: &str) -> Result<()>;
+    // returns the updated user, None if it doesn't exist
+    async fn update_user_profile(&self, id: &str, update: &ProfileUpdate) -> Result<Option<User>>;
+    // moves pending_email over to email, None unless pending_email still equals `email`
+    async fn confirm_email_change(&self, id: &str, email: &str) -> Result<Option<User>>;
+    // None unless the user's email still equals `email`
+    async fn mark_email_verified(&self, id: &str, email: &str) -> Result<Option<User>>;
+    // returns the new version, None if the user doesn't exist
+    async fn increment_token_version(&self, id: &str) -> Result<Option<u64>>;
+    // users ordered by id, optionally only those whose name or email contains `search`
+    async fn list_users(
+        &self,
+        search: Option<&str>,
+        limit: usize,
+        cursor: Option<&str>,
+    ) -> Result<UserPage>;
+    // both return the updated user, None if it doesn't exist
+    async fn set_user_role(&self, id: &str, role: Role) -> Result<Option<User>>;
+    async fn set_user_disabled(&self, id: &str, disabled: bool) -> Result<Option<User>>;
+    // the user an external identity (see utils::oidc) is linked to
+    async fn get_user_from_identity(&self, provider: &str, subject: &str) -> Result<Option<User>>;
+    async fn link_identity(&self, user_id: &str, provider: &str, subject: &str) -> Result<()>;
+    // None if the user doesn't exist or already has TOTP on
+    async fn enable_totp(
+        &self,
+        id: &str,
+        secret: &str,
+        recovery_code_hashes: &[String],
+    ) -> Result<Option<User>>;
+    async fn disable_totp(&self, id: &str) -> Result<Option<User>>;
+    // removes the code so it works once, false if it isn't one of the user's
+    async fn use_recovery_code(&self, id: &str, code_hash: &str) -> Result<bool>;
+    // removes the user and its linked identities, false if it didn't exist
+    async fn delete_user(&self, id: &str) -> Result<bool>;
+}
+
+// every user id starts with this, other items in the table (api keys, ...) don't
+pub(crate) const USER_ID_PREFIX: &str = "USER#";
+// items mapping a provider's subject to a user id
+pub(crate) const IDENTITY_ID_PREFIX: &str = "IDENTITY#";
+
+// "google#1234", how a linked identity is named on the user
+pub(crate) fn identity_name(provider: &str, subject: &str) -> String {
+    format!("{}#{}", provider, subject)
+}
+
+// Emails are stored and looked up in this form, so "Ada@Example.com " finds ada@example.com.
+// Handlers apply it to every address a client sends before using it for anything.
+pub(crate) fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+// Accounts created before emails were normalized still hold the address as it was typed, so
+// when the normalized one finds nobody this tries it that way too. Their owners can move to
+// the normalized form by changing their email, which stores it normalized like any other.
+pub(crate) async fn find_user_by_email(
+    users: &dyn UserRepository,
+    email: &str,
+) -> Result<Option<User>> {
+    let normalized = normalize_email(email);
+    if let Some(user) = users.get_user_from_email(&normalized).await? {
+        return Ok(Some(user));
+    }
+    if normalized == email {
+        return Ok(None);
+    }
+    users.get_user_from_email(email).await
+}
+
+// ordered from least to most privileged, every role can do what the ones below it can
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Curator,
+    Admin,
+}
+
+impl Role {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Curator => "curator",
+            Role::Admin => "admin",
+        }
+    }
+
+    fn from_attribute(value: &str) -> Result<Self> {
+        match value {
+            "user" => Ok(Role::User),
+            "curator" => Ok(Role::Curator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(anyhow!("Unknown role {}", value)),
+        }
+    }
+}
+
+pub(crate) struct UserPage {
+    pub(crate) users: Vec<User>,
+    // pass back as `cursor` for the next page, None on the last one
+    pub(crate) next_cursor: Option<String>,
+}
+
+// None leaves a field as it is, an empty string removes the optional ones
+#[derive(Debug, Default)]
+pub(crate) struct ProfileUpdate {
+    pub(crate) name: Option<String>,
+    pub(crate) bio: Option<String>,
+    pub(crate) avatar_url: Option<String>,
+    pub(crate) pending_email: Option<String>,
+}
+
+impl ProfileUpdate {
+    fn fields(&self) -> [(&'static str, &Option<String>); 4] {
+        [
+            ("name", &self.name),
+            ("bio", &self.bio),
+            ("avatar_url", &self.avatar_url),
+            ("pending_email", &self.pending_email),
+        ]
+    }
+}
+
+// None for a conditional update whose condition didn't hold
+fn updated_user(
+    result: Result<
+        aws_sdk_dynamodb::operation::update_item::UpdateItemOutput,
+        aws_sdk_dynamodb::error::SdkError<UpdateItemError>,
+    >,
+) -> Result<Option<User>> {
+    match result {
+        Ok(output) => output.attributes.as_ref().map(User::from_item).transpose(),
+        Err(err) => match err.into_service_error() {
+            UpdateItemError::ConditionalCheckFailedException(_) => Ok(None),
+            err => Err(anyhow!(err)),
+        },
+    }
+}
+
+pub(crate) struct DynamoUserRepository {
+    client: Client,
+    table_name: String,
+}
+
+impl DynamoUserRepository {
+    pub(crate) fn new(client: Client, table_name: String) -> Self {
+        Self { client, table_name }
+    }
+}
+
+#[async_trait]
+impl UserRepository for DynamoUserRepository {
+    async fn get_user_from_id(&self, id: &str) -> Result<Option<User>> {
+        let result = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            // token_version is read through here and has to be current right after a bump
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+
+        result.item.as_ref().map(User::from_item).transpose()
+    }
+
+    async fn get_user_from_email(&self, email: &str) -> Result<Option<User>> {
+        let result = self
+            .client
+            .query()
+            .table_name(&self.table_name)
+            .index_name("EmailIndex") // Assuming you've created a GSI named "EmailIndex"
+            .key_condition_expression("email = :email")
+            .expression_attribute_values(":email", AttributeValue::S(email.to_string()))
+            .select(aws_sdk_dynamodb::types::Select::AllAttributes)
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+
+        result
+            .items
+            .and_then(|items| items.first().cloned())
+            .as_ref()
+            .map(User::from_item)
+            .transpose()
+    }
+
+    async fn create_user(&self, user: &User) -> Result<()> {
+        self.client
+            .put_item()
+            .table_name(&self.table_name)
+            .set_item(Some(user.to_item()))
+            .condition_expression("attribute_not_exists(id)")
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    async fn update_user_password(&self, id: &str, password_hash: &str) -> Result<()> {
+        self.client
+            .update_item()
+            .table_name(&self.table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            .update_expression("SET password = :password")
+            .condition_expression("attribute_exists(id)")
+            .expression_attribute_values(":password", AttributeValue::S(password_hash.to_string()))
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    async fn update_user_profile(&self, id: &str, update: &ProfileUpdate) -> Result<Option<User>> {
+        let mut set = Vec::new();
+        let mut remove = Vec::new();
+        let mut request = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            .condition_expression("attribute_exists(id)")
+            .return_values(ReturnValue::AllNew);
+
+        // "name" is a reserved word, so every attribute goes through a #placeholder
+        for (attribute, value) in update.fields() {
+            let Some(value) = value else { continue };
+            request = request.expression_attribute_names(format!("#{attribute}"), attribute);
+            if value.is_empty() {
+                remove.push(format!("#{attribute}"));
+            } else {
+                set.push(format!("#{attribute} = :{attribute}"));
+                request = request.expression_attribute_values(
+                    format!(":{attribute}"),
+                    AttributeValue::S(value.clone()),
+                );
+            }
+        }
+
+        if set.is_empty() && remove.is_empty() {
+            return self.get_user_from_id(id).await;
+        }
+
+        let mut update_expression = String::new();
+        if !set.is_empty() {
+            update_expression.push_str(&format!("SET {} ", set.join(", ")));
+        }
+        if !remove.is_empty() {
+            update_expression.push_str(&format!("REMOVE {}", remove.join(", ")));
+        }
+
+        updated_user(
+            request
+                .update_expression(update_expression.trim_end())
+                .send()
+                .await,
+        )
+    }
+
+    async fn confirm_email_change(&self, id: &str, email: &str) -> Result<Option<User>> {
+        updated_user(
+            self.client
+                .update_item()
+                .table_name(&self.table_name)
+                .key("id", AttributeValue::S(id.to_string()))
+                // the confirmation link went to the new address, so it's verified too
+                .update_expression("SET email = :email, verified = :verified REMOVE pending_email")
+                .condition_expression("pending_email = :email")
+                .expression_attribute_values(":email", AttributeValue::S(email.to_string()))
+                .expression_attribute_values(":verified", AttributeValue::Bool(true))
+                .return_values(ReturnValue::AllNew)
+                .send()
+                .await,
+        )
+    }
+
+    async fn mark_email_verified(&self, id: &str, email: &str) -> Result<Option<User>> {
+        updated_user(
+            self.client
+                .update_item()
+                .table_name(&self.table_name)
+                .key("id", AttributeValue::S(id.to_string()))
+                .update_expression("SET verified = :verified")
+                .condition_expression("email = :email")
+                .expression_attribute_values(":email", AttributeValue::S(email.to_string()))
+                .expression_attribute_values(":verified", AttributeValue::Bool(true))
+                .return_values(ReturnValue::AllNew)
+                .send()
+                .await,
+        )
+    }
+
+    async fn increment_token_version(&self, id: &str) -> Result<Option<u64>> {
+        // ADD treats a missing attribute as 0, so older users start at 1
+        let user = updated_user(
+            self.client
+                .update_item()
+                .table_name(&self.table_name)
+                .key("id", AttributeValue::S(id.to_string()))
+                .update_expression("ADD token_version :one")
+                .condition_expression("attribute_exists(id)")
+                .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+                .return_values(ReturnValue::AllNew)
+                .send()
+                .await,
+        )?;
+        Ok(user.map(|user| user.token_version))
+    }
+
+    async fn list_users(
+        &self,
+        search: Option<&str>,
+        limit: usize,
+        cursor: Option<&str>,
+    ) -> Result<UserPage> {
+        let mut filter = "begins_with(id, :prefix)".to_string();
+        if search.is_some() {
+            filter.push_str(" AND (contains(email, :search) OR contains(#name, :search))");
+        }
+
+        // a scan page can hold more matches than we need, so the cursor is the last user we
+        // return rather than LastEvaluatedKey. Scans can start after any key, not just page ends
+        let mut users = Vec::new();
+        let mut start_key = cursor.map(|cursor| cursor.to_string());
+        loop {
+            let mut request = self
+                .client
+                .scan()
+                .table_name(&self.table_name)
+                .filter_expression(&filter)
+                .expression_attribute_values(
+                    ":prefix",
+                    AttributeValue::S(USER_ID_PREFIX.to_string()),
+                );
+            if let Some(search) = search {
+                request = request
+                    .expression_attribute_names("#name", "name")
+                    .expression_attribute_values(":search", AttributeValue::S(search.to_string()));
+            }
+            if let Some(start_key) = &start_key {
+                request =
+                    request.exclusive_start_key("id", AttributeValue::S(start_key.to_string()));
+            }
+            let output = request.send().await.map_err(anyhow::Error::from)?;
+
+            for item in output.items() {
+                users.push(User::from_item(item)?);
+                if users.len() == limit {
+                    let next_cursor = users.last().map(|user| user.id.clone());
+                    return Ok(UserPage { users, next_cursor });
+                }
+            }
+
+            start_key = output
+                .last_evaluated_key()
+                .and_then(|key| key.get("id"))
+                .and_then(|id| id.as_s().ok())
+                .cloned();
+            if start_key.is_none() {
+                return Ok(UserPage {
+                    users,
+                    next_cursor: None,
+                });
+            }
+        }
+    }
+
+    async fn set_user_role(&self, id: &str, role: Role) -> Result<Option<User>> {
+        updated_user(
+            self.client
+                .update_item()
+                .table_name(&self.table_name)
+                .key("id", AttributeValue::S(id.to_string()))
+                .update_expression("SET #role = :role")
+                .condition_expression("attribute_exists(id)")
+                .expression_attribute_names("#role", "role")
+                .expression_attribute_values(":role", AttributeValue::S(role.as_str().to_string()))
+                .return_values(ReturnValue::AllNew)
+                .send()
+                .await,
+        )
+    }
+
+    async fn set_user_disabled(&self, id: &str, disabled: bool) -> Result<Option<User>> {
+        updated_user(
+            self.client
+                .update_item()
+                .table_name(&self.table_name)
+                .key("id", AttributeValue::S(id.to_string()))
+                .update_expression("SET disabled = :disabled")
+                .condition_expression("attribute_exists(id)")
+                .expression_attribute_values(":disabled", AttributeValue::Bool(disabled))
+                .return_values(ReturnValue::AllNew)
+                .send()
+                .await,
+        )
+    }
+
+    async fn get_user_from_identity(&self, provider: &str, subject: &str) -> Result<Option<User>> {
+        let result = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key(
+                "id",
+                AttributeValue::S(format!(
+                    "{}{}",
+                    IDENTITY_ID_PREFIX,
+                    identity_name(provider, subject)
+                )),
+            )
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+
+        let user_id = result
+            .item
+            .as_ref()
+            .and_then(|item| item.get("user_id"))
+            .and_then(|v| v.as_s().ok());
+        match user_id {
+            Some(user_id) => self.get_user_from_id(user_id).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn link_identity(&self, user_id: &str, provider: &str, subject: &str) -> Result<()> {
+        let identity = identity_name(provider, subject);
+        // the user item lists its identities too, so they can be found when it's deleted
+        let put = Put::builder()
+            .table_name(&self.table_name)
+            .item(
+                "id",
+                AttributeValue::S(format!("{}{}", IDENTITY_ID_PREFIX, identity)),
+            )
+            .item("user_id", AttributeValue::S(user_id.to_string()))
+            .build()?;
+        let update = Update::builder()
+            .table_name(&self.table_name)
+            .key("id", AttributeValue::S(user_id.to_string()))
+            .update_expression("ADD identities :identity")
+            .condition_expression("attribute_exists(id)")
+            .expression_attribute_values(":identity", AttributeValue::Ss(vec![identity]))
+            .build()?;
+
+        self.client
+            .transact_write_items()
+            .transact_items(TransactWriteItem::builder().put(put).build())
+            .transact_items(TransactWriteItem::builder().update(update).build())
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    async fn enable_totp(
+        &self,
+        id: &str,
+        secret: &str,
+        recovery_code_hashes: &[String],
+    ) -> Result<Option<User>> {
+        updated_user(
+            self.client
+                .update_item()
+                .table_name(&self.table_name)
+                .key("id", AttributeValue::S(id.to_string()))
+                .update_expression("SET totp_secret = :secret, recovery_codes = :codes")
+                .condition_expression("attribute_exists(id) AND attribute_not_exists(totp_secret)")
+                .expression_attribute_values(":secret", AttributeValue::S(secret.to_string()))
+                .expression_attribute_values(
+                    ":codes",
+                    AttributeValue::Ss(recovery_code_hashes.to_vec()),
+                )
+                .return_values(ReturnValue::AllNew)
+                .send()
+                .await,
+        )
+    }
+
+    async fn disable_totp(&self, id: &str) -> Result<Option<User>> {
+        updated_user(
+            self.client
+                .update_item()
+                .table_name(&self.table_name)
+                .key("id", AttributeValue::S(id.to_string()))
+                .update_expression("REMOVE totp_secret, recovery_codes")
+                .condition_expression("attribute_exists(id)")
+                .return_values(ReturnValue::AllNew)
+                .send()
+                .await,
+        )
+    }
+
+    async fn use_recovery_code(&self, id: &str, code_hash: &str) -> Result<bool> {
+        // the condition makes two requests with the same code race for it, only one wins
+        let user = updated_user(
+            self.client
+                .update_item()
+                .table_name(&self.table_name)
+                .key("id", AttributeValue::S(id.to_string()))
+                .update_expression("DELETE recovery_codes :code")
+                .condition_expression("contains(recovery_codes, :hash)")
+                .expression_attribute_values(
+                    ":code",
+                    AttributeValue::Ss(vec![code_hash.to_string()]),
+                )
+                .expression_attribute_values(":hash", AttributeValue::S(code_hash.to_string()))
+                .return_values(ReturnValue::AllNew)
+                .send()
+                .await,
+        )?;
+        Ok(user.is_some())
+    }
+
+    async fn delete_user(&self, id: &str) -> Result<bool> {
+        let Some(user) = self.get_user_from_id(id).await? else {
+            return Ok(false);
+        };
+
+        let mut items = vec![TransactWriteItem::builder()
+            .delete(
+                Delete::builder()
+                    .table_name(&self.table_name)
+                    .key("id", AttributeValue::S(user.id.clone()))
+                    .condition_expression("attribute_exists(id)")
+                    .build()?,
+            )
+            .build()];
+        for identity in &user.identities {
+            items.push(
+                TransactWriteItem::builder()
+                    .delete(
+                        Delete::builder()
+                            .table_name(&self.table_name)
+                            .key(
+                                "id",
+                                AttributeValue::S(format!("{}{}", IDENTITY_ID_PREFIX, identity)),
+                            )
+                            .build()?,
+                    )
+                    .build(),
+            );
+        }
+
+        self.client
+            .transact_write_items()
+            .set_transact_items(Some(items))
+            .send()
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(true)
+    }
+}
+
+// keeps users in a HashMap keyed by id, for local runs and tests without AWS
+#[derive(Default)]
+pub(crate) struct InMemoryUserRepository {
+    users: Mutex<HashMap<String, User>>,
+    // identity name -> user id
+    identities: Mutex<HashMap<String, String>>,
+}
+
+#[async_trait]
+impl UserRepository for InMemoryUserRepository {
+    async fn get_user_from_id(&self, id: &str) -> Result<Option<User>> {
+        Ok(self.users.lock().unwrap().get(id).cloned())
+    }
+
+    async fn get_user_from_email(&self, email: &str) -> Result<Option<User>> {
+        Ok(self
+            .users
+            .lock()
+            .unwrap()
+            .values()
+            .find(|user| user.email == email)
+            .cloned())
+    }
+
+    async fn create_user(&self, user: &User) -> Result<()> {
+        let mut users = self.users.lock().unwrap();
+        if users.contains_key(&user.id) {
+            return Err(anyhow!("User {} already exists", user.id));
+        }
+        users.insert(user.id.clone(), user.clone());
+        Ok(())
+    }
+
+    async fn update_user_password(&self, id: &str, password_hash: &str) -> Result<()> {
+        let mut users = self.users.lock().unwrap();
+        let user = users
+            .get_mut(id)
+            .ok_or_else(|| anyhow!("User {} not found", id))?;
+        user.password = password_hash.to_string();
+        Ok(())
+    }
+
+    async fn update_user_profile(&self, id: &str, update: &ProfileUpdate) -> Result<Option<User>> {
+        let mut users = self.users.lock().unwrap();
+        let Some(user) = users.get_mut(id) else {
+            return Ok(None);
+        };
+        if let Some(name) = &update.name {
+            user.name = name.clone();
+        }
+        for (field, value) in [
+            (&mut user.bio, &update.bio),
+            (&mut user.avatar_url, &update.avatar_url),
+            (&mut user.pending_email, &update.pending_email),
+        ] {
+            if let Some(value) = value {
+                *field = Some(value.clone()).filter(|value| !value.is_empty());
+            }
+        }
+        Ok(Some(user.clone()))
+    }
+
+    async fn confirm_email_change(&self, id: &str, email: &str) -> Result<Option<User>> {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(id) {
+            Some(user) if user.pending_email.as_deref() == Some(email) => {
+                user.email = email.to_string();
+                user.pending_email = None;
+                user.verified = true;
+                Ok(Some(user.clone()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn mark_email_verified(&self, id: &str, email: &str) -> Result<Option<User>> {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(id) {
+            Some(user) if user.email == email => {
+                user.verified = true;
+                Ok(Some(user.clone()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn increment_token_version(&self, id: &str) -> Result<Option<u64>> {
+        let mut users = self.users.lock().unwrap();
+        Ok(users.get_mut(id).map(|user| {
+            user.token_version += 1;
+            user.token_version
+        }))
+    }
+
+    async fn list_users(
+        &self,
+        search: Option<&str>,
+        limit: usize,
+        cursor: Option<&str>,
+    ) -> Result<UserPage> {
+        let users = self.users.lock().unwrap();
+        let mut matching: Vec<&User> = users
+            .values()
+            .filter(|user| match cursor {
+                Some(cursor) => user.id.as_str() > cursor,
+                None => true,
+            })
+            .filter(|user| match search {
+                Some(search) => user.email.contains(search) || user.name.contains(search),
+                None => true,
+            })
+            .collect();
+        matching.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let next_cursor = (matching.len() > limit).then(|| matching[limit - 1].id.clone());
+        Ok(UserPage {
+            users: matching.into_iter().take(limit).cloned().collect(),
+            next_cursor,
+        })
+    }
+
+    async fn set_user_role(&self, id: &str, role: Role) -> Result<Option<User>> {
+        let mut users = self.users.lock().unwrap();
+        Ok(users.get_mut(id).map(|user| {
+            user.role = role;
+            user.clone()
+        }))
+    }
+
+    async fn set_user_disabled(&self, id: &str, disabled: bool) -> Result<Option<User>> {
+        let mut users = self.users.lock().unwrap();
+        Ok(users.get_mut(id).map(|user| {
+            user.disabled = disabled;
+            user.clone()
+        }))
+    }
+
+    async fn get_user_from_identity(&self, provider: &str, subject: &str) -> Result<Option<User>> {
+        let user_id = self
+            .identities
+            .lock()
+            .unwrap()
+            .get(&identity_name(provider, subject))
+            .cloned();
+        match user_id {
+            Some(user_id) => self.get_user_from_id(&user_id).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn link_identity(&self, user_id: &str, provider: &str, subject: &str) -> Result<()> {
+        let identity = identity_name(provider, subject);
+        let mut users = self.users.lock().unwrap();
+        let user = users
+            .get_mut(user_id)
+            .ok_or_else(|| anyhow!("User {} not found", user_id))?;
+        if !user.identities.contains(&identity) {
+            user.identities.push(identity.clone());
+        }
+        self.identities
+            .lock()
+            .unwrap()
+            .insert(identity, user_id.to_string());
+        Ok(())
+    }
+
+    async fn enable_totp(
+        &self,
+        id: &str,
+        secret: &str,
+        recovery_code_hashes: &[String],
+    ) -> Result<Option<User>> {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(id) {
+            Some(user) if user.totp_secret.is_none() => {
+                user.totp_secret = Some(secret.to_string());
+                user.recovery_codes = recovery_code_hashes.to_vec();
+                Ok(Some(user.clone()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn disable_totp(&self, id: &str) -> Result<Option<User>> {
+        let mut users = self.users.lock().unwrap();
+        Ok(users.get_mut(id).map(|user| {
+            user.totp_secret = None;
+            user.recovery_codes.clear();
+            user.clone()
+        }))
+    }
+
+    async fn use_recovery_code(&self, id: &str, code_hash: &str) -> Result<bool> {
+        let mut users = self.users.lock().unwrap();
+        let Some(user) = users.get_mut(id) else {
+            return Ok(false);
+        };
+        let before = user.recovery_codes.len();
+        user.recovery_codes.retain(|hash| hash != code_hash);
+        Ok(user.recovery_codes.len() < before)
+    }
+
+    async fn delete_user(&self, id: &str) -> Result<bool> {
+        let Some(user) = self.users.lock().unwrap().remove(id) else {
+            return Ok(false);
+        };
+        let mut identities = self.identities.lock().unwrap();
+        for identity in &user.identities {
+            identities.remove(identity);
+        }
+        Ok(true)
+    }
+}
+
+// the parts of a user that are safe to send back to the client
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UserProfile {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) email: String,
+    pub(crate) email_verified: bool,
+    pub(crate) mfa_enabled: bool,
+    pub(crate) role: Role,
+    pub(crate) bio: Option<String>,
+    pub(crate) avatar_url: Option<String>,
+    // set while an email change waits for confirmation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) pending_email: Option<String>,
+}
+
+#[derive(Debug, Clone)]
 pub(crate) struct User {
     pub(crate) id: String,
     pub(crate) name: String,
     pub(crate) email: String,
-    #[allow(dead_code)]
+    // empty for users who only ever signed in through a provider, no password matches it
     pub(crate) password: String,
+    pub(crate) verified: bool,
+    // bumped to invalidate every token issued so far
+    pub(crate) token_version: u64,
+    pub(crate) role: Role,
+    // disabled users can't sign in, see admin_handlers
+    pub(crate) disabled: bool,
+    // linked sign in providers like "google#1234", see utils::oidc
+    pub(crate) identities: Vec<String>,
+    // base32 TOTP secret once two-factor is on, see utils::mfa
+    pub(crate) totp_secret: Option<String>,
+    // sha256 of the unused recovery codes
+    pub(crate) recovery_codes: Vec<String>,
+    pub(crate) bio: Option<String>,
+    pub(crate) avatar_url: Option<String>,
+    pub(crate) pending_email: Option<String>,
 }
 
 impl User {
+    pub(crate) fn profile(&self) -> UserProfile {
+        UserProfile {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            email: self.email.clone(),
+            email_verified: self.verified,
+            mfa_enabled: self.totp_secret.is_some(),
+            role: self.role,
+            bio: self.bio.clone(),
+            avatar_url: self.avatar_url.clone(),
+            pending_email: self.pending_email.clone(),
+        }
+    }
+
+    pub(crate) fn to_item(&self) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::new();
+        item.insert("id".to_string(), AttributeValue::S(self.id.clone()));
+        item.insert("name".to_string(), AttributeValue::S(self.name.clone()));
+        item.insert("email".to_string(), AttributeValue::S(self.email.clone()));
+        item.insert(
+            "password".to_string(),
+            AttributeValue::S(self.password.clone()),
+        );
+        item.insert("verified".to_string(), AttributeValue::Bool(self.verified));
+        item.insert(
+            "token_version".to_string(),
+            AttributeValue::N(self.token_version.to_string()),
+        );
+        item.insert(
+            "role".to_string(),
+            AttributeValue::S(self.role.as_str().to_string()),
+        );
+        item.insert("disabled".to_string(), AttributeValue::Bool(self.disabled));
+        // string sets can't be empty
+        for (attribute, values) in [
+            ("identities", &self.identities),
+            ("recovery_codes", &self.recovery_codes),
+        ] {
+            if !values.is_empty() {
+                item.insert(attribute.to_string(), AttributeValue::Ss(values.clone()));
+            }
+        }
+        for (attribute, value) in [
+            ("bio", &self.bio),
+            ("avatar_url", &self.avatar_url),
+            ("pending_email", &self.pending_email),
+            ("totp_secret", &self.totp_secret),
+        ] {
+            if let Some(value) = value {
+                item.insert(attribute.to_string(), AttributeValue::S(value.clone()));
+            }
+        }
+        item
+    }
+
     pub(crate) fn from_item(item: &HashMap<String, AttributeValue>) -> Result<Self> {
+        let optional = |attribute: &str| {
+            item.get(attribute)
+                .and_then(|v| v.as_s().ok())
+                .map(|v| v.to_string())
+        };
+        let string_set = |attribute: &str| {
+            item.get(attribute)
+                .and_then(|v| v.as_ss().ok())
+                .cloned()
+                .unwrap_or_default()
+        };
         Ok(User {
             id: item
                 .get("id")
@@ -58,6 +929,34 @@ impl User {
                 .and_then(|v| v.as_s().ok())
                 .ok_or_else(|| anyhow!("Missing password"))?
                 .to_string(),
+            // users from before email verification have no flag and count as unverified
+            verified: item
+                .get("verified")
+                .and_then(|v| v.as_bool().ok())
+                .copied()
+                .unwrap_or(false),
+            token_version: item
+                .get("token_version")
+                .and_then(|v| v.as_n().ok())
+                .map(|v| v.parse::<u64>())
+                .transpose()?
+                .unwrap_or(0),
+            // users from before roles are plain users
+            role: optional("role")
+                .map(|role| Role::from_attribute(&role))
+                .transpose()?
+                .unwrap_or_default(),
+            disabled: item
+                .get("disabled")
+                .and_then(|v| v.as_bool().ok())
+                .copied()
+                .unwrap_or(false),
+            identities: string_set("identities"),
+            totp_secret: optional("totp_secret"),
+            recovery_codes: string_set("recovery_codes"),
+            bio: optional("bio"),
+            avatar_url: optional("avatar_url"),
+            pending_email: optional("pending_email"),
         })
     }
 }